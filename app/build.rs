@@ -6,7 +6,16 @@ use std::env;
 // https://github.com/protocolbuffers/protobuf/blob/main/docs/implementing_proto3_presence.md
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let proto_file = "./proto/echo.proto";
+    // every proto, encoded for grpc reflection; committed next to the generated code,
+    // for builds without protoc
+    let descriptor_path = "./src/simply_descriptor.bin";
+    let proto_files = [
+        "./proto/echo.proto",
+        "./proto/replication.proto",
+        "./proto/person.proto",
+        "./proto/gupload.proto",
+        "./proto/payments.proto",
+    ];
 
     match env::var("SKIP_COMPILE_PROTO") {
         Err(_) => {
@@ -19,13 +28,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .server_mod_attribute("attrs", "#[cfg(feature = \"server\")]")
                 .client_mod_attribute("attrs", "#[cfg(feature = \"client\")]")
                 .out_dir("./src")
+                .file_descriptor_set_path(descriptor_path)
                 .protoc_arg("--experimental_allow_proto3_optional")
-                .compile(&[proto_file], &["."])
+                .compile(&proto_files, &["."])
                 .unwrap_or_else(|e| panic!("protobuf compile error: {}", e));
 
-            println!("cargo:rerun-if-changed={}", proto_file);
+            for proto_file in proto_files {
+                println!("cargo:rerun-if-changed={}", proto_file);
+            }
+        }
+        Ok(_) => {
+            println!("protocolbuffer compilation skipped");
         }
-        Ok(_) => println!("protocolbuffer compilation skipped"),
     }
 
     Ok(())
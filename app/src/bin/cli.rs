@@ -6,9 +6,21 @@
 // cargo build --release --bin simply-cli
 // cargo run --bin simply-cli
 // ./simply-cli stream-echo 5
+// ./simply-cli upload ./report.pdf
+// tar c src | ./simply-cli upload - --name src.tar
+// ./simply-cli download src.tar -o - | tar x
+// ./simply-cli --host localhost --ca ca.pem --cert client.pem --key client.key get foo
+// SIMPLY_TOKEN=<token> ./simply-cli set foo bar
+// ./simply-cli --token-file ./token get foo
 
-use app::{clients::Client, DEFAULT_PORT};
+use app::{
+    clients::{progress_bar, Client},
+    tls::ClientTls,
+    DEFAULT_PORT,
+};
 use clap::{Parser, Subcommand};
+use colored::*;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -21,10 +33,69 @@ struct Cli {
     #[command(subcommand)]
     command: Command,
 
-    // #[clap(name = "hostname", long, default_value = "127.0.0.1")]
-    // host: String,
+    #[clap(long, default_value = "127.0.0.1")]
+    host: String,
+
     #[clap(long, default_value_t = DEFAULT_PORT)]
     port: u16,
+
+    /// shard keys across many servers, e.g. --servers 10.0.0.1:50051,10.0.0.2:50051
+    #[clap(long, value_delimiter = ',')]
+    servers: Vec<String>,
+
+    /// connect over TLS, verifying the server with the system roots unless --ca
+    #[clap(long)]
+    tls: bool,
+
+    /// CA bundle verifying the server certificate; implies --tls
+    #[clap(long)]
+    ca: Option<PathBuf>,
+
+    /// client certificate, for servers requiring one (mTLS); implies --tls
+    #[clap(long, requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// key of the client certificate
+    #[clap(long, requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// file of the bearer token, for servers requiring authentication; SIMPLY_TOKEN
+    /// without it. Never an argument, which other users of the host can read.
+    #[clap(long)]
+    token_file: Option<PathBuf>,
+
+    /// file of the secret signing writes, the HMAC_SECRET of servers requiring
+    /// signatures; SIMPLY_SIGNING_SECRET without it
+    #[clap(long)]
+    signing_secret_file: Option<PathBuf>,
+}
+
+/// Secret read from `file`, else from the environment variable `var`, if set
+fn read_secret(file: Option<&PathBuf>, var: &str) -> app::Result<Option<String>> {
+    let secret = match file {
+        Some(file) => std::fs::read_to_string(file)
+            .map_err(|err| app::AppError::FileError(format!("{}: {}", file.display(), err)))?,
+        None => std::env::var(var).unwrap_or_default(),
+    };
+
+    Ok(Some(secret.trim().to_owned()).filter(|secret| !secret.is_empty()))
+}
+
+impl Cli {
+    fn client_tls(&self) -> ClientTls {
+        ClientTls {
+            ca: self.ca.clone(),
+            cert: self.cert.clone(),
+            key: self.key.clone(),
+        }
+    }
+
+    fn scheme(&self) -> &'static str {
+        match self.tls || self.client_tls().is_configured() {
+            true => "https",
+            false => "http",
+        }
+    }
 }
 
 #[allow(clippy::enum_variant_names)]
@@ -54,6 +125,10 @@ enum Command {
 
         /// value
         value: String,
+
+        /// expire the key after ttl seconds
+        #[clap(long)]
+        ttl: Option<u64>,
     },
 
     /// Get value by key
@@ -62,6 +137,155 @@ enum Command {
         /// key
         key: String,
     },
+
+    /// Set many key-value pairs, e.g. mset foo=1 bar=2
+    #[command(name = "mset", arg_required_else_help = true)]
+    MSet {
+        /// key=value pairs
+        #[arg(value_parser = parse_key_value)]
+        pairs: Vec<(String, String)>,
+    },
+
+    /// Get values of many keys, e.g. mget foo bar
+    #[command(name = "mget", arg_required_else_help = true)]
+    MGet {
+        /// keys
+        keys: Vec<String>,
+    },
+
+    /// Memory usage, evictions and compression of the key-value store, per server
+    Stats,
+
+    /// Upload a file, e.g. upload ./report.pdf, or from stdin: upload - --name report.pdf
+    #[command(arg_required_else_help = true)]
+    Upload {
+        /// file to upload, - for stdin
+        path: PathBuf,
+
+        /// name of the uploaded file; defaults to the file name of path
+        #[clap(long)]
+        name: Option<String>,
+
+        /// content type, e.g. application/pdf; sniffed by the server if not given
+        #[clap(long = "type")]
+        file_type: Option<String>,
+    },
+
+    /// Download a file, e.g. download report.pdf -o ./report.pdf
+    #[command(arg_required_else_help = true)]
+    Download {
+        /// name of the file
+        name: String,
+
+        /// where to save the file, - for stdout; defaults to name
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Upload `path`, or stdin if `-`
+async fn upload(
+    client: &mut Client,
+    path: PathBuf,
+    name: Option<String>,
+    file_type: Option<String>,
+) -> app::Result<()> {
+    let from_stdin = path.as_os_str() == "-";
+    let name = match name.or_else(|| {
+        path.file_name()
+            .filter(|_| !from_stdin)
+            .map(|name| name.to_string_lossy().into_owned())
+    }) {
+        Some(name) => name,
+        None => {
+            return Err(app::AppError::InvalidArgument {
+                field: "name".to_owned(),
+                reason: "required when uploading from stdin".to_owned(),
+            })
+        }
+    };
+
+    let status = if from_stdin && client.is_signing() {
+        // signatures cover the sha256 of the file, known once stdin is read whole
+        let progress = progress_bar(Some(0));
+        client
+            .upload_buffered(
+                tokio::io::stdin(),
+                name,
+                file_type.unwrap_or_default(),
+                progress,
+            )
+            .await?
+    } else if from_stdin {
+        let progress = progress_bar(None);
+        client
+            .upload(
+                tokio::io::stdin(),
+                name,
+                file_type.unwrap_or_default(),
+                None,
+                None,
+                progress,
+            )
+            .await?
+    } else {
+        // length set once known
+        let progress = progress_bar(Some(0));
+        client
+            .upload_file(&path, name, file_type.unwrap_or_default(), progress)
+            .await?
+    };
+
+    eprintln!("{} sha256 {}", status.message.green(), status.sha256);
+    Ok(())
+}
+
+/// Download `name` into `output`, or stdout if `-`. A file is written next to
+/// `output`, and moved into place once its checksum is verified.
+async fn download(client: &mut Client, name: String, output: Option<PathBuf>) -> app::Result<()> {
+    let output = output.unwrap_or_else(|| PathBuf::from(&name));
+    // length set once known
+    let progress = progress_bar(Some(0));
+
+    if output.as_os_str() == "-" {
+        let written = client.download(name, tokio::io::stdout(), progress).await?;
+        eprintln!("{}", format!("{} bytes verified", written).green());
+        return Ok(());
+    }
+
+    let file_error =
+        |err: std::io::Error| app::AppError::FileError(format!("{}: {}", output.display(), err));
+    let mut partial = output.clone().into_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    let file = tokio::fs::File::create(&partial)
+        .await
+        .map_err(file_error)?;
+    match client.download(name, file, progress).await {
+        Ok(written) => {
+            tokio::fs::rename(&partial, &output)
+                .await
+                .map_err(file_error)?;
+            eprintln!(
+                "{}",
+                format!("{} bytes verified, saved to {}", written, output.display()).green()
+            );
+            Ok(())
+        }
+        Err(err) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            Err(err)
+        }
+    }
+}
+
+/// Parse a `key=value` pair
+fn parse_key_value(pair: &str) -> Result<(String, String), String> {
+    match pair.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("invalid key=value pair: `{}`", pair)),
+    }
 }
 
 /// Entry point for CLI tool.
@@ -76,7 +300,6 @@ enum Command {
 #[cfg(feature = "cli")]
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> app::Result<()> {
-    use colored::*;
     use tracing::info;
 
     app::clients::set_up_logging()?;
@@ -84,15 +307,38 @@ async fn main() -> app::Result<()> {
     // Parse command line arguments
     let cli = Cli::parse();
 
-    // Get the remote address to connect to
-    let addr = format!("http://0.0.0.0:{}", cli.port);
+    // Get the remote address(es) to connect to
+    let tls = cli.client_tls();
+    let scheme = cli.scheme();
+    let connected = if cli.servers.is_empty() {
+        let addr = format!("{}://{}:{}", scheme, cli.host, cli.port);
+        info!(message = format!("{}", "Connecting".blue()), addr);
+
+        Client::connect_tls(addr, tls).await
+    } else {
+        let servers = cli
+            .servers
+            .iter()
+            .map(|server| match server.contains("://") {
+                true => server.clone(),
+                false => format!("{}://{}", scheme, server),
+            })
+            .collect::<Vec<_>>();
+        info!(message = format!("{}", "Connecting".blue()), servers = ?servers);
 
-    info!(message = format!("{}", "Connecting".blue()), addr);
+        Client::connect_many_tls(servers, tls).await
+    };
 
-    let mut client = match Client::connect(addr).await {
+    let mut client = match connected {
         Ok(client) => client,
-        Err(_) => panic!("{}", "failed to establish connection".red()),
+        Err(err) => panic!("{}: {}", "failed to establish connection".red(), err),
     };
+    if let Some(token) = read_secret(cli.token_file.as_ref(), "SIMPLY_TOKEN")? {
+        client = client.with_token(&token)?;
+    }
+    if let Some(secret) = read_secret(cli.signing_secret_file.as_ref(), "SIMPLY_SIGNING_SECRET")? {
+        client = client.with_signing_secret(&secret);
+    }
 
     match cli.command {
         Command::StreamEcho { num } => {
@@ -107,12 +353,31 @@ async fn main() -> app::Result<()> {
         Command::ClientStreamEcho { num } => {
             client.client_streaming_echo(num).await;
         }
-        Command::Set { key, value } => {
-            client.set_value(key, value).await;
+        Command::Set { key, value, ttl } => {
+            client.set_value(key, value, ttl).await;
         }
         Command::Get { key } => {
             client.get_value(key).await;
         }
+        Command::MSet { pairs } => {
+            client.set_values(pairs).await;
+        }
+        Command::MGet { keys } => {
+            client.get_values(keys).await;
+        }
+        Command::Stats => {
+            client.stats().await;
+        }
+        Command::Upload {
+            path,
+            name,
+            file_type,
+        } => {
+            upload(&mut client, path, name, file_type).await?;
+        }
+        Command::Download { name, output } => {
+            download(&mut client, name, output).await?;
+        }
     }
 
     app::clients::shutdown_tracer_provider();
@@ -154,7 +419,7 @@ fn test_cli_unary_echo() {
 
             let simply_server = EchoServerBuilder::default()
                 .person(person_repository)
-                .connection(<InMemoryDatabase as Connection>::new().await)
+                .connection(<InMemoryDatabase as Connection>::new().await.unwrap())
                 .build()
                 .unwrap();
 
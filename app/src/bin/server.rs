@@ -6,13 +6,28 @@
 // cargo build --release --bin simply-server
 // cargo run --bin simply-server
 // ./simply-server --port 50051
+// ./simply-server migrate status
+// ./simply-server hash-token
+// ./simply-server hash-token --stdin < ./token
+// ./simply-server import-files
 extern crate derive_builder;
 
 use app::{
-    models::PersonRepository, protobuffer, server::EchoServerBuilder, Connection, InMemoryDatabase,
-    Settings, DEFAULT_PORT, GLOBAL_SETTINGS,
+    migrations::{self, MigrationState},
+    models::{FileStore, Ledger, PersonRepository},
+    protobuffer,
+    replicas::{self, Replication, Role},
+    server::{
+        hash_token, payments_admin_enabled, reflection_enabled, reflection_service, Authenticator,
+        Authorizer, EchoServerBuilder, GuploadServerBuilder, HealthMonitor, PaymentServerBuilder,
+        PersonServerBuilder, ReplicationServerBuilder,
+    },
+    signing::{SignatureLayer, Verifier},
+    tls::{self, ServerTls},
+    AppError, Connection, InMemoryDatabase, RemoteDatabase, Settings, DEFAULT_PORT,
+    GLOBAL_SETTINGS,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colored::*;
 
 #[derive(Parser, Debug)]
@@ -20,13 +35,136 @@ use colored::*;
 struct Cli {
     #[clap(long, default_value_t = DEFAULT_PORT)]
     port: u16,
+
+    /// replicate from a leader, e.g. --follow http://127.0.0.1:50051
+    #[clap(long)]
+    follow: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Schema migrations of the SurrealDb at SURREALDB_HOST, e.g. migrate status
+    #[command(arg_required_else_help = true)]
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+
+    /// Hash a token for AUTH_TOKENS; a random token is generated unless --stdin
+    HashToken {
+        /// read the token from stdin; never an argument, which other users of the
+        /// host can read
+        #[clap(long)]
+        stdin: bool,
+    },
+
+    /// Move files stored flat in UPLOAD_DIR, before the blob store, into it
+    ImportFiles,
+}
+
+#[derive(Debug, Subcommand)]
+enum MigrateAction {
+    /// List migrations, and whether they are applied
+    Status,
+
+    /// Apply pending migrations
+    Up,
+}
+
+/// Run a `migrate` subcommand against the persistent database. The embedded one
+/// starts empty with every server, and is migrated at startup.
+async fn migrate(action: MigrateAction) -> app::Result<()> {
+    let database = <RemoteDatabase as Connection>::new().await?.db()?;
+
+    match action {
+        MigrateAction::Status => {
+            for status in migrations::status(&database).await? {
+                let state = match status.state {
+                    MigrationState::Applied { .. } => status.state.to_string().green(),
+                    MigrationState::Pending => status.state.to_string().yellow(),
+                    _ => status.state.to_string().red(),
+                };
+                println!("{:>4}  {:<16} {}", status.version, status.name, state);
+            }
+        }
+        MigrateAction::Up => {
+            let versions = migrations::up(&database).await?;
+            println!("applied {} migration(s): {:?}", versions.len(), versions);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the token read from stdin, or a new random one, and its hash
+fn print_token_hash(stdin: bool) -> app::Result<()> {
+    use rand::{distributions::Alphanumeric, Rng};
+
+    let token = match stdin {
+        true => {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            let token = line.trim().to_owned();
+            if token.is_empty() {
+                return Err(AppError::InvalidArgument {
+                    field: "token".to_owned(),
+                    reason: "no token on stdin".to_owned(),
+                });
+            }
+            token
+        }
+        false => rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(40)
+            .map(char::from)
+            .collect(),
+    };
+    println!("token:  {}", token);
+    println!("sha256: {}", hash_token(&token));
+    Ok(())
+}
+
+/// Hint printed below a startup error
+fn hint(err: &AppError) -> Option<&'static str> {
+    match err {
+        AppError::InvalidSetting { key, .. } if key == "AUTH_TOKENS" => {
+            Some("add tokens hashed by `simply-server hash-token`, or set AUTH_DISABLED = \"true\"")
+        }
+        AppError::InvalidSetting { .. } => {
+            Some("check env.toml, or the APP_ environment variables")
+        }
+        AppError::DbAuthError { .. } => Some("check SURREALDB_USERNAME and SURREALDB_PASSWORD"),
+        AppError::DbUnreachable { .. } => {
+            Some("check SURREALDB_HOST and SURREALDB_PORT, and that SurrealDb is running")
+        }
+        AppError::MigrationDrift { .. } => {
+            Some("an applied migration was edited; restore it, and add a new migration instead")
+        }
+        AppError::TlsError(_) => Some("check TLS_CERT_PATH, TLS_KEY_PATH and TLS_CLIENT_CA_PATH"),
+        _ => None,
+    }
 }
 
 #[cfg(feature = "server")]
 #[tokio::main]
-async fn main() -> app::Result<()> {
+async fn main() {
+    if let Err(err) = run().await {
+        eprintln!("{} {}", "simply-server:".red().bold(), err);
+        if let Some(hint) = hint(&err) {
+            eprintln!("  {}", hint.yellow());
+        }
+        std::process::exit(err.exit_code());
+    }
+}
+
+#[cfg(feature = "server")]
+async fn run() -> app::Result<()> {
+    use futures::FutureExt;
     use tonic::transport::Server;
-    use tracing::info;
+    use tracing::{error, info};
 
     app::server::set_up_logging()?;
 
@@ -35,31 +173,233 @@ async fn main() -> app::Result<()> {
 
     let cli = Cli::parse();
 
-    let person_repository = PersonRepository::default();
+    if let Some(Command::HashToken { stdin }) = cli.command {
+        return print_token_hash(stdin);
+    }
+
+    if let Some(Command::Migrate { action }) = cli.command {
+        return migrate(action).await;
+    }
+
+    if let Some(Command::ImportFiles) = cli.command {
+        let imported = FileStore::from_settings().await?.import_files().await?;
+        println!("imported {} file(s) into the blob store", imported);
+        return Ok(());
+    }
+
+    let database = <InMemoryDatabase as Connection>::new().await?;
+    // the embedded database starts empty: this creates the schema, drift is only
+    // detected by `migrate` against the persistent database
+    let versions = migrations::up(&database.db).await?;
+    info!(
+        "{}",
+        format!("Schema up to date, applied {:?}", versions).blue()
+    );
+
+    let role = match cli.follow {
+        Some(leader) => Role::Follower { leader },
+        None => Role::from_settings().await,
+    };
+    info!("{}", format!("Replication role: {:?}", role).blue());
+    let replication = Replication::new(role);
+    // publishes its writes, when leader
+    let person_repository = PersonRepository::from_settings()
+        .await?
+        .with_replication(replication.clone());
+
+    // bearer tokens or API keys of AUTH_TOKENS; every caller is let in only if
+    // AUTH_DISABLED = "true"
+    let auth = Authenticator::from_settings().await?;
+    info!(
+        "{}",
+        format!("Token authentication enabled: {}", auth.is_enabled()).blue()
+    );
+    tokio::spawn(auth.clone().watch_settings());
+    // roles of the token ids, per RPC and key prefix; off unless AUTH_GRANTS is set
+    let authz = Authorizer::from_settings().await?;
+    tokio::spawn(authz.clone().watch_settings());
+    // writes must be signed with HMAC_SECRET, if set
+    let signatures = Verifier::from_settings().await?;
+    info!(
+        "{}",
+        format!("Signed writes required: {}", signatures.is_some()).blue()
+    );
 
     let simply_server = EchoServerBuilder::default()
-        .person(person_repository)
-        .connection(<InMemoryDatabase as Connection>::new().await)
+        .person(person_repository.clone())
+        .connection(database.get_db())
+        .replication(replication.clone())
+        .authz(authz.clone())
+        .build()
+        .unwrap();
+
+    let replication_server = ReplicationServerBuilder::default()
+        .person(person_repository.clone())
+        .connection(database.get_db())
+        .replication(replication.clone())
+        .authz(authz.clone())
+        .build()
+        .unwrap();
+
+    tokio::spawn(
+        person_repository
+            .clone()
+            .watch_key_rotation(database.get_db()),
+    );
+    // removes keys once their time to live elapses; followers apply the deletes
+    if replication.leader().is_none() {
+        tokio::spawn(person_repository.clone().watch_expiry(database.get_db()));
+    }
+
+    let person_server = PersonServerBuilder::default()
+        .person(person_repository.clone())
+        .connection(database.get_db())
+        .replication(replication.clone())
+        .build()
+        .unwrap();
+
+    let ledger = Ledger::from_settings().await?;
+    info!(
+        "{}",
+        format!("Payments on bitcoin {}", ledger.network()).blue()
+    );
+    // confirms payments of the mempool; followers are read-only, and mine no blocks
+    if replication.leader().is_none() {
+        tokio::spawn(ledger.clone().watch_blocks(database.get_db()));
+    }
+    let payment_server = PaymentServerBuilder::default()
+        .ledger(ledger.clone())
+        .connection(database.get_db())
+        .replication(replication.clone())
         .build()
         .unwrap();
+    // seeds balances; off unless PAYMENTS_ADMIN = "true"
+    let ledger_admin = match payments_admin_enabled().await {
+        true => Some(
+            protobuffer::payments::ledger_admin_server::LedgerAdminServer::with_interceptor(
+                PaymentServerBuilder::default()
+                    .ledger(ledger)
+                    .connection(database.get_db())
+                    .replication(replication.clone())
+                    .authz(authz)
+                    .build()
+                    .unwrap(),
+                auth.clone(),
+            ),
+        ),
+        false => None,
+    };
+
+    let file_store = FileStore::from_settings().await?;
+    tokio::spawn(file_store.clone().watch_storage());
+    let gupload_server = GuploadServerBuilder::default()
+        .files(file_store)
+        .replication(replication.clone())
+        .build()
+        .unwrap();
+
+    // no-op when running as leader
+    tokio::spawn(replicas::follow(
+        replication,
+        person_repository,
+        database.get_db(),
+    ));
+
+    // lets grpcurl and Postman discover the services; off unless GRPC_REFLECTION = "true"
+    let reflection = match reflection_enabled().await {
+        true => Some(reflection_service()?),
+        false => None,
+    };
+
+    // grpc.health.v1, NOT_SERVING while the database is unhealthy
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    let health = HealthMonitor::new(health_reporter);
+    tokio::spawn(health.clone().watch_database(database.get_db()));
 
     let graceful_shutdown = async {
         if let Ok(result) = tokio::signal::ctrl_c().await {
-            // TODO: add logic
             info!("{}", "gracefully shutting down".green());
+            health.shutdown().await;
             result
         }
     };
-    let addr = format!("0.0.0.0:{}", cli.port).parse().unwrap();
+    let addr: std::net::SocketAddr = format!("0.0.0.0:{}", cli.port).parse().unwrap();
     // let addr = format!("[::1]:{}", cli.port).parse().unwrap();
 
     info!("{}", format!("Server listening on {:?}", addr).blue());
 
-    let server = Server::builder()
+    // plaintext unless TLS_CERT_PATH and TLS_KEY_PATH are set
+    let tls = ServerTls::from_settings().await?;
+    if let Some(tls) = &tls {
+        info!(
+            "{}",
+            format!(
+                "TLS with {}, client certificates {}",
+                tls.cert.display(),
+                match &tls.client_ca {
+                    Some(client_ca) => format!("verified by {}", client_ca.display()),
+                    None => "not required".to_owned(),
+                }
+            )
+            .blue()
+        );
+    }
+
+    let router = Server::builder()
+        // signed writes are verified before any service decodes them
+        .layer(SignatureLayer::new(signatures))
         // FIXME: this is not useful
         // .trace_fn(|_| info_span!("serving_echo_server"))
-        .add_service(protobuffer::echo_server::EchoServer::new(simply_server))
-        .serve_with_shutdown(addr, graceful_shutdown);
+        // health and reflection stay open, for probes and tooling
+        .add_service(health_service)
+        .add_service(protobuffer::echo_server::EchoServer::with_interceptor(
+            simply_server,
+            auth.clone(),
+        ))
+        .add_optional_service(reflection)
+        .add_service(
+            protobuffer::replication::replication_server::ReplicationServer::with_interceptor(
+                replication_server,
+                auth.clone(),
+            ),
+        )
+        .add_service(
+            protobuffer::person::person_service_server::PersonServiceServer::with_interceptor(
+                person_server,
+                auth.clone(),
+            ),
+        )
+        .add_service(
+            protobuffer::gupload::gupload_service_server::GuploadServiceServer::with_interceptor(
+                gupload_server,
+                auth.clone(),
+            ),
+        )
+        .add_service(
+            protobuffer::payments::bitcoin_server::BitcoinServer::with_interceptor(
+                payment_server,
+                auth,
+            ),
+        )
+        .add_optional_service(ledger_admin);
+
+    let server = match tls {
+        Some(tls) => {
+            let (acceptor, resolver) = tls.acceptor()?;
+            // swaps the certificate of new connections when its files change
+            tokio::spawn(async move {
+                if let Err(err) = tls.watch(resolver).await {
+                    error!(error = format!("TLS watch error, {:?}", err));
+                }
+            });
+
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            router
+                .serve_with_incoming_shutdown(tls::incoming(listener, acceptor), graceful_shutdown)
+                .boxed()
+        }
+        None => router.serve_with_shutdown(addr, graceful_shutdown).boxed(),
+    };
 
     tokio::spawn(async {
         if let Err(err) = GLOBAL_SETTINGS.watch().await {
@@ -73,6 +413,6 @@ async fn main() -> app::Result<()> {
             app::server::shutdown_tracer_provider();
             Ok(())
         }
-        Err(err) => Err(app::AppError::TonicError(err)),
+        Err(err) => Err(AppError::TonicError(err)),
     }
 }
@@ -1,14 +1,25 @@
 // NOTE:
 // https://github.com/open-telemetry/opentelemetry-rust/blob/main/examples/tracing-grpc/src/client.rs
 
+use super::{Credentials, HashRing};
 use crate::{
-    protobuffer::{echo_client::EchoClient, EchoRequest, KeyValueRequest},
+    protobuffer::{
+        echo_client::EchoClient, gupload::gupload_service_client::GuploadServiceClient,
+        EchoRequest, KeyValueRequest, KeyValueResponse, StatsRequest,
+    },
+    replicas::LEADER_METADATA_KEY,
+    signing::{Signer, SET_VALUE_METHOD},
+    tls::ClientTls,
     AppError,
 };
 use colored::*;
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 use tokio_stream::{Stream, StreamExt};
-use tonic::{codegen::StdError, transport::Channel, Request};
+use tonic::{
+    codegen::{InterceptedService, StdError},
+    transport::{Channel, Endpoint},
+    Request, Response, Status,
+};
 use tracing::{error, info, instrument};
 #[cfg(feature = "otel")]
 use tracing::{info_span, Instrument};
@@ -30,9 +41,21 @@ impl<'a> opentelemetry::propagation::Injector for MetadataMap<'a> {
     }
 }
 
+/// Channel attaching the credentials of the client
+pub(super) type AuthChannel = InterceptedService<Channel, Credentials>;
+
+/// Simply client. Key-value commands are routed to one of the connected servers,
+/// by a consistent-hash ring over the keys; echo and file commands go to the first
+/// server.
 #[cfg_attr(feature = "cli", derive(Debug))]
 pub struct Client {
-    echo_client: EchoClient<Channel>,
+    echo_client: EchoClient<AuthChannel>,
+    pub(super) gupload_client: GuploadServiceClient<AuthChannel>,
+    shards: HashMap<String, EchoClient<AuthChannel>>,
+    ring: HashRing,
+    tls: ClientTls,
+    credentials: Credentials,
+    pub(super) signer: Option<Signer>,
 }
 
 impl Client {
@@ -41,12 +64,160 @@ impl Client {
         D: TryInto<tonic::transport::Endpoint>,
         D::Error: Into<StdError>,
     {
-        match EchoClient::connect(addr).await {
-            Ok(echo_client) => Ok(Client { echo_client }),
+        let endpoint = Endpoint::new(addr).map_err(AppError::TonicError)?;
+        let tls = ClientTls::default();
+        let node = Self::node(&endpoint.uri().to_string(), &tls);
+        let endpoint = tls.endpoint(endpoint)?;
+
+        match endpoint.connect().await {
+            Ok(channel) => Ok(Client::from_shards(vec![(node, channel)], tls)),
+            Err(err) => Err(AppError::TonicError(err)),
+        }
+    }
+
+    /// Connect to an `https://` server, verified by the CA of `tls`, presenting its
+    /// client certificate if any (mTLS)
+    pub async fn connect_tls(addr: impl ToString, tls: ClientTls) -> crate::Result<Client> {
+        Self::connect_many_tls([addr], tls).await
+    }
+
+    /// Connect to every server of a sharded deployment, e.g. `["a:50051", "b:50051"]`.
+    /// Addresses without scheme default to `http://`.
+    pub async fn connect_many<I>(addrs: I) -> crate::Result<Client>
+    where
+        I: IntoIterator,
+        I::Item: ToString,
+    {
+        Self::connect_many_tls(addrs, ClientTls::default()).await
+    }
+
+    /// Like `connect_many`, over TLS; addresses without scheme default to `https://`
+    pub async fn connect_many_tls<I>(addrs: I, tls: ClientTls) -> crate::Result<Client>
+    where
+        I: IntoIterator,
+        I::Item: ToString,
+    {
+        let mut shards = Vec::new();
+        for addr in addrs {
+            shards.push(Self::connect_shard(addr.to_string(), &tls).await?);
+        }
+
+        if shards.is_empty() {
+            return Err(AppError::NoServerAddress);
+        }
+
+        Ok(Client::from_shards(shards, tls))
+    }
+
+    fn from_shards(shards: Vec<(String, Channel)>, tls: ClientTls) -> Client {
+        let mut ring = HashRing::default();
+        for (node, _) in shards.iter() {
+            ring.add(node);
+        }
+
+        let credentials = Credentials::default();
+        Client {
+            echo_client: EchoClient::with_interceptor(shards[0].1.clone(), credentials.clone()),
+            gupload_client: GuploadServiceClient::with_interceptor(
+                shards[0].1.clone(),
+                credentials.clone(),
+            ),
+            shards: shards
+                .into_iter()
+                .map(|(node, channel)| {
+                    (
+                        node,
+                        EchoClient::with_interceptor(channel, credentials.clone()),
+                    )
+                })
+                .collect(),
+            ring,
+            tls,
+            credentials,
+            signer: None,
+        }
+    }
+
+    /// Send `token` as a bearer token with every request, to every server
+    pub fn with_token(self, token: &str) -> crate::Result<Self> {
+        self.credentials.set_token(token)?;
+        Ok(self)
+    }
+
+    /// Sign writes and uploads with `secret`, the HMAC_SECRET of the servers
+    pub fn with_signing_secret(mut self, secret: &str) -> Self {
+        self.signer = Some(Signer::new(secret));
+        self
+    }
+
+    /// Whether writes and uploads are signed
+    pub fn is_signing(&self) -> bool {
+        self.signer.is_some()
+    }
+
+    fn sign_write(&self, request: &mut Request<KeyValueRequest>) {
+        if let Some(signer) = &self.signer {
+            signer.sign_request(SET_VALUE_METHOD, request);
+        }
+    }
+
+    /// Ring node of `addr`: with a scheme, `https://` over TLS, without trailing `/`
+    fn node(addr: &str, tls: &ClientTls) -> String {
+        let addr = addr.trim().trim_end_matches('/');
+        match (addr.contains("://"), tls.is_configured()) {
+            (true, _) => addr.to_owned(),
+            (false, true) => format!("https://{}", addr),
+            (false, false) => format!("http://{}", addr),
+        }
+    }
+
+    async fn connect_shard(addr: String, tls: &ClientTls) -> crate::Result<(String, Channel)> {
+        let addr = Self::node(&addr, tls);
+        let endpoint = Endpoint::new(addr.clone()).map_err(AppError::TonicError)?;
+        let endpoint = tls.endpoint(endpoint)?;
+        match endpoint.connect().await {
+            Ok(channel) => Ok((addr, channel)),
             Err(err) => Err(AppError::TonicError(err)),
         }
     }
 
+    /// Add a server to the ring. Only the keys it now owns move to it.
+    pub async fn add_server(&mut self, addr: impl ToString) -> crate::Result<()> {
+        let (node, channel) = Self::connect_shard(addr.to_string(), &self.tls).await?;
+        self.ring.add(&node);
+        self.shards.insert(
+            node,
+            EchoClient::with_interceptor(channel, self.credentials.clone()),
+        );
+
+        Ok(())
+    }
+
+    /// Remove a server from the ring, given as to `add_server`. Its keys move to the
+    /// remaining servers.
+    pub fn remove_server(&mut self, addr: &str) -> crate::Result<()> {
+        let node = Self::node(addr, &self.tls);
+        if !self.shards.contains_key(&node) {
+            return Err(AppError::UnknownServer(node));
+        }
+        if self.shards.len() == 1 {
+            return Err(AppError::LastServer(node));
+        }
+
+        self.ring.remove(&node);
+        self.shards.remove(&node);
+        Ok(())
+    }
+
+    /// Server owning `key`
+    fn shard(&self, key: &str) -> EchoClient<AuthChannel> {
+        self.ring
+            .get(key)
+            .and_then(|node| self.shards.get(node))
+            .unwrap_or(&self.echo_client)
+            .clone()
+    }
+
     // infinite iterator of EchoRequests
     fn echo_requests_iter() -> impl Stream<Item = EchoRequest> {
         tokio_stream::iter(1..usize::MAX).map(|i| EchoRequest {
@@ -71,8 +242,11 @@ impl Client {
 
     #[instrument(skip(self, key), name = "command_get_value")]
     pub async fn get_value(&mut self, key: String) {
-        let mut request: Request<KeyValueRequest> =
-            Request::new(KeyValueRequest { key, value: None });
+        let mut request: Request<KeyValueRequest> = Request::new(KeyValueRequest {
+            key,
+            value: None,
+            ttl: None,
+        });
 
         info!(
             message = format!("{}", "Sending get_value request".blue()),
@@ -83,13 +257,13 @@ impl Client {
 
         #[cfg(feature = "otel")]
         let submit_get_value_request = self
-            .echo_client
+            .shard(&request.get_ref().key)
             .get_value(request)
             .instrument(info_span!("submit_get_value_request"))
             .await;
 
         #[cfg(not(feature = "otel"))]
-        let submit_get_value_request = self.echo_client.get_value(request).await;
+        let submit_get_value_request = self.shard(&request.get_ref().key).get_value(request).await;
 
         match submit_get_value_request {
             Ok(response) => {
@@ -107,11 +281,13 @@ impl Client {
         }
     }
 
+    /// Set `key` to `value`, expiring after `ttl` seconds if given
     #[instrument(skip(self, key, value), name = "command_set_value")]
-    pub async fn set_value(&mut self, key: String, value: String) {
+    pub async fn set_value(&mut self, key: String, value: String, ttl: Option<u64>) {
         let mut request = Request::new(KeyValueRequest {
             key,
             value: Some(value),
+            ttl,
         });
 
         info!(
@@ -120,16 +296,17 @@ impl Client {
         );
 
         Self::inject_context(&mut request);
+        self.sign_write(&mut request);
 
         #[cfg(feature = "otel")]
         let submit_set_value_request = self
-            .echo_client
+            .shard(&request.get_ref().key)
             .set_value(request)
             .instrument(info_span!("submit_set_value_request"))
             .await;
 
         #[cfg(not(feature = "otel"))]
-        let submit_set_value_request = self.echo_client.set_value(request).await;
+        let submit_set_value_request = self.shard(&request.get_ref().key).set_value(request).await;
 
         match submit_set_value_request {
             Ok(response) => {
@@ -143,7 +320,113 @@ impl Client {
                 );
                 println!("\n{message}");
             }
-            Err(err) => error!(error = format!("{:?}", err)),
+            Err(err) => match err.metadata().get(LEADER_METADATA_KEY) {
+                // write was sent to a read-only follower
+                Some(leader) => println!(
+                    "\n{}",
+                    format!(
+                        "write rejected by follower, retry against leader {}",
+                        leader.to_str().unwrap_or_default()
+                    )
+                    .red()
+                ),
+                None => error!(error = format!("{:?}", err)),
+            },
+        }
+    }
+
+    /// Get the values of many keys. Requests are fanned out to the owning servers
+    /// concurrently; results are printed in the order of `keys`.
+    #[instrument(skip(self, keys), name = "command_get_values")]
+    pub async fn get_values(&mut self, keys: Vec<String>) {
+        info!(
+            message = format!("{}", "Sending get_value requests".blue()),
+            keys = keys.len(),
+        );
+
+        let requests = keys.iter().map(|key| {
+            let mut echo_client = self.shard(key);
+            let mut request = Request::new(KeyValueRequest {
+                key: key.to_owned(),
+                value: None,
+                ttl: None,
+            });
+            Self::inject_context(&mut request);
+
+            async move { echo_client.get_value(request).await }
+        });
+        let responses = futures::future::join_all(requests).await;
+
+        for (key, response) in keys.iter().zip(responses) {
+            Self::print_key_value_response(key, response);
+        }
+    }
+
+    /// Set many key-value pairs. Requests are fanned out to the owning servers
+    /// concurrently.
+    #[instrument(skip(self, pairs), name = "command_set_values")]
+    pub async fn set_values(&mut self, pairs: Vec<(String, String)>) {
+        info!(
+            message = format!("{}", "Sending set_value requests".blue()),
+            keys = pairs.len(),
+        );
+
+        let requests = pairs.iter().map(|(key, value)| {
+            let mut echo_client = self.shard(key);
+            let mut request = Request::new(KeyValueRequest {
+                key: key.to_owned(),
+                value: Some(value.to_owned()),
+                ttl: None,
+            });
+            Self::inject_context(&mut request);
+            self.sign_write(&mut request);
+
+            async move { echo_client.set_value(request).await }
+        });
+        let responses = futures::future::join_all(requests).await;
+
+        for ((key, _), response) in pairs.iter().zip(responses) {
+            Self::print_key_value_response(key, response);
+        }
+    }
+
+    /// Print the memory usage, evictions and compression ratio of every server
+    #[instrument(skip(self), name = "command_stats")]
+    pub async fn stats(&mut self) {
+        let mut nodes: Vec<&String> = self.shards.keys().collect();
+        nodes.sort();
+
+        for node in nodes {
+            match self.shards[node].clone().get_stats(StatsRequest {}).await {
+                Ok(response) => {
+                    let stats = response.into_inner();
+                    println!(
+                        "{}: {} keys, {} of {} bytes used, {} evicted, \
+                         {} values compressed {:.2}x",
+                        node,
+                        stats.keys,
+                        stats.used_memory,
+                        match stats.max_memory {
+                            0 => "unlimited".to_owned(),
+                            max_memory => max_memory.to_string(),
+                        },
+                        stats.evicted_keys,
+                        stats.compressed_values,
+                        stats.compression_ratio
+                    );
+                }
+                Err(err) => println!("{}: {}", node, err.message().red()),
+            }
+        }
+    }
+
+    fn print_key_value_response(key: &str, response: Result<Response<KeyValueResponse>, Status>) {
+        match response {
+            Ok(response) => match response.get_ref().error.clone() {
+                Some(err) => println!("{}: {}", key, err.red()),
+                None => println!("{}: {}", key, response.get_ref().status),
+            },
+            Err(err) => println!("{}: {}", key, err.message().red()),
         }
     }
 
@@ -254,3 +537,26 @@ impl Client {
         }
     }
 }
+
+#[test]
+fn test_node() {
+    let plaintext = ClientTls::default();
+    let tls = ClientTls {
+        ca: Some("ca.pem".into()),
+        ..Default::default()
+    };
+
+    assert_eq!(Client::node("a:50051", &plaintext), "http://a:50051");
+    assert_eq!(
+        Client::node("http://a:50051/", &plaintext),
+        "http://a:50051"
+    );
+    assert_eq!(Client::node("a:50051", &tls), "https://a:50051");
+    assert_eq!(
+        Client::node(
+            &Endpoint::from_static("http://a:50051").uri().to_string(),
+            &plaintext
+        ),
+        Client::node("a:50051", &plaintext)
+    );
+}
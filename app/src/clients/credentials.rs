@@ -0,0 +1,48 @@
+use crate::AppError;
+use std::sync::{Arc, RwLock};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    service::Interceptor,
+    Request, Status,
+};
+
+/// Interceptor attaching "authorization: Bearer <token>" to every request, once a
+/// token is set. Clones share the token.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    authorization: Arc<RwLock<Option<MetadataValue<Ascii>>>>,
+}
+
+impl Credentials {
+    pub fn set_token(&self, token: &str) -> crate::Result<()> {
+        let authorization =
+            format!("Bearer {}", token.trim())
+                .parse()
+                .map_err(|_| AppError::InvalidArgument {
+                    field: "token".to_owned(),
+                    reason: "must be visible ASCII".to_owned(),
+                })?;
+
+        *self
+            .authorization
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(authorization);
+        Ok(())
+    }
+}
+
+impl Interceptor for Credentials {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let authorization = self
+            .authorization
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        if let Some(authorization) = authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization);
+        }
+        Ok(request)
+    }
+}
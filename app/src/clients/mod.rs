@@ -9,5 +9,23 @@ mod client;
 #[cfg(feature = "cli")]
 pub use client::Client;
 
+#[cfg(feature = "cli")]
+mod credentials;
+
+#[cfg(feature = "cli")]
+pub use credentials::Credentials;
+
+#[cfg(feature = "cli")]
+mod transfer;
+
+#[cfg(feature = "cli")]
+pub use transfer::{progress_bar, UPLOAD_CHUNK_SIZE};
+
+#[cfg(feature = "cli")]
+mod ring;
+
+#[cfg(feature = "cli")]
+pub use ring::HashRing;
+
 mod setup_logging;
 pub use setup_logging::{set_up_logging, shutdown_tracer_provider};
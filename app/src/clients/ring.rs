@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+/// Number of virtual nodes placed on the ring, per server
+pub const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+/// Consistent-hash ring, mapping keys to nodes.
+///
+/// Every node is placed on the ring `virtual_nodes` times, which evens out the
+/// share of keys each node owns. Adding or removing a node only moves the keys
+/// owned by that node; all other keys stay where they are.
+///
+/// # Example
+///
+/// ```
+/// use app::clients::HashRing;
+///
+/// let mut ring = HashRing::new(64);
+/// ring.add("http://10.0.0.1:50051");
+/// ring.add("http://10.0.0.2:50051");
+///
+/// assert!(ring.get("foo").is_some());
+/// ```
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    ring: BTreeMap<u64, String>,
+    nodes: Vec<String>,
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        Self::new(DEFAULT_VIRTUAL_NODES)
+    }
+}
+
+/// 64-bit FNV-1a, finalized with the murmur3 mixer. Stable across processes and
+/// platforms, unlike `std::collections::hash_map::DefaultHasher`.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+impl HashRing {
+    pub fn new(virtual_nodes: usize) -> Self {
+        Self {
+            virtual_nodes: virtual_nodes.max(1),
+            ring: BTreeMap::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Place `node` on the ring. Adding an existing node is a no-op.
+    pub fn add(&mut self, node: impl ToString) {
+        let node = node.to_string();
+        if self.nodes.contains(&node) {
+            return;
+        }
+
+        for i in 0..self.virtual_nodes {
+            self.ring
+                .insert(hash(format!("{}#{}", node, i).as_bytes()), node.clone());
+        }
+        self.nodes.push(node);
+    }
+
+    /// Remove `node`, and all its virtual nodes, from the ring
+    pub fn remove(&mut self, node: &str) {
+        self.nodes.retain(|n| n != node);
+        self.ring.retain(|_, n| n != node);
+    }
+
+    /// Node owning `key`: the first virtual node clockwise from the key hash
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let point = hash(key.as_bytes());
+
+        self.ring
+            .range(point..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+#[test]
+fn test_ring_distribution() {
+    let mut ring = HashRing::default();
+    for node in ["a", "b", "c", "d"] {
+        ring.add(node);
+    }
+
+    let mut counts = std::collections::HashMap::new();
+    for i in 0..10_000 {
+        *counts
+            .entry(ring.get(&format!("key-{}", i)).unwrap())
+            .or_insert(0) += 1;
+    }
+
+    // every node owns a fair share: 2_500 keys, give or take
+    assert_eq!(counts.len(), 4);
+    assert!(counts.values().all(|count| (1_800..3_200).contains(count)));
+}
+
+#[test]
+fn test_ring_stable_under_membership_change() {
+    let mut ring = HashRing::default();
+    for node in ["a", "b", "c"] {
+        ring.add(node);
+    }
+    let keys: Vec<String> = (0..5_000).map(|i| format!("key-{}", i)).collect();
+    let before: Vec<String> = keys
+        .iter()
+        .map(|k| ring.get(k).unwrap().to_owned())
+        .collect();
+
+    // only keys moving onto the new node change owner
+    ring.add("d");
+    for (key, owner) in keys.iter().zip(before.iter()) {
+        let now = ring.get(key).unwrap();
+        assert!(now == owner || now == "d");
+    }
+
+    // removing it again restores the original placement
+    ring.remove("d");
+    for (key, owner) in keys.iter().zip(before.iter()) {
+        assert_eq!(ring.get(key).unwrap(), owner);
+    }
+    assert_eq!(ring.nodes(), &["a", "b", "c"]);
+}
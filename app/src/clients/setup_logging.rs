@@ -9,6 +9,8 @@ pub fn set_up_logging() -> crate::Result<()> {
         .with_file(false)
         .with_line_number(true)
         .with_thread_ids(false)
+        // stdout is kept for downloads
+        .with_writer(std::io::stderr)
         .finish();
 
     match tracing::subscriber::set_global_default(subscriber) {
@@ -31,7 +33,7 @@ pub fn set_up_logging() -> crate::Result<()> {
     match tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new("INFO"))
         .with(tracing_opentelemetry::layer().with_tracer(tracer))
-        .with(fmt::Layer::default())
+        .with(fmt::Layer::default().with_writer(std::io::stderr))
         .try_init()
     {
         Ok(_) => Ok(()),
@@ -0,0 +1,321 @@
+//!
+//! File transfers, with progress bars, verified by SHA-256 checksums
+//!
+
+use super::Client;
+use crate::{
+    models::{
+        blobs::{digest_file, hex},
+        sha256_hex,
+    },
+    protobuffer::gupload::{
+        chunk::Data, Chunk, ChunkPart, FileRequest, StatFileRequest, StatusCode, UploadFileInfo,
+        UploadStatus,
+    },
+    signing::UPLOAD_METHOD,
+    AppError,
+};
+use colored::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Request;
+use tracing::{info, instrument};
+
+/// Bytes per uploaded chunk
+pub const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+fn io_error(err: std::io::Error) -> AppError {
+    AppError::FileError(err.to_string())
+}
+
+/// Progress of a transfer of `len` bytes, or of unknown length, drawn on stderr
+pub fn progress_bar(len: Option<u64>) -> ProgressBar {
+    let (bar, template) = match len {
+        Some(len) => (
+            ProgressBar::new(len),
+            "[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta})",
+        ),
+        None => (
+            {
+                let spinner = ProgressBar::new_spinner();
+                spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+                spinner
+            },
+            "{spinner} [{elapsed_precise}] {bytes} ({bytes_per_sec})",
+        ),
+    };
+
+    if let Ok(style) = ProgressStyle::with_template(template) {
+        bar.set_style(style.progress_chars("=> "));
+    }
+    bar
+}
+
+/// Fill `buffer`, unless the end is reached first. Returns the bytes read.
+async fn read_full<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut [u8],
+) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+
+    Ok(filled)
+}
+
+impl Client {
+    /// Upload the file at `path` as `filename`. Its SHA-256 is computed first, so
+    /// the server refuses to commit content corrupted on the way.
+    #[instrument(skip(self, progress), name = "command_upload_file")]
+    pub async fn upload_file(
+        &mut self,
+        path: &Path,
+        filename: String,
+        file_type: String,
+        progress: ProgressBar,
+    ) -> crate::Result<UploadStatus> {
+        let (sha256, size) = digest_file(path).await?;
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|err| AppError::FileError(format!("{}: {}", path.display(), err)))?;
+        progress.set_length(size);
+
+        self.upload(
+            file,
+            filename,
+            file_type,
+            Some(size),
+            Some(sha256),
+            progress,
+        )
+        .await
+    }
+
+    /// Upload `reader`, of unknown length, as `filename`. It is first copied to a
+    /// temporary file, whose SHA-256 the signature of a signed upload covers.
+    #[instrument(skip(self, reader, progress), name = "command_upload_buffered")]
+    pub async fn upload_buffered<R>(
+        &mut self,
+        mut reader: R,
+        filename: String,
+        file_type: String,
+        progress: ProgressBar,
+    ) -> crate::Result<UploadStatus>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let path = std::env::temp_dir().join(format!("simply-upload-{}", uuid::Uuid::new_v4()));
+        let copied = async {
+            let mut file = tokio::fs::File::create(&path).await?;
+            tokio::io::copy(&mut reader, &mut file).await?;
+            file.flush().await
+        }
+        .await;
+
+        let status = match copied {
+            Ok(()) => self.upload_file(&path, filename, file_type, progress).await,
+            Err(err) => Err(io_error(err)),
+        };
+        let _ = tokio::fs::remove_file(&path).await;
+        status
+    }
+
+    /// Upload `reader` as `filename`, in chunks checksummed with SHA-256, then verify
+    /// the checksum of the stored file. With `size`, the server tells an incomplete
+    /// upload from a complete one. Without `sha256`, the checksum of the whole file
+    /// is sent once read. Signed uploads are signed over their file info, which binds
+    /// their content only with `sha256`: they require it.
+    #[instrument(skip(self, reader, progress), name = "command_upload")]
+    pub async fn upload<R>(
+        &mut self,
+        mut reader: R,
+        filename: String,
+        file_type: String,
+        size: Option<u64>,
+        sha256: Option<String>,
+        progress: ProgressBar,
+    ) -> crate::Result<UploadStatus>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        info!(
+            message = format!("{}", "Sending upload request".blue()),
+            filename,
+            size = ?size
+        );
+
+        if self.signer.is_some() && sha256.is_none() {
+            return Err(AppError::InvalidArgument {
+                field: "sha256".to_owned(),
+                reason: "required to sign an upload; see upload_buffered".to_owned(),
+            });
+        }
+
+        let info = Chunk {
+            data: Some(Data::Info(UploadFileInfo {
+                filename,
+                file_type,
+                size: size.unwrap_or_default(),
+                sha256: sha256.clone().unwrap_or_default(),
+                ..Default::default()
+            })),
+        };
+
+        let (tx, rx) = mpsc::channel(4);
+        let mut request = Request::new(ReceiverStream::new(rx));
+        if let Some(signer) = &self.signer {
+            signer.sign_payload(UPLOAD_METHOD, &info.encode_to_vec(), request.metadata_mut());
+        }
+        let bar = progress.clone();
+        let reading = tokio::spawn(async move {
+            let mut hasher = Sha256::new();
+            let mut buffer = vec![0; UPLOAD_CHUNK_SIZE];
+            let mut offset = 0;
+
+            if tx.send(info).await.is_err() {
+                return Ok(String::new());
+            }
+            loop {
+                let read = match read_full(&mut reader, &mut buffer).await {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(err) => {
+                        // a second file info makes the server reject the upload, instead
+                        // of committing it truncated
+                        let _ = tx
+                            .send(Chunk {
+                                data: Some(Data::Info(UploadFileInfo::default())),
+                            })
+                            .await;
+                        return Err(err);
+                    }
+                };
+
+                let content = buffer[..read].to_vec();
+                hasher.update(&content);
+                let part = ChunkPart {
+                    offset,
+                    sha256: sha256_hex(&content),
+                    content,
+                };
+                if tx
+                    .send(Chunk {
+                        data: Some(Data::Part(part)),
+                    })
+                    .await
+                    .is_err()
+                {
+                    // the server ended the upload
+                    break;
+                }
+                offset += read as u64;
+                bar.inc(read as u64);
+            }
+
+            let local = hex(&hasher.finalize());
+            if sha256.is_none() {
+                // the server commits a file of unknown size only once its checksum is known
+                let _ = tx
+                    .send(Chunk {
+                        data: Some(Data::Info(UploadFileInfo {
+                            sha256: local.clone(),
+                            ..Default::default()
+                        })),
+                    })
+                    .await;
+            }
+
+            Ok(local)
+        });
+
+        let status = self
+            .gupload_client
+            .upload(request)
+            .await
+            .map_err(AppError::RpcError)?
+            .into_inner();
+        let local = reading
+            .await
+            .map_err(|err| AppError::FileError(err.to_string()))?
+            .map_err(io_error)?;
+        progress.finish();
+
+        match StatusCode::from_i32(status.code) {
+            Some(StatusCode::Ok) if status.sha256 == local => Ok(status),
+            Some(StatusCode::Ok) => Err(AppError::ChecksumMismatch {
+                expected: local,
+                found: status.sha256,
+            }),
+            _ => Err(AppError::UploadFailed(status.message)),
+        }
+    }
+
+    /// Download `filename` into `writer`, then verify its SHA-256 against the stored
+    /// one. Returns the bytes written.
+    #[instrument(skip(self, writer, progress), name = "command_download")]
+    pub async fn download<W>(
+        &mut self,
+        filename: String,
+        mut writer: W,
+        progress: ProgressBar,
+    ) -> crate::Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        info!(
+            message = format!("{}", "Sending download request".blue()),
+            filename
+        );
+
+        let stat = self
+            .gupload_client
+            .stat_file(StatFileRequest {
+                filename: filename.clone(),
+            })
+            .await
+            .map_err(AppError::RpcError)?
+            .into_inner();
+        progress.set_length(stat.size);
+
+        let mut stream = self
+            .gupload_client
+            .download(FileRequest {
+                filename,
+                ..Default::default()
+            })
+            .await
+            .map_err(AppError::RpcError)?
+            .into_inner();
+
+        let mut hasher = Sha256::new();
+        let mut written = 0;
+        while let Some(response) = stream.message().await.map_err(AppError::RpcError)? {
+            writer.write_all(&response.shard).await.map_err(io_error)?;
+            hasher.update(&response.shard);
+            written += response.shard.len() as u64;
+            progress.inc(response.shard.len() as u64);
+        }
+        writer.flush().await.map_err(io_error)?;
+        progress.finish();
+
+        let found = hex(&hasher.finalize());
+        match found == stat.sha256 {
+            true => Ok(written),
+            false => Err(AppError::ChecksumMismatch {
+                expected: stat.sha256,
+                found,
+            }),
+        }
+    }
+}
@@ -1,7 +1,5 @@
-use crate::{
-    models::{KeyValueStore, PersonRepository},
-    Connection, InMemoryDatabase,
-};
+use crate::{models::PersonRepository, Connection, InMemoryDatabase};
+use std::time::Duration;
 use tracing::{error, instrument};
 
 /// Set `key` to hold the string `value`.
@@ -17,6 +15,9 @@ pub struct Set {
 
     /// the value to be stored
     value: String,
+
+    /// time to live of the key, if any
+    ttl: Option<Duration>,
 }
 
 impl Set {
@@ -25,9 +26,16 @@ impl Set {
         Set {
             key: key.to_string(),
             value: value.to_string(),
+            ttl: None,
         }
     }
 
+    /// Expire the key after `ttl`
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
     /// Parse a `Set` instance from a received frame.
     ///
     /// The `Parse` argument provides a cursor-like API to read fields from the
@@ -50,7 +58,8 @@ impl Set {
     where
         C: Connection<Output = InMemoryDatabase> + Send + Sync,
     {
-        match PersonRepository::set_value(repository, conn, self.key.as_str(), self.value.as_str())
+        match repository
+            .set_value_with_ttl(conn, self.key.as_str(), self.value.as_str(), self.ttl)
             .await
         {
             Ok(_) => Ok("Ok".to_owned()),
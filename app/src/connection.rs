@@ -1,11 +1,24 @@
-use crate::Settings;
+use crate::{AppError, Settings};
 use colored::Colorize;
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
 use surrealdb::{
-    engine::{local::Mem, remote::ws::Ws},
+    engine::{
+        local::Mem,
+        remote::ws::{Client, Ws},
+    },
     opt::auth::Root,
     Surreal,
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+const MIN_BACKOFF: Duration = Duration::from_millis(200);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Connection attempts before `RemoteDatabase::new` gives up
+const INITIAL_CONNECT_ATTEMPTS: u32 = 5;
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub struct DummyDatabase {}
@@ -18,16 +31,52 @@ pub struct InMemoryDatabase {
     pub database_name: String,
 }
 
-/// Remote connection via SurrealDb client
+/// Remote connection via SurrealDb client. Reconnects with exponential backoff
+/// when the connection drops; see `RemoteDatabase::db`.
 #[derive(Debug)]
 pub struct RemoteDatabase {
-    pub db: Surreal<surrealdb::engine::remote::ws::Client>,
     pub port: u32,
     pub host: String,
     pub namespace: String,
     pub database_name: String,
     pub username: String,
     pub password: String,
+    inner: Arc<RemoteState>,
+}
+
+/// Connection shared by clones of a `RemoteDatabase`
+#[derive(Debug)]
+struct RemoteState {
+    db: RwLock<Option<Surreal<Client>>>,
+    state: Mutex<ConnectionState>,
+    last_error: Mutex<Option<String>>,
+}
+
+/// Connection state of a `RemoteDatabase`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Disconnected => "disconnected",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Exponential backoff, with jitter
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
 }
 
 /// SurrealDb client connection
@@ -35,7 +84,10 @@ pub struct RemoteDatabase {
 pub trait Connection {
     type Output;
 
-    async fn new() -> Self;
+    /// connect, as configured in settings
+    async fn new() -> crate::Result<Self>
+    where
+        Self: Sized;
 
     /// get database connection
     fn get_db(&self) -> Self::Output;
@@ -53,7 +105,7 @@ impl Connection for InMemoryDatabase {
         }
     }
 
-    async fn new() -> Self {
+    async fn new() -> crate::Result<Self> {
         let namespace = Settings::get_config_item("SURREALDB_NS")
             .await
             .unwrap_or("test".to_owned());
@@ -65,36 +117,230 @@ impl Connection for InMemoryDatabase {
         // NOTE: if () is change to "Strict" server mode, will throw an NSNotFound exception
         // when submittting query.
         // see https://github.com/surrealdb/surrealdb/issues/13
-        let db: surrealdb::Result<Surreal<surrealdb::engine::local::Db>> =
-            Surreal::new::<Mem>(()).await;
+        let db: Surreal<surrealdb::engine::local::Db> = Surreal::new::<Mem>(())
+            .await
+            .map_err(|err| AppError::DbConnectError(err.to_string()))?;
 
-        if let Ok(db) = db {
-            match db
-                .use_ns(namespace.clone())
-                .use_db(database_name.clone())
+        match db
+            .use_ns(namespace.clone())
+            .use_db(database_name.clone())
+            .await
+        {
+            Ok(_) => {
+                info!(
+                    message = "Use namespace / database".blue().to_string(),
+                    namespace, database_name
+                );
+            }
+            Err(err) => {
+                let err_info = format!("{:?}", err);
+                error!(error = %err_info);
+                return Err(AppError::DbConnectError(err.to_string()));
+            }
+        };
+
+        Ok(Self {
+            db,
+            namespace,
+            database_name,
+        })
+    }
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    /// Delay before the next attempt: doubles on every attempt, up to `max`, with
+    /// its upper half randomized so that clients do not retry in lockstep
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self.current;
+        self.current = (self.current * 2).min(self.max);
+
+        let half = base / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+
+    /// Start over from `min`, after a success
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+impl RemoteDatabase {
+    /// Database client, or `AppError::DbConnectError` while disconnected
+    pub fn db(&self) -> crate::Result<Surreal<Client>> {
+        let db = self
+            .inner
+            .db
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+
+        db.ok_or_else(|| {
+            let last_error = self
+                .inner
+                .last_error
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone();
+            AppError::DbConnectError(format!(
+                "{} at {}:{}, {}",
+                self.state(),
+                self.host,
+                self.port,
+                last_error.unwrap_or_default()
+            ))
+        })
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self
+            .inner
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        let previous = std::mem::replace(
+            &mut *self
+                .inner
+                .state
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            state,
+        );
+
+        if previous != state {
+            let message = format!("SurrealDb {} -> {}", previous, state);
+            match state {
+                ConnectionState::Connected => info!(message = message.green().to_string()),
+                ConnectionState::Connecting => info!(message = message.blue().to_string()),
+                ConnectionState::Disconnected => warn!(message = message.yellow().to_string()),
+            }
+        }
+    }
+
+    fn set_db(&self, db: Option<Surreal<Client>>) {
+        *self
+            .inner
+            .db
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = db;
+    }
+
+    /// Connect, sign in, and select the namespace / database
+    async fn connect_once(&self) -> crate::Result<Surreal<Client>> {
+        let address = format!("{}:{}", self.host, self.port);
+        let db =
+            Surreal::new::<Ws>(address.as_str())
                 .await
-            {
-                Ok(_) => {
+                .map_err(|err| AppError::DbUnreachable {
+                    address: address.clone(),
+                    reason: err.to_string(),
+                })?;
+
+        db.signin(Root {
+            username: &self.username,
+            password: &self.password,
+        })
+        .await
+        .map_err(|err| AppError::DbAuthError {
+            username: self.username.clone(),
+            reason: err.to_string(),
+        })?;
+
+        db.use_ns(self.namespace.clone())
+            .use_db(self.database_name.clone())
+            .await
+            .map_err(|err| AppError::DbConnectError(err.to_string()))?;
+
+        Ok(db)
+    }
+
+    /// Connect with exponential backoff. With `max_attempts`, gives up after as many
+    /// attempts, or on the first rejected sign-in.
+    async fn connect(&self, max_attempts: Option<u32>) -> crate::Result<()> {
+        let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            self.set_state(ConnectionState::Connecting);
+
+            match self.connect_once().await {
+                Ok(db) => {
+                    self.set_db(Some(db));
+                    self.set_state(ConnectionState::Connected);
                     info!(
                         message = "Use namespace / database".blue().to_string(),
-                        namespace, database_name
+                        namespace = self.namespace,
+                        database_name = self.database_name
                     );
+                    return Ok(());
                 }
                 Err(err) => {
-                    let err_info = format!("{:?}", err);
-                    error!(error = %err_info);
-                    panic!("{}", "failed to connect surrealdb".red());
+                    error!(error = format!("{:?}", err), attempt);
+                    *self
+                        .inner
+                        .last_error
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(err.to_string());
+                    self.set_state(ConnectionState::Disconnected);
+
+                    if let Some(max_attempts) = max_attempts {
+                        if attempt >= max_attempts || matches!(err, AppError::DbAuthError { .. }) {
+                            return Err(err);
+                        }
+                    }
                 }
-            };
+            }
 
-            return Self {
-                db,
-                namespace,
-                database_name,
+            let delay = backoff.next_delay();
+            info!(
+                message = "retrying SurrealDb connection".blue().to_string(),
+                attempt,
+                delay_millis = delay.as_millis() as u64
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Check the connection health periodically, and reconnect after a drop
+    async fn supervise(self) {
+        loop {
+            tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+
+            let healthy = match self.db() {
+                Ok(db) => match db.health().await {
+                    Ok(_) => true,
+                    Err(err) => {
+                        error!(error = format!("{:?}", err));
+                        *self
+                            .inner
+                            .last_error
+                            .lock()
+                            .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                            Some(err.to_string());
+                        false
+                    }
+                },
+                Err(_) => false,
             };
-        };
 
-        panic!("{}", "failed to connect surrealdb".red());
+            if !healthy {
+                self.set_db(None);
+                self.set_state(ConnectionState::Disconnected);
+                // retries until connected
+                let _ = self.connect(None).await;
+            }
+        }
     }
 }
 
@@ -104,27 +350,38 @@ impl Connection for RemoteDatabase {
 
     fn get_db(&self) -> Self::Output {
         RemoteDatabase {
-            db: self.db.to_owned(),
             namespace: self.namespace.to_owned(),
             database_name: self.database_name.to_owned(),
             port: self.port.to_owned(),
             host: self.host.to_owned(),
             username: self.username.to_owned(),
             password: self.password.to_owned(),
+            inner: self.inner.clone(),
         }
     }
 
-    async fn new() -> Self {
+    /// Fails if SurrealDb is unreachable after a few attempts, or rejects the sign-in.
+    /// Once connected, reconnects in the background whenever the connection drops;
+    /// `db()` returns `AppError::DbConnectError` meanwhile.
+    async fn new() -> crate::Result<Self> {
         // load from global setting, verify as u32. If NONE, use default 8000
-        let port = Settings::get_config_item("SURREALDB_PORT")
-            .await
-            .unwrap_or("8000".to_owned())
-            .parse::<u32>()
-            .unwrap_or(8000);
+        let port = match Settings::get_config_item("SURREALDB_PORT").await {
+            Some(port) => port.parse::<u32>().map_err(|_| AppError::InvalidSetting {
+                key: "SURREALDB_PORT".to_owned(),
+                value: port,
+            })?,
+            None => 8000,
+        };
 
         let host = Settings::get_config_item("SURREALDB_HOST")
             .await
             .unwrap_or("127.0.0.1".to_owned());
+        if host.trim().is_empty() {
+            return Err(AppError::InvalidSetting {
+                key: "SURREALDB_HOST".to_owned(),
+                value: host,
+            });
+        }
 
         let namespace = Settings::get_config_item("SURREALDB_NS")
             .await
@@ -142,56 +399,48 @@ impl Connection for RemoteDatabase {
             .await
             .unwrap_or("root".to_owned());
 
-        if let Ok(db) = Surreal::new::<Ws>(format!("{}:{}", host, port)).await {
-            info!(
-                message = format!("{}", "Connecting SurrealDb".blue()),
-                host, port
-            );
+        let database = Self {
+            port,
+            host,
+            username,
+            password,
+            namespace,
+            database_name,
+            inner: Arc::new(RemoteState {
+                db: RwLock::new(None),
+                state: Mutex::new(ConnectionState::Disconnected),
+                last_error: Mutex::new(None),
+            }),
+        };
 
-            // Signin as a namespace, database, or root user
-            match db
-                .signin(Root {
-                    username: &username,
-                    password: &password,
-                })
-                .await
-            {
-                Ok(_) => info!(message = "SurrealDb sign-in".blue().to_string(), username),
-                Err(err) => {
-                    let err_info = format!("{:?}", err);
-                    error!(error = %err_info);
-                    panic!("{}", "failed to signin".red())
-                }
-            }
+        info!(
+            message = format!("{}", "Connecting SurrealDb".blue()),
+            host = database.host,
+            port = database.port
+        );
 
-            match db
-                .use_ns(namespace.clone())
-                .use_db(database_name.clone())
-                .await
-            {
-                Ok(_) => {
-                    info!(
-                        message = "Use namespace / database".blue().to_string(),
-                        namespace, database_name
-                    );
-                }
-                Err(err) => {
-                    let err_info = format!("{:?}", err);
-                    error!(error = %err_info);
-                    panic!("{}", "failed to connect surrealdb".red());
-                }
-            };
+        database.connect(Some(INITIAL_CONNECT_ATTEMPTS)).await?;
+        tokio::spawn(database.get_db().supervise());
 
-            return Self {
-                db,
-                port,
-                host,
-                username,
-                password,
-                namespace,
-                database_name,
-            };
-        }
-        panic!("{}", "failed to connect surrealdb".red());
+        Ok(database)
     }
 }
+
+#[test]
+fn test_backoff() {
+    let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+
+    let delays: Vec<Duration> = (0..6).map(|_| backoff.next_delay()).collect();
+    for (delay, base) in delays.iter().zip([100, 200, 400, 800, 1000, 1000]) {
+        let base = Duration::from_millis(base);
+        assert!(
+            *delay >= base / 2 && *delay <= base,
+            "{:?} for {:?}",
+            delay,
+            base
+        );
+    }
+
+    backoff.reset();
+    assert!(backoff.next_delay() <= Duration::from_millis(100));
+}
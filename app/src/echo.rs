@@ -22,6 +22,9 @@ pub struct KeyValueRequest {
     pub key: ::prost::alloc::string::String,
     #[prost(string, optional, tag = "2")]
     pub value: ::core::option::Option<::prost::alloc::string::String>,
+    /// time to live, in seconds
+    #[prost(uint64, optional, tag = "3")]
+    pub ttl: ::core::option::Option<u64>,
 }
 /// KeyValueResponse
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -32,6 +35,39 @@ pub struct KeyValueResponse {
     #[prost(string, optional, tag = "2")]
     pub error: ::core::option::Option<::prost::alloc::string::String>,
 }
+/// StatsRequest asks for the statistics of the key-value store.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StatsRequest {}
+/// StatsResponse reports the memory usage, evictions and compression of the
+/// key-value store.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StatsResponse {
+    /// approximate bytes of the stored records
+    #[prost(uint64, tag = "1")]
+    pub used_memory: u64,
+    /// memory limit, 0 if unlimited
+    #[prost(uint64, tag = "2")]
+    pub max_memory: u64,
+    #[prost(uint64, tag = "3")]
+    pub keys: u64,
+    #[prost(uint64, tag = "4")]
+    pub evicted_keys: u64,
+    /// values stored compressed, and stored raw as compression did not pay off
+    #[prost(uint64, tag = "5")]
+    pub compressed_values: u64,
+    #[prost(uint64, tag = "6")]
+    pub incompressible_values: u64,
+    /// size of values above COMPRESSION_THRESHOLD, before compression and as stored
+    #[prost(uint64, tag = "7")]
+    pub raw_bytes: u64,
+    #[prost(uint64, tag = "8")]
+    pub stored_bytes: u64,
+    /// raw_bytes over stored_bytes
+    #[prost(double, tag = "9")]
+    pub compression_ratio: f64,
+}
 /// Generated client implementations.
 pub mod echo_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -259,6 +295,26 @@ pub mod echo_client {
             req.extensions_mut().insert(GrpcMethod::new("echo.Echo", "GetValue"));
             self.inner.unary(req, path, codec).await
         }
+        /// KeyValue store - memory usage, evictions and compression
+        pub async fn get_stats(
+            &mut self,
+            request: impl tonic::IntoRequest<super::StatsRequest>,
+        ) -> std::result::Result<tonic::Response<super::StatsResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/echo.Echo/GetStats");
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new("echo.Echo", "GetStats"));
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -322,6 +378,11 @@ pub mod echo_server {
             tonic::Response<super::KeyValueResponse>,
             tonic::Status,
         >;
+        /// KeyValue store - memory usage, evictions and compression
+        async fn get_stats(
+            &self,
+            request: tonic::Request<super::StatsRequest>,
+        ) -> std::result::Result<tonic::Response<super::StatsResponse>, tonic::Status>;
     }
     /// Echo is the echo service.
     #[derive(Debug)]
@@ -667,6 +728,48 @@ pub mod echo_server {
                     };
                     Box::pin(fut)
                 }
+                "/echo.Echo/GetStats" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetStatsSvc<T: Echo>(pub Arc<T>);
+                    impl<T: Echo> tonic::server::UnaryService<super::StatsRequest>
+                    for GetStatsSvc<T> {
+                        type Response = super::StatsResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::StatsRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { (*inner).get_stats(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetStatsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         Ok(
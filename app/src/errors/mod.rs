@@ -5,9 +5,17 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum AppError {
-    /// Surrealdb: Fail to connect db
-    #[error("database is unhealthy")]
-    DbConnectError(surrealdb::Error),
+    /// Surrealdb: Fail to connect db, or connection lost
+    #[error("database is unavailable: {0}")]
+    DbConnectError(String),
+
+    /// Surrealdb: sign-in rejected
+    #[error("database sign-in failed for user `{username}`: {reason}")]
+    DbAuthError { username: String, reason: String },
+
+    /// Surrealdb: host cannot be reached
+    #[error("database at {address} is unreachable: {reason}")]
+    DbUnreachable { address: String, reason: String },
 
     /// Surrealdb: Unhealthy
     #[error("database is unhealthy")]
@@ -21,10 +29,122 @@ pub enum AppError {
     #[error("get_value error")]
     SurrealdbGetError(surrealdb::Error),
 
+    /// Surrealdb: key does not exist, or has expired
+    #[error("key `{0}` not found")]
+    KeyNotFound(String),
+
+    /// Person: no person with this id
+    #[error("person `{0}` not found")]
+    PersonNotFound(String),
+
+    /// Files: no uploaded file with this name
+    #[error("file `{0}` not found")]
+    FileNotFound(String),
+
+    /// Files: no stored content with this hash
+    #[error("blob `{0}` not found")]
+    BlobNotFound(String),
+
+    /// Files: file cannot be read, or written
+    #[error("file error: {0}")]
+    FileError(String),
+
+    /// Files: no upload session with this id, or it expired
+    #[error("upload session `{0}` not found")]
+    UploadSessionNotFound(String),
+
+    /// Files: chunk does not start at the uploaded offset
+    #[error("chunk out of order: expected offset {expected}, got {found}")]
+    UploadOutOfOrder { expected: u64, found: u64 },
+
+    /// Files: content does not match its checksum
+    #[error("checksum mismatch: expected {expected}, got {found}")]
+    ChecksumMismatch { expected: String, found: String },
+
+    /// Payments: no payment with this txid
+    #[error("payment `{0}` not found")]
+    PaymentNotFound(String),
+
+    /// Payments: confirmed outputs of the payer are below the amount, plus the fee
+    #[error("insufficient funds: {address} has {balance} confirmed satoshis, {amount} needed with the fee")]
+    InsufficientFunds {
+        address: String,
+        balance: i64,
+        amount: i64,
+    },
+
+    /// Storage: write exceeds the memory limit, and no key can be evicted
+    #[error("out of memory: {requested} bytes requested, {used} of {max} bytes used")]
+    OutOfMemory {
+        requested: usize,
+        used: usize,
+        max: usize,
+    },
+
+    /// Storage: stored value cannot be compressed, or decompressed
+    #[error("compression error: {0}")]
+    CompressionError(String),
+
+    /// Storage: stored value cannot be encrypted, or decrypted
+    #[error("encryption error: {0}")]
+    EncryptionError(String),
+
+    /// Validation: invalid request argument
+    #[error("invalid {field}: {reason}")]
+    InvalidArgument { field: String, reason: String },
+
+    /// Migrations: an applied migration script has changed since
+    #[error("migration {version} drifted: applied with checksum {expected}, now {found}")]
+    MigrationDrift {
+        version: u32,
+        expected: String,
+        found: String,
+    },
+
+    /// Migrations: migration cannot be applied
+    #[error("{0}")]
+    MigrationError(String),
+
+    /// Settings: invalid configuration item
+    #[error("invalid setting {key} = `{value}`")]
+    InvalidSetting { key: String, value: String },
+
     /// grpc: Fail to connect server
     #[error("tonic error")]
     TonicError(tonic::transport::Error),
 
+    /// grpc: No server address to connect to
+    #[error("no server address given")]
+    NoServerAddress,
+
+    /// grpc: server is not in the ring of the client
+    #[error("server `{0}` is not connected")]
+    UnknownServer(String),
+
+    /// grpc: the only server of the client cannot be removed
+    #[error("server `{0}` is the last one connected")]
+    LastServer(String),
+
+    /// grpc: TLS certificates or keys cannot be loaded
+    #[error("TLS error: {0}")]
+    TlsError(String),
+
+    /// grpc: reflection service cannot be built from the file descriptor sets
+    #[error("reflection error: {0}")]
+    ReflectionError(String),
+
+    /// grpc: request rejected by the server
+    #[error("request failed: {}", .0.message())]
+    RpcError(tonic::Status),
+
+    /// Files: upload rejected by the server
+    #[error("upload failed: {0}")]
+    UploadFailed(String),
+
+    /// Replication: stream from leader failed
+    #[error("replication error")]
+    ReplicationError(tonic::Status),
+
     /// Tracing
     #[error("tracing-subscriber init error")]
     TracingSubscriberInitError(tracing_subscriber::util::TryInitError),
@@ -46,6 +166,25 @@ pub enum AppError {
     Unknown,
 }
 
+impl AppError {
+    /// Process exit code, following sysexits.h
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // EX_CONFIG
+            AppError::InvalidSetting { .. } | AppError::TlsError(_) => 78,
+            // EX_NOPERM
+            AppError::DbAuthError { .. } => 77,
+            // EX_UNAVAILABLE
+            AppError::DbUnreachable { .. } | AppError::DbConnectError(_) => 69,
+            // EX_DATAERR
+            AppError::MigrationDrift { .. } | AppError::MigrationError(_) => 65,
+            // EX_IOERR
+            AppError::TonicError(_) => 74,
+            _ => 1,
+        }
+    }
+}
+
 /// A specialized `Result` type for general operations.
 ///
 /// This is defined as a convenience.
@@ -6,9 +6,14 @@
 pub mod clients;
 mod cmd;
 pub mod errors;
+pub mod migrations;
 pub mod models;
+pub mod replicas;
 pub mod server;
 mod settings;
+pub mod signing;
+pub mod tls;
+pub mod watch;
 
 mod connection;
 pub use connection::*;
@@ -27,4 +32,27 @@ pub const DEFAULT_PORT: u16 = 50051;
 #[allow(clippy::derive_partial_eq_without_eq)]
 pub mod protobuffer {
     include!("./echo.rs");
+
+    /// Encoded file descriptor set of every proto, served by grpc reflection
+    pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!("./simply_descriptor.bin");
+
+    /// Leader / follower replication
+    pub mod replication {
+        include!("./replication.rs");
+    }
+
+    /// Person records
+    pub mod person {
+        include!("./person.rs");
+    }
+
+    /// File upload / download
+    pub mod gupload {
+        include!("./gupload.rs");
+    }
+
+    /// Bitcoin payments, against a simulated ledger
+    pub mod payments {
+        include!("./payments.rs");
+    }
 }
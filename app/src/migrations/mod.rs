@@ -0,0 +1,297 @@
+//!
+//! Schema migrations
+//!
+//! Ordered, versioned SurrealQL scripts, applied once each. Applied versions are
+//! recorded in the `migration` table, with the checksum of their script.
+//!
+//! Migrations run against any SurrealDb engine. The server serves from an embedded
+//! database that starts empty, so startup only creates its schema. Drift, an applied
+//! script edited since, is detected on the persistent `RemoteDatabase`, which keeps
+//! its applied versions across restarts, by `simply-server migrate status|up`.
+//!
+
+use crate::{replicas::now_millis, AppError};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use surrealdb::{Connection as Engine, Surreal};
+use tracing::info;
+
+/// Table of applied migrations
+pub const MIGRATION_TABLE: &str = "migration";
+
+/// A versioned SurrealQL script
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub script: &'static str,
+}
+
+/// Every migration, by increasing version. Append only: never edit nor remove an
+/// applied migration, add a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "kv",
+        script: include_str!("0001_kv.surql"),
+    },
+    Migration {
+        version: 2,
+        name: "person",
+        script: include_str!("0002_person.surql"),
+    },
+    Migration {
+        version: 3,
+        name: "ledger",
+        script: include_str!("0003_ledger.surql"),
+    },
+    Migration {
+        version: 4,
+        name: "payment_outcome",
+        script: include_str!("0004_payment_outcome.surql"),
+    },
+    Migration {
+        version: 5,
+        name: "utxo",
+        script: include_str!("0005_utxo.surql"),
+    },
+];
+
+/// Record of an applied migration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub name: String,
+    pub checksum: String,
+    pub applied_at_ms: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationState {
+    Applied {
+        applied_at_ms: i64,
+    },
+    Pending,
+    /// the script changed since it was applied
+    Drifted {
+        expected: String,
+        found: String,
+    },
+    /// applied by a newer server, unknown to this one
+    Unknown,
+}
+
+impl std::fmt::Display for MigrationState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationState::Applied { applied_at_ms } => {
+                write!(f, "applied at {} ms", applied_at_ms)
+            }
+            MigrationState::Pending => write!(f, "pending"),
+            MigrationState::Drifted { expected, found } => {
+                write!(f, "drifted: applied {}, now {}", expected, found)
+            }
+            MigrationState::Unknown => write!(f, "unknown to this server"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    pub version: u32,
+    pub name: String,
+    pub state: MigrationState,
+}
+
+impl Migration {
+    /// SHA-256 of the script, hex encoded
+    pub fn checksum(&self) -> String {
+        Sha256::digest(self.script.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Run the script, and record it as applied, in a single transaction
+    async fn apply<E: Engine>(&self, db: &Surreal<E>) -> crate::Result<()> {
+        let sql = format!(
+            "BEGIN TRANSACTION;\n{}\nCREATE type::thing($table, $version) CONTENT $record;\n\
+             COMMIT TRANSACTION;",
+            self.script
+        );
+        let record = AppliedMigration {
+            version: self.version,
+            name: self.name.to_owned(),
+            checksum: self.checksum(),
+            applied_at_ms: now_millis(),
+        };
+
+        db.query(sql)
+            .bind(("table", MIGRATION_TABLE))
+            .bind(("version", self.version))
+            .bind(("record", record))
+            .await
+            .and_then(|response| response.check())
+            .map_err(|err| {
+                AppError::MigrationError(format!(
+                    "migration {} ({}) failed, {}",
+                    self.version, self.name, err
+                ))
+            })?;
+
+        Ok(())
+    }
+}
+
+async fn applied<E: Engine>(db: &Surreal<E>) -> crate::Result<Vec<AppliedMigration>> {
+    let applied: surrealdb::Result<Vec<AppliedMigration>> = db.select(MIGRATION_TABLE).await;
+
+    applied.map_err(AppError::SurrealdbGetError)
+}
+
+/// State of `migrations`, given the `applied` ones
+fn states(migrations: &[Migration], applied: &[AppliedMigration]) -> Vec<MigrationStatus> {
+    let mut statuses: Vec<MigrationStatus> = migrations
+        .iter()
+        .map(|migration| {
+            let state = match applied.iter().find(|a| a.version == migration.version) {
+                None => MigrationState::Pending,
+                Some(a) if a.checksum != migration.checksum() => MigrationState::Drifted {
+                    expected: a.checksum.clone(),
+                    found: migration.checksum(),
+                },
+                Some(a) => MigrationState::Applied {
+                    applied_at_ms: a.applied_at_ms,
+                },
+            };
+
+            MigrationStatus {
+                version: migration.version,
+                name: migration.name.to_owned(),
+                state,
+            }
+        })
+        .collect();
+
+    statuses.extend(
+        applied
+            .iter()
+            .filter(|a| !migrations.iter().any(|m| m.version == a.version))
+            .map(|a| MigrationStatus {
+                version: a.version,
+                name: a.name.clone(),
+                state: MigrationState::Unknown,
+            }),
+    );
+    statuses.sort_by_key(|status| status.version);
+
+    statuses
+}
+
+/// State of every migration, known or applied
+pub async fn status<E: Engine>(db: &Surreal<E>) -> crate::Result<Vec<MigrationStatus>> {
+    Ok(states(MIGRATIONS, &applied(db).await?))
+}
+
+/// Apply pending migrations, in order. Fails without applying anything if an
+/// applied migration drifted, or is unknown. Returns the versions applied.
+pub async fn up<E: Engine>(db: &Surreal<E>) -> crate::Result<Vec<u32>> {
+    let statuses = status(db).await?;
+
+    for status in statuses.iter() {
+        match &status.state {
+            MigrationState::Drifted { expected, found } => {
+                return Err(AppError::MigrationDrift {
+                    version: status.version,
+                    expected: expected.to_owned(),
+                    found: found.to_owned(),
+                })
+            }
+            MigrationState::Unknown => {
+                return Err(AppError::MigrationError(format!(
+                    "migration {} ({}) is applied, but unknown to this server",
+                    status.version, status.name
+                )))
+            }
+            _ => {}
+        }
+    }
+
+    let mut versions = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|migration| {
+        statuses
+            .iter()
+            .any(|s| s.version == migration.version && s.state == MigrationState::Pending)
+    }) {
+        migration.apply(db).await?;
+        info!(
+            message = "Applied migration".blue().to_string(),
+            version = migration.version,
+            name = migration.name
+        );
+        versions.push(migration.version);
+    }
+
+    Ok(versions)
+}
+
+#[test]
+fn test_migrations_are_ordered() {
+    assert!(MIGRATIONS
+        .windows(2)
+        .all(|pair| pair[0].version < pair[1].version));
+    assert_eq!(MIGRATIONS[0].checksum().len(), 64);
+}
+
+#[test]
+fn test_migration_states() {
+    let applied = |migration: &Migration, checksum: String| AppliedMigration {
+        version: migration.version,
+        name: migration.name.to_owned(),
+        checksum,
+        applied_at_ms: 1,
+    };
+    let unknown = Migration {
+        version: 99,
+        name: "future",
+        script: "",
+    };
+
+    let statuses = states(
+        MIGRATIONS,
+        &[
+            applied(&MIGRATIONS[0], "edited".to_owned()),
+            applied(&unknown, unknown.checksum()),
+        ],
+    );
+
+    assert!(matches!(statuses[0].state, MigrationState::Drifted { .. }));
+    assert_eq!(statuses[1].state, MigrationState::Pending);
+    assert_eq!(statuses.last().unwrap().state, MigrationState::Unknown);
+
+    let statuses = states(
+        MIGRATIONS,
+        &[applied(&MIGRATIONS[0], MIGRATIONS[0].checksum())],
+    );
+    assert_eq!(
+        statuses[0].state,
+        MigrationState::Applied { applied_at_ms: 1 }
+    );
+}
+
+#[tokio::test]
+async fn test_up_applies_pending_migrations_once() {
+    use crate::{Connection, InMemoryDatabase};
+
+    let database = <InMemoryDatabase as Connection>::new().await.unwrap();
+
+    let versions = up(&database.db).await.unwrap();
+    assert_eq!(versions.len(), MIGRATIONS.len());
+    assert!(up(&database.db).await.unwrap().is_empty());
+    assert!(status(&database.db)
+        .await
+        .unwrap()
+        .iter()
+        .all(|status| matches!(status.state, MigrationState::Applied { .. })));
+}
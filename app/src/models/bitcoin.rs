@@ -0,0 +1,311 @@
+//!
+//! Bitcoin address validation: Base58Check (P2PKH, P2SH) and Bech32 / Bech32m
+//! (SegWit, Taproot), for the network selected by "BITCOIN_NETWORK"
+//!
+
+use crate::{AppError, Settings};
+use bech32::{FromBase32, Variant};
+use std::str::FromStr;
+
+/// Longest bech32 string, per BIP 173
+const MAX_ADDRESS_LENGTH: usize = 90;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    /// the ledger is simulated, so regtest unless configured otherwise
+    #[default]
+    Regtest,
+}
+
+impl Network {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    /// Human-readable part of segwit addresses
+    fn bech32_hrp(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "bc",
+            Network::Testnet => "tb",
+            Network::Regtest => "bcrt",
+        }
+    }
+
+    /// Version bytes of P2PKH and P2SH addresses; testnet and regtest share them
+    fn base58_versions(&self) -> (u8, u8) {
+        match self {
+            Network::Mainnet => (0x00, 0x05),
+            Network::Testnet | Network::Regtest => (0x6f, 0xc4),
+        }
+    }
+
+    /// Network of "BITCOIN_NETWORK": mainnet, testnet or regtest
+    pub async fn from_settings() -> crate::Result<Self> {
+        match Settings::get_config_item("BITCOIN_NETWORK").await {
+            Some(network) => network.parse().map_err(|_| AppError::InvalidSetting {
+                key: "BITCOIN_NETWORK".to_owned(),
+                value: network,
+            }),
+            None => Ok(Network::default()),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(network: &str) -> Result<Self, Self::Err> {
+        match network.trim().to_lowercase().as_str() {
+            "mainnet" | "bitcoin" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network `{}`", network)),
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressKind {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    /// segwit version without a standard script yet
+    Witness {
+        version: u8,
+    },
+}
+
+impl AddressKind {
+    /// Whether addresses of this kind are bech32 encoded
+    pub fn is_segwit(&self) -> bool {
+        !matches!(self, AddressKind::P2pkh | AddressKind::P2sh)
+    }
+}
+
+/// Kind of `address`, if valid on `network`; else why it is not
+pub fn parse_address(address: &str, network: Network) -> Result<AddressKind, String> {
+    if address.is_empty() {
+        return Err("empty address".to_owned());
+    }
+    if address.len() > MAX_ADDRESS_LENGTH {
+        return Err(format!(
+            "address is {} characters, at most {} expected",
+            address.len(),
+            MAX_ADDRESS_LENGTH
+        ));
+    }
+
+    let lowercase = address.to_lowercase();
+    let is_segwit = [Network::Mainnet, Network::Testnet, Network::Regtest]
+        .iter()
+        .any(|network| lowercase.starts_with(&format!("{}1", network.bech32_hrp())));
+
+    match is_segwit {
+        true => parse_segwit(address, network),
+        false => parse_base58(address, network),
+    }
+}
+
+fn parse_base58(address: &str, network: Network) -> Result<AddressKind, String> {
+    let payload = bs58::decode(address)
+        .with_check(None)
+        .into_vec()
+        .map_err(|err| format!("invalid base58check encoding: {}", err))?;
+
+    // version byte, then a 20-byte hash
+    if payload.len() != 21 {
+        return Err(format!(
+            "base58check payload is {} bytes, 21 expected",
+            payload.len()
+        ));
+    }
+
+    let version = payload[0];
+    let found = match version {
+        0x00 => (Network::Mainnet, AddressKind::P2pkh),
+        0x05 => (Network::Mainnet, AddressKind::P2sh),
+        0x6f => (Network::Testnet, AddressKind::P2pkh),
+        0xc4 => (Network::Testnet, AddressKind::P2sh),
+        _ => {
+            return Err(format!(
+                "unknown base58check version byte 0x{:02x}",
+                version
+            ))
+        }
+    };
+
+    let (p2pkh, p2sh) = network.base58_versions();
+    match version == p2pkh || version == p2sh {
+        true => Ok(found.1),
+        false => Err(mismatch(found.1, found.0, network)),
+    }
+}
+
+fn parse_segwit(address: &str, network: Network) -> Result<AddressKind, String> {
+    let (hrp, data, variant) =
+        bech32::decode(address).map_err(|err| format!("invalid bech32 encoding: {}", err))?;
+
+    let version = data
+        .first()
+        .map(|version| version.to_u8())
+        .ok_or_else(|| "missing witness version".to_owned())?;
+    if version > 16 {
+        return Err(format!("invalid witness version {}", version));
+    }
+    let program = Vec::<u8>::from_base32(&data[1..])
+        .map_err(|err| format!("invalid witness program: {}", err))?;
+
+    if !(2..=40).contains(&program.len()) {
+        return Err(format!(
+            "witness program is {} bytes, 2 to 40 expected",
+            program.len()
+        ));
+    }
+    let kind = match (version, program.len()) {
+        (0, 20) => AddressKind::P2wpkh,
+        (0, 32) => AddressKind::P2wsh,
+        (0, len) => {
+            return Err(format!(
+                "version 0 witness program is {} bytes, 20 or 32 expected",
+                len
+            ))
+        }
+        (1, 32) => AddressKind::P2tr,
+        (version, _) => AddressKind::Witness { version },
+    };
+    // BIP 350: bech32 for version 0, bech32m from version 1 on
+    match (version, variant) {
+        (0, Variant::Bech32m) => {
+            return Err("version 0 witness address must use bech32, not bech32m".to_owned())
+        }
+        (version, Variant::Bech32) if version > 0 => {
+            return Err(format!(
+                "version {} witness address must use bech32m, not bech32",
+                version
+            ))
+        }
+        _ => {}
+    }
+
+    let found = match hrp.as_str() {
+        "bc" => Network::Mainnet,
+        "tb" => Network::Testnet,
+        _ => Network::Regtest,
+    };
+    match found == network {
+        true => Ok(kind),
+        false => Err(mismatch(kind, found, network)),
+    }
+}
+
+fn mismatch(kind: AddressKind, found: Network, network: Network) -> String {
+    // base58 addresses of testnet are regtest addresses too
+    let found = match (found, network) {
+        (Network::Testnet, Network::Mainnet)
+            if matches!(kind, AddressKind::P2pkh | AddressKind::P2sh) =>
+        {
+            "testnet or regtest"
+        }
+        (found, _) => found.as_str(),
+    };
+    format!(
+        "{:?} address is for {}, but the server is on {}",
+        kind, found, network
+    )
+}
+
+#[test]
+fn test_parse_address() {
+    let valid = [
+        (
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH",
+            Network::Mainnet,
+            AddressKind::P2pkh,
+        ),
+        (
+            "3CNHUhP3uyB9EUtRLsmvFUmvGdjGdkTxJw",
+            Network::Mainnet,
+            AddressKind::P2sh,
+        ),
+        (
+            "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r",
+            Network::Testnet,
+            AddressKind::P2pkh,
+        ),
+        (
+            "2N3vVYSK5XRgVSGWy21PnsRmBUywSQNdCsf",
+            Network::Regtest,
+            AddressKind::P2sh,
+        ),
+        (
+            "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4",
+            Network::Mainnet,
+            AddressKind::P2wpkh,
+        ),
+        (
+            "tb1q0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqlfz5su",
+            Network::Testnet,
+            AddressKind::P2wsh,
+        ),
+        (
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+            Network::Mainnet,
+            AddressKind::P2tr,
+        ),
+        (
+            "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+            Network::Regtest,
+            AddressKind::P2wpkh,
+        ),
+    ];
+    for (address, network, kind) in valid {
+        assert_eq!(parse_address(address, network), Ok(kind), "{}", address);
+    }
+
+    let invalid = [
+        // bad checksum
+        ("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMJ", Network::Mainnet),
+        // another network
+        ("mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r", Network::Mainnet),
+        (
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            Network::Regtest,
+        ),
+        // version 0 with a bech32m checksum
+        (
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+            Network::Mainnet,
+        ),
+        // version 1 with a bech32 checksum
+        (
+            "bcrt1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqdmchcc",
+            Network::Regtest,
+        ),
+        // version 0 program of 16 bytes
+        ("bc1qw508d6qejxtdg4y5r3zarvaryvjsqfh9", Network::Mainnet),
+        ("", Network::Regtest),
+    ];
+    for (address, network) in invalid {
+        assert!(parse_address(address, network).is_err(), "{}", address);
+    }
+
+    assert_eq!(
+        parse_address("mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r", Network::Mainnet),
+        Err("P2pkh address is for testnet or regtest, but the server is on mainnet".to_owned())
+    );
+    assert_eq!("Regtest".parse(), Ok(Network::Regtest));
+}
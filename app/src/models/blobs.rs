@@ -0,0 +1,513 @@
+//!
+//! Content-addressed blob store
+//!
+//! Content is stored once, in `blobs/<sha256>`, however many files share it. Each
+//! logical filename is an entry in `names/<filename>`, referencing a blob by hash.
+//! A blob is removed once no name references it; `gc` sweeps blobs left behind by
+//! a crash.
+//!
+
+use crate::{replicas::now_millis, AppError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+use tokio::{
+    fs::{self, File},
+    io::AsyncReadExt,
+    sync::Mutex,
+};
+
+/// Content type of binary files of unknown type
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Bytes read to sniff the content type
+const SNIFF_SIZE: usize = 8 * 1024;
+
+/// A logical file, referencing a blob
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub filename: String,
+    /// hash of the content, hex encoded
+    pub sha256: String,
+    pub size: u64,
+    pub file_type: String,
+    pub uploaded_at_ms: i64,
+}
+
+/// Stored content, and how many files reference it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobStat {
+    pub sha256: String,
+    pub size: u64,
+    pub content_type: String,
+    pub references: u64,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    entries: HashMap<String, FileEntry>,
+    /// reference count, by hash
+    references: HashMap<String, u64>,
+}
+
+/// Blobs, and the names referencing them
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    root: PathBuf,
+    index: Arc<Mutex<Index>>,
+}
+
+pub(crate) fn file_error(path: &Path, err: std::io::Error) -> AppError {
+    AppError::FileError(format!("{}: {}", path.display(), err))
+}
+
+/// MIME type guessed from the first bytes of content: known magic numbers, else
+/// text if valid UTF-8
+pub fn sniff_content_type(head: &[u8]) -> &'static str {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type();
+    }
+
+    match std::str::from_utf8(head) {
+        Ok(_) if head.is_empty() => DEFAULT_CONTENT_TYPE,
+        Ok(_) => "text/plain",
+        // cut in the middle of a character
+        Err(err) if err.error_len().is_none() && !head.is_empty() => "text/plain",
+        Err(_) => DEFAULT_CONTENT_TYPE,
+    }
+}
+
+pub(crate) fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// SHA-256 of a file, hex encoded, and its size
+pub(crate) async fn digest_file(path: &Path) -> crate::Result<(String, u64)> {
+    let mut file = File::open(path)
+        .await
+        .map_err(|err| file_error(path, err))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        match file.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(read) => {
+                hasher.update(&buffer[..read]);
+                size += read as u64;
+            }
+            Err(err) => return Err(file_error(path, err)),
+        }
+    }
+
+    Ok((hex(&hasher.finalize()), size))
+}
+
+impl Index {
+    fn acquire(&mut self, sha256: &str) {
+        *self.references.entry(sha256.to_owned()).or_default() += 1;
+    }
+
+    /// Whether the blob is no longer referenced
+    fn release(&mut self, sha256: &str) -> bool {
+        match self.references.get_mut(sha256) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            _ => {
+                self.references.remove(sha256);
+                true
+            }
+        }
+    }
+}
+
+impl BlobStore {
+    /// Open the store in `root`, indexing the names it holds
+    pub async fn open(root: impl Into<PathBuf>) -> crate::Result<Self> {
+        let root = root.into();
+        let store = Self {
+            root,
+            index: Arc::default(),
+        };
+
+        for dir in [store.blobs_dir(), store.names_dir()] {
+            fs::create_dir_all(&dir)
+                .await
+                .map_err(|err| file_error(&dir, err))?;
+        }
+
+        let names_dir = store.names_dir();
+        let mut names = fs::read_dir(&names_dir)
+            .await
+            .map_err(|err| file_error(&names_dir, err))?;
+        let mut index = store.index.lock().await;
+        while let Some(name) = names
+            .next_entry()
+            .await
+            .map_err(|err| file_error(&names_dir, err))?
+        {
+            let path = name.path();
+            // leftover of an interrupted write
+            if name.file_name().to_string_lossy().starts_with('.') {
+                let _ = fs::remove_file(&path).await;
+                continue;
+            }
+
+            let json = fs::read(&path)
+                .await
+                .map_err(|err| file_error(&path, err))?;
+            if let Ok(entry) = serde_json::from_slice::<FileEntry>(&json) {
+                index.acquire(&entry.sha256);
+                index.entries.insert(entry.filename.clone(), entry);
+            }
+        }
+        drop(index);
+
+        Ok(store)
+    }
+
+    fn blobs_dir(&self) -> PathBuf {
+        self.root.join("blobs")
+    }
+
+    fn names_dir(&self) -> PathBuf {
+        self.root.join("names")
+    }
+
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.blobs_dir().join(sha256)
+    }
+
+    /// Store the content of `path`, moved into the store, as `filename`. Identical
+    /// content is stored once. Replaces any file of the same name.
+    pub async fn put(
+        &self,
+        path: &Path,
+        filename: &str,
+        file_type: &str,
+        sha256: &str,
+        size: u64,
+    ) -> crate::Result<FileEntry> {
+        let entry = FileEntry {
+            filename: filename.to_owned(),
+            sha256: sha256.to_owned(),
+            size,
+            file_type: file_type.to_owned(),
+            uploaded_at_ms: now_millis(),
+        };
+        self.insert(path, entry.clone()).await?;
+
+        Ok(entry)
+    }
+
+    async fn insert(&self, path: &Path, entry: FileEntry) -> crate::Result<()> {
+        let blob = self.blob_path(&entry.sha256);
+        let mut index = self.index.lock().await;
+
+        if fs::metadata(&blob).await.is_ok() {
+            // deduplicated
+            let _ = fs::remove_file(path).await;
+        } else {
+            fs::rename(path, &blob)
+                .await
+                .map_err(|err| file_error(&blob, err))?;
+        }
+        self.write_entry(&entry).await?;
+
+        index.acquire(&entry.sha256);
+        if let Some(previous) = index.entries.insert(entry.filename.clone(), entry) {
+            self.release(&mut index, &previous.sha256).await;
+        }
+
+        Ok(())
+    }
+
+    /// Write an entry atomically, through a hidden temporary file. Its name is a
+    /// uuid only, as the filename may be up to the 255 bytes a name can have.
+    async fn write_entry(&self, entry: &FileEntry) -> crate::Result<()> {
+        let path = self.names_dir().join(&entry.filename);
+        let temp = self
+            .names_dir()
+            .join(format!(".{}.tmp", uuid::Uuid::new_v4()));
+        let json = serde_json::to_vec(entry)
+            .map_err(|err| AppError::FileError(format!("{}: {}", path.display(), err)))?;
+
+        fs::write(&temp, json)
+            .await
+            .map_err(|err| file_error(&temp, err))?;
+        fs::rename(&temp, &path)
+            .await
+            .map_err(|err| file_error(&path, err))
+    }
+
+    /// Drop a reference, and the blob with the last one
+    async fn release(&self, index: &mut Index, sha256: &str) {
+        if index.release(sha256) {
+            let _ = fs::remove_file(self.blob_path(sha256)).await;
+        }
+    }
+
+    pub async fn entry(&self, filename: &str) -> crate::Result<FileEntry> {
+        self.index
+            .lock()
+            .await
+            .entries
+            .get(filename)
+            .cloned()
+            .ok_or_else(|| AppError::FileNotFound(filename.to_owned()))
+    }
+
+    /// Open the content of `filename`
+    pub async fn open_file(&self, filename: &str) -> crate::Result<(FileEntry, File)> {
+        // open while no writer can release the blob; an open blob stays readable
+        let index = self.index.lock().await;
+        let entry = index
+            .entries
+            .get(filename)
+            .cloned()
+            .ok_or_else(|| AppError::FileNotFound(filename.to_owned()))?;
+        let blob = self.blob_path(&entry.sha256);
+
+        let file = File::open(&blob)
+            .await
+            .map_err(|err| file_error(&blob, err))?;
+        Ok((entry, file))
+    }
+
+    /// Every file, by name, optionally only those starting with `prefix`
+    pub async fn list(&self, prefix: &str) -> Vec<FileEntry> {
+        let mut entries: Vec<FileEntry> = self
+            .index
+            .lock()
+            .await
+            .entries
+            .values()
+            .filter(|entry| entry.filename.starts_with(prefix))
+            .cloned()
+            .collect();
+        entries.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+        entries
+    }
+
+    /// Remove `filename`, and its blob unless other files share it
+    pub async fn remove(&self, filename: &str) -> crate::Result<FileEntry> {
+        let mut index = self.index.lock().await;
+        let entry = index
+            .entries
+            .remove(filename)
+            .ok_or_else(|| AppError::FileNotFound(filename.to_owned()))?;
+
+        let path = self.names_dir().join(filename);
+        if let Err(err) = fs::remove_file(&path).await {
+            index.entries.insert(filename.to_owned(), entry);
+            return Err(file_error(&path, err));
+        }
+        self.release(&mut index, &entry.sha256).await;
+
+        Ok(entry)
+    }
+
+    /// Content type of a file: as uploaded, else sniffed from its content
+    pub async fn content_type(&self, entry: &FileEntry) -> String {
+        match entry.file_type.as_str() {
+            "" => self.sniff(&entry.sha256).await,
+            file_type => file_type.to_owned(),
+        }
+    }
+
+    async fn sniff(&self, sha256: &str) -> String {
+        let mut head = Vec::with_capacity(SNIFF_SIZE);
+        if let Ok(file) = File::open(self.blob_path(sha256)).await {
+            let _ = file.take(SNIFF_SIZE as u64).read_to_end(&mut head).await;
+        }
+
+        sniff_content_type(&head).to_owned()
+    }
+
+    /// Stat of a blob, by hash
+    pub async fn stat(&self, sha256: &str) -> crate::Result<BlobStat> {
+        let sha256 = sha256.trim().to_ascii_lowercase();
+        let (references, file_type) = {
+            let index = self.index.lock().await;
+            let references = *index
+                .references
+                .get(&sha256)
+                .ok_or_else(|| AppError::BlobNotFound(sha256.clone()))?;
+
+            // of the oldest file with this content
+            let file_type = index
+                .entries
+                .values()
+                .filter(|entry| entry.sha256 == sha256 && !entry.file_type.is_empty())
+                .min_by_key(|entry| entry.uploaded_at_ms)
+                .map(|entry| entry.file_type.clone());
+
+            (references, file_type)
+        };
+
+        let content_type = match file_type {
+            Some(file_type) => file_type,
+            None => self.sniff(&sha256).await,
+        };
+        let blob = self.blob_path(&sha256);
+        let size = fs::metadata(&blob)
+            .await
+            .map_err(|err| file_error(&blob, err))?
+            .len();
+
+        Ok(BlobStat {
+            sha256,
+            size,
+            content_type,
+            references,
+        })
+    }
+
+    /// Remove blobs no name references. Returns how many.
+    pub async fn gc(&self) -> crate::Result<usize> {
+        let blobs_dir = self.blobs_dir();
+        let index = self.index.lock().await;
+        let mut blobs = fs::read_dir(&blobs_dir)
+            .await
+            .map_err(|err| file_error(&blobs_dir, err))?;
+        let mut removed = 0;
+
+        while let Some(blob) = blobs
+            .next_entry()
+            .await
+            .map_err(|err| file_error(&blobs_dir, err))?
+        {
+            let sha256 = blob.file_name().to_string_lossy().into_owned();
+            if !index.references.contains_key(&sha256) {
+                let _ = fs::remove_file(blob.path()).await;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Move files stored flat in `dir`, before the blob store, into it
+    pub(crate) async fn import_dir(&self, dir: &Path) -> crate::Result<usize> {
+        let mut files = fs::read_dir(dir)
+            .await
+            .map_err(|err| file_error(dir, err))?;
+        let mut imported = 0;
+
+        while let Some(file) = files
+            .next_entry()
+            .await
+            .map_err(|err| file_error(dir, err))?
+        {
+            let filename = file.file_name().to_string_lossy().into_owned();
+            let metadata = match file.metadata().await {
+                Ok(metadata) if metadata.is_file() && !filename.starts_with('.') => metadata,
+                _ => continue,
+            };
+
+            let (sha256, size) = digest_file(&file.path()).await?;
+            let uploaded_at_ms = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_millis() as i64)
+                .unwrap_or_else(now_millis);
+            let entry = FileEntry {
+                filename,
+                sha256,
+                size,
+                file_type: String::new(),
+                uploaded_at_ms,
+            };
+            self.insert(&file.path(), entry).await?;
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+}
+
+#[test]
+fn test_deduplication() {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let root = std::env::temp_dir().join(format!("simply-blobs-{}", uuid::Uuid::new_v4()));
+            let store = BlobStore::open(&root).await.unwrap();
+
+            let put = |filename: &'static str, content: &'static [u8]| {
+                let store = store.clone();
+                let path = root.join(format!(".{}", filename));
+                async move {
+                    fs::write(&path, content).await.unwrap();
+                    let (sha256, size) = digest_file(&path).await.unwrap();
+                    store
+                        .put(&path, filename, "text/plain", &sha256, size)
+                        .await
+                        .unwrap()
+                }
+            };
+
+            let a = put("a.txt", b"same").await;
+            let b = put("b.txt", b"same").await;
+            assert_eq!(a.sha256, b.sha256);
+            assert_eq!(store.stat(&a.sha256).await.unwrap().references, 2);
+
+            // replacing b releases the shared blob once
+            let c = put("b.txt", b"other").await;
+            assert_eq!(store.stat(&a.sha256).await.unwrap().references, 1);
+            assert_eq!(store.stat(&c.sha256).await.unwrap().size, 5);
+
+            // reopened, the index is rebuilt from the names
+            let store = BlobStore::open(&root).await.unwrap();
+            assert_eq!(store.entry("b.txt").await.unwrap().sha256, c.sha256);
+            assert_eq!(store.stat(&a.sha256).await.unwrap().references, 1);
+
+            // removing the last reference removes the blob
+            assert_eq!(store.remove("b.txt").await.unwrap().sha256, c.sha256);
+            assert!(store.stat(&c.sha256).await.is_err());
+            assert!(matches!(
+                store.remove("b.txt").await,
+                Err(AppError::FileNotFound(_))
+            ));
+            assert_eq!(store.list("").await.len(), 1);
+
+            fs::write(store.blob_path(&"0".repeat(64)), b"orphan")
+                .await
+                .unwrap();
+            assert_eq!(store.gc().await.unwrap(), 1);
+            assert_eq!(store.gc().await.unwrap(), 0);
+
+            fs::remove_dir_all(&root).await.unwrap();
+        });
+}
+
+#[test]
+fn test_sniff_content_type() {
+    assert_eq!(
+        sniff_content_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+        "image/png"
+    );
+    assert_eq!(sniff_content_type(b"%PDF-1.7"), "application/pdf");
+    assert_eq!(
+        sniff_content_type("plain text, café".as_bytes()),
+        "text/plain"
+    );
+    assert_eq!(sniff_content_type(&"é".as_bytes()[..1]), "text/plain");
+    assert_eq!(
+        sniff_content_type(&[0xff, 0xfe, 0x00, 0x9f]),
+        DEFAULT_CONTENT_TYPE
+    );
+    assert_eq!(sniff_content_type(b""), DEFAULT_CONTENT_TYPE);
+}
@@ -0,0 +1,249 @@
+use crate::AppError;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use std::{
+    borrow::Cow,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Stored values starting with this marker carry a header, `\0<codec>:`
+const HEADER_MARKER: char = '\0';
+
+/// Level of zstd compression, favouring speed
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression codec of stored values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// stored as is
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    fn name(&self) -> &'static str {
+        match self {
+            Codec::None => "raw",
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = AppError;
+
+    fn from_str(codec: &str) -> Result<Self, Self::Err> {
+        match codec.to_lowercase().as_str() {
+            "none" | "raw" => Ok(Codec::None),
+            "lz4" => Ok(Codec::Lz4),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(AppError::InvalidSetting {
+                key: "COMPRESSION_CODEC".to_owned(),
+                value: codec.to_owned(),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Compression counters, of values above the threshold
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CompressionStats {
+    /// values stored compressed
+    pub compressed_values: u64,
+    /// values above the threshold, stored raw as compression did not pay off
+    pub incompressible_values: u64,
+    /// size of values above the threshold, before compression
+    pub raw_bytes: u64,
+    /// size of values above the threshold, as stored
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    /// raw size over stored size, e.g. 10.0 when values shrink tenfold
+    pub fn ratio(&self) -> f64 {
+        match self.stored_bytes {
+            0 => 1.0,
+            stored_bytes => self.raw_bytes as f64 / stored_bytes as f64,
+        }
+    }
+}
+
+/// Transparent compression of stored values.
+///
+/// Values of at least `threshold` bytes are compressed with `codec`, and stored as
+/// `\0<codec>:<base64 payload>`. Smaller values are stored as is, unless they start
+/// with the header marker, in which case they are stored as `\0raw:<value>`.
+#[derive(Debug, Default)]
+pub struct Compressor {
+    codec: Codec,
+    threshold: usize,
+    compressed_values: AtomicU64,
+    incompressible_values: AtomicU64,
+    raw_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl Compressor {
+    pub fn new(codec: Codec, threshold: usize) -> Self {
+        Self {
+            codec,
+            threshold,
+            ..Default::default()
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            compressed_values: self.compressed_values.load(Ordering::Relaxed),
+            incompressible_values: self.incompressible_values.load(Ordering::Relaxed),
+            raw_bytes: self.raw_bytes.load(Ordering::Relaxed),
+            stored_bytes: self.stored_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn compress(codec: Codec, value: &[u8]) -> crate::Result<Vec<u8>> {
+        match codec {
+            Codec::None => Ok(value.to_vec()),
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(value)),
+            Codec::Zstd => zstd::encode_all(value, ZSTD_LEVEL)
+                .map_err(|err| AppError::CompressionError(err.to_string())),
+        }
+    }
+
+    fn decompress(codec: Codec, payload: &[u8]) -> crate::Result<Vec<u8>> {
+        match codec {
+            Codec::None => Ok(payload.to_vec()),
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(payload)
+                .map_err(|err| AppError::CompressionError(err.to_string())),
+            Codec::Zstd => {
+                zstd::decode_all(payload).map_err(|err| AppError::CompressionError(err.to_string()))
+            }
+        }
+    }
+
+    /// Encode `value` into its stored form
+    pub fn encode<'v>(&self, value: &'v str) -> crate::Result<Cow<'v, str>> {
+        if self.codec == Codec::None || value.len() < self.threshold {
+            return Ok(match value.starts_with(HEADER_MARKER) {
+                true => Cow::Owned(format!("{}{}:{}", HEADER_MARKER, Codec::None, value)),
+                false => Cow::Borrowed(value),
+            });
+        }
+
+        let payload = Self::compress(self.codec, value.as_bytes())?;
+        let encoded = format!(
+            "{}{}:{}",
+            HEADER_MARKER,
+            self.codec,
+            STANDARD.encode(payload)
+        );
+
+        let stored = match encoded.len() < value.len() {
+            true => {
+                self.compressed_values.fetch_add(1, Ordering::Relaxed);
+                encoded
+            }
+            false => {
+                // compression did not pay off
+                self.incompressible_values.fetch_add(1, Ordering::Relaxed);
+                match value.starts_with(HEADER_MARKER) {
+                    true => format!("{}{}:{}", HEADER_MARKER, Codec::None, value),
+                    false => value.to_owned(),
+                }
+            }
+        };
+        self.raw_bytes
+            .fetch_add(value.len() as u64, Ordering::Relaxed);
+        self.stored_bytes
+            .fetch_add(stored.len() as u64, Ordering::Relaxed);
+
+        Ok(Cow::Owned(stored))
+    }
+
+    /// Decode a stored value, whatever codec it was compressed with
+    pub fn decode<'v>(&self, stored: &'v str) -> crate::Result<Cow<'v, str>> {
+        let header = match stored.strip_prefix(HEADER_MARKER) {
+            Some(header) => header,
+            None => return Ok(Cow::Borrowed(stored)),
+        };
+
+        let (codec, payload) = header
+            .split_once(':')
+            .ok_or_else(|| AppError::CompressionError("malformed value header".to_owned()))?;
+
+        match codec.parse::<Codec>() {
+            Ok(Codec::None) => Ok(Cow::Borrowed(payload)),
+            Ok(codec) => {
+                let payload = STANDARD
+                    .decode(payload)
+                    .map_err(|err| AppError::CompressionError(err.to_string()))?;
+                let value = Self::decompress(codec, &payload)?;
+
+                String::from_utf8(value)
+                    .map(Cow::Owned)
+                    .map_err(|err| AppError::CompressionError(err.to_string()))
+            }
+            Err(_) => Err(AppError::CompressionError(format!(
+                "unknown codec `{}`",
+                codec
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(Codec::Lz4 ; "lz4")]
+    #[test_case(Codec::Zstd ; "zstd")]
+    fn test_compress_large_values(codec: Codec) {
+        let compressor = Compressor::new(codec, 1024);
+        let value = r#"{"name": "simply", "tags": ["hard", "rust"]}"#.repeat(1000);
+
+        let stored = compressor.encode(&value).unwrap();
+        assert!(stored.starts_with(&format!("\0{}:", codec)));
+        assert!(stored.len() * 10 < value.len());
+        assert_eq!(compressor.decode(&stored).unwrap(), value);
+
+        let stats = compressor.stats();
+        assert_eq!(stats.compressed_values, 1);
+        assert!(stats.ratio() > 10.0);
+
+        // values written with another codec remain readable
+        assert_eq!(Compressor::default().decode(&stored).unwrap(), value);
+    }
+
+    #[test]
+    fn test_small_and_marked_values() {
+        let compressor = Compressor::new(Codec::Lz4, 1024);
+
+        assert!(matches!(
+            compressor.encode("small").unwrap(),
+            Cow::Borrowed("small")
+        ));
+        assert_eq!(compressor.decode("small").unwrap(), "small");
+
+        // raw values looking like a header are escaped
+        let stored = compressor.encode("\0lz4:not compressed").unwrap();
+        assert_eq!(stored, "\0raw:\0lz4:not compressed");
+        assert_eq!(compressor.decode(&stored).unwrap(), "\0lz4:not compressed");
+
+        assert!(compressor.decode("\0gzip:abc").is_err());
+        assert_eq!(compressor.stats(), CompressionStats::default());
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{AppError, Settings};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use config::{Config, File, FileFormat};
+use std::collections::HashMap;
+
+/// Salt used when "ENCRYPTION_SALT" is not set
+const DEFAULT_SALT: &str = "simply-hard";
+
+/// A value encrypted at rest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sealed {
+    /// ciphertext, base64 encoded
+    pub value: String,
+    /// nonce, base64 encoded
+    pub nonce: String,
+    /// id of the data key
+    pub key_id: String,
+}
+
+/// Data keys, by key id. Values are sealed with the active key, and opened with
+/// whichever key sealed them.
+///
+/// Keys are derived from passphrases with Argon2id; the salt is the configured salt
+/// suffixed by the key id, so every key id gets its own salt. Values are encrypted
+/// with XChaCha20-Poly1305, authenticating the record key as associated data: a
+/// sealed value cannot be moved to another record.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    active: Option<String>,
+    ciphers: HashMap<String, XChaCha20Poly1305>,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("active", &self.active)
+            .field("key_ids", &self.key_ids())
+            .finish()
+    }
+}
+
+/// Derive a 256-bit data key from `passphrase`, with Argon2id
+pub fn derive_key(passphrase: &str, salt: &str, key_id: &str) -> crate::Result<[u8; 32]> {
+    let salt = format!("{}:{}", salt, key_id);
+    let mut key = [0u8; 32];
+
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+        .map_err(|err| AppError::EncryptionError(format!("key derivation failed, {}", err)))?;
+
+    Ok(key)
+}
+
+impl Keyring {
+    /// Keyring holding `keys`, sealing with `active`
+    pub fn new(active: &str, keys: HashMap<String, [u8; 32]>) -> crate::Result<Self> {
+        if !keys.contains_key(active) {
+            return Err(AppError::EncryptionError(format!(
+                "unknown key id `{}`",
+                active
+            )));
+        }
+
+        Ok(Self {
+            active: Some(active.to_owned()),
+            ciphers: keys
+                .into_iter()
+                .map(|(key_id, key)| (key_id, XChaCha20Poly1305::new(Key::from_slice(&key))))
+                .collect(),
+        })
+    }
+
+    /// Derive a keyring from passphrases, by key id
+    pub fn derive(
+        active: &str,
+        passphrases: &HashMap<String, String>,
+        salt: &str,
+    ) -> crate::Result<Self> {
+        let keys = passphrases
+            .iter()
+            .map(|(key_id, passphrase)| {
+                derive_key(passphrase, salt, key_id).map(|key| (key_id.to_owned(), key))
+            })
+            .collect::<crate::Result<HashMap<_, _>>>()?;
+
+        Self::new(active, keys)
+    }
+
+    /// Load the keyring from settings:
+    /// - "ENCRYPTION_KEY_ID": id of the active key; encryption is off when empty
+    /// - "ENCRYPTION_PASSPHRASE": passphrase of the active key
+    /// - "ENCRYPTION_KEYFILE": toml file of `key_id = "passphrase"` lines, holding
+    ///   retired keys still needed to open values, while they are re-encrypted
+    /// - "ENCRYPTION_SALT": salt of the key derivation
+    pub async fn from_settings() -> crate::Result<Self> {
+        let active = match Settings::get_config_item("ENCRYPTION_KEY_ID").await {
+            Some(active) if !active.is_empty() => active,
+            _ => return Ok(Self::default()),
+        };
+
+        let mut passphrases = match Settings::get_config_item("ENCRYPTION_KEYFILE").await {
+            Some(path) if !path.is_empty() => Config::builder()
+                .add_source(File::new(&path, FileFormat::Toml))
+                .build()
+                .and_then(|config| config.try_deserialize::<HashMap<String, String>>())
+                .map_err(|_| AppError::InvalidSetting {
+                    key: "ENCRYPTION_KEYFILE".to_owned(),
+                    value: path,
+                })?,
+            _ => HashMap::new(),
+        };
+
+        if let Some(passphrase) = Settings::get_config_item("ENCRYPTION_PASSPHRASE").await {
+            if !passphrase.is_empty() {
+                passphrases.insert(active.clone(), passphrase);
+            }
+        }
+
+        if !passphrases.contains_key(&active) {
+            return Err(AppError::InvalidSetting {
+                key: "ENCRYPTION_KEY_ID".to_owned(),
+                value: active,
+            });
+        }
+
+        let salt = Settings::get_config_item("ENCRYPTION_SALT")
+            .await
+            .unwrap_or(DEFAULT_SALT.to_owned());
+
+        Self::derive(&active, &passphrases, &salt)
+    }
+
+    /// This keyring, also holding the keys of `previous` it lacks, so that values
+    /// sealed before a rotation stay readable
+    pub fn retaining(mut self, previous: &Keyring) -> Self {
+        for (key_id, cipher) in previous.ciphers.iter() {
+            self.ciphers
+                .entry(key_id.to_owned())
+                .or_insert_with(|| cipher.clone());
+        }
+        self
+    }
+
+    /// Id of the key sealing new values, `None` when encryption is off
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    pub fn key_ids(&self) -> Vec<&str> {
+        let mut key_ids: Vec<&str> = self.ciphers.keys().map(String::as_str).collect();
+        key_ids.sort_unstable();
+        key_ids
+    }
+
+    /// Encrypt `plaintext`, stored under `record_key`, with the active key. Returns
+    /// `None` when encryption is off.
+    pub fn seal(&self, record_key: &str, plaintext: &str) -> crate::Result<Option<Sealed>> {
+        let key_id = match &self.active {
+            Some(key_id) => key_id,
+            None => return Ok(None),
+        };
+        let cipher = &self.ciphers[key_id];
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: record_key.as_bytes(),
+                },
+            )
+            .map_err(|err| AppError::EncryptionError(err.to_string()))?;
+
+        Ok(Some(Sealed {
+            value: STANDARD.encode(ciphertext),
+            nonce: STANDARD.encode(nonce),
+            key_id: key_id.to_owned(),
+        }))
+    }
+
+    /// Decrypt a value sealed with `key_id`, stored under `record_key`
+    pub fn open(
+        &self,
+        record_key: &str,
+        value: &str,
+        nonce: &str,
+        key_id: &str,
+    ) -> crate::Result<String> {
+        let cipher = self
+            .ciphers
+            .get(key_id)
+            .ok_or_else(|| AppError::EncryptionError(format!("unknown key id `{}`", key_id)))?;
+
+        let nonce = STANDARD
+            .decode(nonce)
+            .map_err(|err| AppError::EncryptionError(err.to_string()))?;
+        if nonce.len() != 24 {
+            return Err(AppError::EncryptionError("malformed nonce".to_owned()));
+        }
+        let ciphertext = STANDARD
+            .decode(value)
+            .map_err(|err| AppError::EncryptionError(err.to_string()))?;
+
+        let plaintext = cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: record_key.as_bytes(),
+                },
+            )
+            .map_err(|_| AppError::EncryptionError(format!("cannot decrypt `{}`", record_key)))?;
+
+        String::from_utf8(plaintext).map_err(|err| AppError::EncryptionError(err.to_string()))
+    }
+}
+
+#[test]
+fn test_seal_and_open() {
+    let passphrases = HashMap::from([
+        ("k1".to_owned(), "correct horse".to_owned()),
+        ("k2".to_owned(), "battery staple".to_owned()),
+    ]);
+    let old = Keyring::derive("k1", &passphrases, DEFAULT_SALT).unwrap();
+    let new = Keyring::derive("k2", &passphrases, DEFAULT_SALT).unwrap();
+
+    let sealed = old.seal("foo", "bar").unwrap().unwrap();
+    assert_eq!(sealed.key_id, "k1");
+    assert_ne!(sealed.value, "bar");
+    assert_ne!(old.seal("foo", "bar").unwrap().unwrap().nonce, sealed.nonce);
+
+    // retired keys still open values
+    let open = |keyring: &Keyring, record_key| {
+        keyring.open(record_key, &sealed.value, &sealed.nonce, &sealed.key_id)
+    };
+    assert_eq!(open(&new, "foo").unwrap(), "bar");
+
+    // sealed values are bound to their record key
+    assert!(open(&new, "baz").is_err());
+
+    // and to their data key
+    let other = Keyring::derive("k1", &passphrases, "another salt").unwrap();
+    assert!(open(&other, "foo").is_err());
+
+    assert!(Keyring::default().seal("foo", "bar").unwrap().is_none());
+    assert!(Keyring::derive("k3", &passphrases, DEFAULT_SALT).is_err());
+}
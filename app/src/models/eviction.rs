@@ -0,0 +1,477 @@
+use crate::AppError;
+use std::{
+    collections::{BTreeSet, HashMap},
+    str::FromStr,
+};
+
+/// Approximate per-record overhead: record id, field names and index entries
+const RECORD_OVERHEAD: usize = 64;
+
+/// What to do when a write would exceed `MAX_MEMORY`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// reject the write
+    #[default]
+    NoEviction,
+    /// evict the least recently used key
+    AllKeysLru,
+    /// evict the least frequently used key
+    AllKeysLfu,
+    /// evict the key with the nearest expiry, among keys with a time to live
+    VolatileTtl,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = AppError;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy.to_lowercase().as_str() {
+            "noeviction" | "no-eviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => Err(AppError::InvalidSetting {
+                key: "MAXMEMORY_POLICY".to_owned(),
+                value: policy.to_owned(),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parse a memory size, e.g. "1024", "512kb", "64mb" or "1gb"
+pub fn parse_memory_size(size: &str) -> crate::Result<usize> {
+    let size = size.trim().to_lowercase();
+    let (digits, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => size.split_at(index),
+        None => (size.as_str(), ""),
+    };
+
+    let multiplier = match unit.trim() {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => 0,
+    };
+
+    match digits.parse::<usize>() {
+        Ok(digits) if multiplier > 0 => Ok(digits * multiplier),
+        _ => Err(AppError::InvalidSetting {
+            key: "MAX_MEMORY".to_owned(),
+            value: size,
+        }),
+    }
+}
+
+/// Approximate memory used by a stored key-value record
+pub fn record_size(key: &str, value: &str) -> usize {
+    // the key is stored twice: in the record id, and in the `key` field
+    2 * key.len() + value.len() + RECORD_OVERHEAD
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    size: usize,
+    /// logical clock of the last access
+    last_access: u64,
+    /// number of accesses
+    hits: u64,
+    /// expiry, in milliseconds since unix epoch
+    expires_at: Option<i64>,
+}
+
+/// Outcome of admitting a write
+#[derive(Debug, Default)]
+pub struct Admission {
+    /// keys to be removed from the store, to make room
+    pub victims: Vec<String>,
+    /// accounting of the key before the write, to roll back a failed write
+    pub previous: Option<Entry>,
+    /// accounting of the victims, to roll back a failed write
+    evicted: Vec<Entry>,
+}
+
+/// Memory usage and eviction counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryStats {
+    pub used_memory: usize,
+    pub max_memory: usize,
+    pub keys: usize,
+    pub evicted_keys: u64,
+}
+
+/// Approximate size accounting of stored records, selecting keys to evict when
+/// `max_memory` is exceeded. A `max_memory` of 0 means unlimited.
+///
+/// Recency is measured with a logical clock, ticking on every access, so that
+/// eviction is deterministic.
+#[derive(Debug, Default)]
+pub struct MemoryTracker {
+    max_memory: usize,
+    policy: EvictionPolicy,
+    used_memory: usize,
+    clock: u64,
+    evicted_keys: u64,
+    entries: HashMap<String, Entry>,
+    /// keys with a time to live, by expiry
+    expiries: BTreeSet<(i64, String)>,
+}
+
+impl MemoryTracker {
+    pub fn new(max_memory: usize, policy: EvictionPolicy) -> Self {
+        Self {
+            max_memory,
+            policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            used_memory: self.used_memory,
+            max_memory: self.max_memory,
+            keys: self.entries.len(),
+            evicted_keys: self.evicted_keys,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Account `entry` to `key`, in place of its previous entry
+    fn insert(&mut self, key: &str, entry: Entry) {
+        let expires_at = entry.expires_at;
+        if let Some(previous) = self.entries.insert(key.to_owned(), entry) {
+            self.unindex(key, &previous);
+        }
+        if let Some(expires_at) = expires_at {
+            self.expiries.insert((expires_at, key.to_owned()));
+        }
+    }
+
+    /// Stop accounting `key`, returning its entry
+    fn take(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.unindex(key, &entry);
+        Some(entry)
+    }
+
+    fn unindex(&mut self, key: &str, entry: &Entry) {
+        if let Some(expires_at) = entry.expires_at {
+            self.expiries.remove(&(expires_at, key.to_owned()));
+        }
+    }
+
+    /// Account a write of `size` bytes to `key`, discarding any previous time to
+    /// live. Returns the keys to evict to stay within `max_memory`, or
+    /// `AppError::OutOfMemory` if not enough keys can be evicted.
+    pub fn admit(&mut self, key: &str, size: usize) -> crate::Result<Admission> {
+        let previous = self.entries.get(key).cloned();
+        let previous_size = previous.as_ref().map(|entry| entry.size).unwrap_or(0);
+        let mut used_memory = self.used_memory - previous_size + size;
+
+        let mut victims = Vec::new();
+        if self.max_memory > 0 {
+            if size > self.max_memory {
+                return Err(self.out_of_memory(size));
+            }
+
+            let mut candidates: Vec<(&String, &Entry)> = self
+                .entries
+                .iter()
+                .filter(|(candidate, entry)| {
+                    *candidate != key
+                        && (self.policy != EvictionPolicy::VolatileTtl
+                            || entry.expires_at.is_some())
+                })
+                .collect();
+
+            // victims first
+            match self.policy {
+                EvictionPolicy::NoEviction => candidates.clear(),
+                EvictionPolicy::AllKeysLru => candidates.sort_by_key(|(_, e)| e.last_access),
+                EvictionPolicy::AllKeysLfu => {
+                    candidates.sort_by_key(|(_, e)| (e.hits, e.last_access))
+                }
+                EvictionPolicy::VolatileTtl => {
+                    candidates.sort_by_key(|(_, e)| (e.expires_at, e.last_access))
+                }
+            }
+
+            let mut candidates = candidates.into_iter();
+            while used_memory > self.max_memory {
+                match candidates.next() {
+                    Some((candidate, entry)) => {
+                        used_memory -= entry.size;
+                        victims.push(candidate.to_owned());
+                    }
+                    None => return Err(self.out_of_memory(size)),
+                }
+            }
+        }
+
+        let evicted = victims
+            .iter()
+            .filter_map(|victim| self.take(victim))
+            .collect();
+        self.evicted_keys += victims.len() as u64;
+        self.used_memory = used_memory;
+
+        let clock = self.tick();
+        let hits = previous.as_ref().map(|entry| entry.hits).unwrap_or(0) + 1;
+        self.insert(
+            key,
+            Entry {
+                size,
+                last_access: clock,
+                hits,
+                expires_at: None,
+            },
+        );
+
+        Ok(Admission {
+            victims,
+            previous,
+            evicted,
+        })
+    }
+
+    /// Account a write of `size` bytes to `key`, expiring at `expires_at`, neither
+    /// evicting nor rejecting: a write replicated from the leader, which made room
+    /// for it already
+    pub fn admit_replicated(&mut self, key: &str, size: usize, expires_at: Option<i64>) {
+        self.remove(key);
+        self.used_memory += size;
+
+        let clock = self.tick();
+        self.insert(
+            key,
+            Entry {
+                size,
+                last_access: clock,
+                hits: 1,
+                expires_at,
+            },
+        );
+    }
+
+    fn out_of_memory(&self, size: usize) -> AppError {
+        AppError::OutOfMemory {
+            requested: size,
+            used: self.used_memory,
+            max: self.max_memory,
+        }
+    }
+
+    /// Undo the accounting of a write that failed to reach the store, and of the
+    /// evictions it required
+    pub fn rollback(&mut self, key: &str, admission: Admission) {
+        if let Some(entry) = self.take(key) {
+            self.used_memory -= entry.size;
+        }
+        if let Some(previous) = admission.previous {
+            self.used_memory += previous.size;
+            self.insert(key, previous);
+        }
+
+        self.evicted_keys -= admission.victims.len() as u64;
+        for (victim, entry) in admission.victims.iter().zip(admission.evicted) {
+            self.used_memory += entry.size;
+            self.insert(victim, entry);
+        }
+    }
+
+    /// Record a read of `key`
+    pub fn touch(&mut self, key: &str) {
+        let clock = self.tick();
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.last_access = clock;
+            entry.hits += 1;
+        }
+    }
+
+    /// Set the expiry of `key`, in milliseconds since unix epoch
+    pub fn expire_at(&mut self, key: &str, expires_at: i64) {
+        if let Some(mut entry) = self.entries.get(key).cloned() {
+            entry.expires_at = Some(expires_at);
+            self.insert(key, entry);
+        }
+    }
+
+    /// Keys whose time to live elapsed at `now_ms`, soonest expired first
+    pub fn expired(&self, now_ms: i64) -> Vec<String> {
+        self.expiries
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now_ms)
+            .map(|(_, key)| key.to_owned())
+            .collect()
+    }
+
+    /// `key` has a time to live, which elapsed at `now_ms`
+    pub fn is_expired(&self, key: &str, now_ms: i64) -> bool {
+        matches!(
+            self.entries.get(key),
+            Some(Entry { expires_at: Some(expires_at), .. }) if *expires_at <= now_ms
+        )
+    }
+
+    /// Stop accounting a removed key
+    pub fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.take(key) {
+            self.used_memory -= entry.size;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.expiries.clear();
+        self.used_memory = 0;
+    }
+}
+
+#[test]
+fn test_parse_memory_size() {
+    assert_eq!(parse_memory_size("1024").unwrap(), 1024);
+    assert_eq!(parse_memory_size("512kb").unwrap(), 512 * 1024);
+    assert_eq!(parse_memory_size("64MB").unwrap(), 64 * 1024 * 1024);
+    assert_eq!(parse_memory_size("1g").unwrap(), 1024 * 1024 * 1024);
+    assert!(parse_memory_size("lots").is_err());
+    assert!(parse_memory_size("10tb").is_err());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    /// Tracker holding three 100 bytes keys "a", "b", "c" written in that order.
+    /// "a" is then read twice, "b" once; "b" and "c" expire, "c" first.
+    fn tracker(policy: EvictionPolicy) -> MemoryTracker {
+        let mut tracker = MemoryTracker::new(300, policy);
+        for key in ["a", "b", "c"] {
+            tracker.admit(key, 100).unwrap();
+        }
+        tracker.touch("a");
+        tracker.touch("b");
+        tracker.touch("a");
+        tracker.expire_at("b", 2_000);
+        tracker.expire_at("c", 1_000);
+        tracker
+    }
+
+    #[test_case(EvictionPolicy::AllKeysLru, "c" ; "least recently used")]
+    #[test_case(EvictionPolicy::AllKeysLfu, "c" ; "least frequently used")]
+    #[test_case(EvictionPolicy::VolatileTtl, "c" ; "nearest expiry")]
+    fn test_evict_one(policy: EvictionPolicy, victim: &str) {
+        let mut tracker = tracker(policy);
+
+        let admission = tracker.admit("d", 100).unwrap();
+        assert_eq!(admission.victims, vec![victim.to_owned()]);
+        assert_eq!(tracker.stats().used_memory, 300);
+        assert_eq!(tracker.stats().evicted_keys, 1);
+    }
+
+    #[test]
+    fn test_lru_and_lfu_diverge() {
+        // "hot" is read often, but long ago; "new" was written last, read never
+        let mut lru = MemoryTracker::new(200, EvictionPolicy::AllKeysLru);
+        let mut lfu = MemoryTracker::new(200, EvictionPolicy::AllKeysLfu);
+        for tracker in [&mut lru, &mut lfu] {
+            tracker.admit("hot", 100).unwrap();
+            for _ in 0..3 {
+                tracker.touch("hot");
+            }
+            tracker.admit("new", 100).unwrap();
+        }
+
+        assert_eq!(lru.admit("d", 100).unwrap().victims, vec!["hot"]);
+        assert_eq!(lfu.admit("d", 100).unwrap().victims, vec!["new"]);
+    }
+
+    #[test]
+    fn test_lfu_ties_evict_least_recently_used() {
+        let mut tracker = tracker(EvictionPolicy::AllKeysLfu);
+        tracker.touch("c");
+        tracker.touch("c");
+        tracker.touch("b");
+
+        // three hits each; "a" was used longest ago, then "c"
+        assert_eq!(tracker.admit("d", 150).unwrap().victims, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_no_eviction_rejects_writes() {
+        let mut tracker = tracker(EvictionPolicy::NoEviction);
+
+        assert!(matches!(
+            tracker.admit("d", 1),
+            Err(AppError::OutOfMemory { .. })
+        ));
+        // overwriting with a value of the same size still fits
+        assert!(tracker.admit("a", 100).unwrap().victims.is_empty());
+        assert_eq!(tracker.stats().used_memory, 300);
+
+        // replicated writes are never rejected, nor evict keys
+        tracker.admit_replicated("d", 100, None);
+        assert_eq!(tracker.stats().used_memory, 400);
+        assert_eq!(tracker.stats().evicted_keys, 0);
+    }
+
+    #[test]
+    fn test_volatile_ttl_only_evicts_volatile_keys() {
+        let mut tracker = tracker(EvictionPolicy::VolatileTtl);
+
+        // "b" and "c" have a ttl, "a" has not
+        assert_eq!(tracker.admit("d", 150).unwrap().victims, vec!["c", "b"]);
+        assert!(matches!(
+            tracker.admit("e", 100),
+            Err(AppError::OutOfMemory { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rollback_and_expiry() {
+        let mut tracker = tracker(EvictionPolicy::AllKeysLru);
+
+        let admission = tracker.admit("a", 50).unwrap();
+        tracker.rollback("a", admission);
+        assert_eq!(tracker.stats().used_memory, 300);
+
+        // and its evictions
+        let admission = tracker.admit("a", 150).unwrap();
+        assert_eq!(admission.victims, vec!["c"]);
+        tracker.rollback("a", admission);
+        assert_eq!(tracker.stats().used_memory, 300);
+        assert_eq!(tracker.stats().evicted_keys, 0);
+
+        assert!(tracker.is_expired("c", 1_000));
+        assert!(!tracker.is_expired("b", 1_000));
+        assert!(!tracker.is_expired("a", i64::MAX));
+        assert_eq!(tracker.expired(2_000), vec!["c", "b"]);
+
+        // a new write discards the time to live
+        tracker.admit("c", 100).unwrap();
+        assert!(!tracker.is_expired("c", 1_000));
+        assert_eq!(tracker.expired(2_000), vec!["b"]);
+        tracker.remove("b");
+        assert!(tracker.expired(i64::MAX).is_empty());
+    }
+}
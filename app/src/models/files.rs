@@ -0,0 +1,671 @@
+//!
+//! File storage, for the gupload service
+//!
+//! Uploads go through a session: content is appended to a hidden partial file,
+//! `.<session>.part`, next to its metadata, `.<session>.json`. An interrupted
+//! upload resumes from the size of its partial file. Once complete and verified,
+//! the partial file is moved into the blob store: readers never see a partial file.
+//!
+
+use super::{
+    blobs::{digest_file, file_error, hex, BlobStore, FileEntry},
+    eviction,
+};
+use crate::{AppError, Settings};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, Take},
+};
+use tracing::{error, info};
+
+/// Directory of uploaded files, unless `UPLOAD_DIR` is set
+pub const DEFAULT_UPLOAD_DIR: &str = "uploads";
+
+/// Bytes per download shard, unless `DOWNLOAD_SHARD_SIZE` is set
+pub const DEFAULT_SHARD_SIZE: usize = 64 * 1024;
+
+/// Largest download shard, well below the 4MB message limit of grpc
+pub const MAX_SHARD_SIZE: usize = 2 * 1024 * 1024;
+
+/// Upload sessions untouched for longer are discarded
+pub const UPLOAD_SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Directory of uploaded files
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    root: PathBuf,
+    blobs: BlobStore,
+    shard_size: usize,
+    /// sessions being written to, by a stream
+    active: Arc<Mutex<HashSet<String>>>,
+}
+
+/// An upload, resumable until committed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: String,
+    pub filename: String,
+    pub file_type: String,
+    /// total size, if known
+    pub size: Option<u64>,
+    /// expected SHA-256 of the whole file, hex encoded
+    pub sha256: Option<String>,
+}
+
+/// Upload session being written to. Dropped without `commit`, the partial file is
+/// kept, to resume from.
+#[derive(Debug)]
+pub struct FileWriter {
+    store: FileStore,
+    session: UploadSession,
+    file: File,
+    offset: u64,
+}
+
+/// SHA-256, hex encoded
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+/// Filenames are flat and visible: no directories, no leading dot
+fn validate_filename(filename: &str) -> crate::Result<()> {
+    let reason = if filename.trim().is_empty() {
+        "must not be empty"
+    } else if filename.starts_with('.') {
+        "must not start with a dot"
+    } else if filename.contains(['/', '\\', '\0']) {
+        "must not contain path separators"
+    } else if filename.len() > 255 {
+        "must be at most 255 bytes"
+    } else {
+        return Ok(());
+    };
+
+    Err(AppError::InvalidArgument {
+        field: "filename".to_owned(),
+        reason: reason.to_owned(),
+    })
+}
+
+/// Hex encoded SHA-256, if any
+fn validate_sha256(sha256: &str) -> crate::Result<Option<String>> {
+    match sha256.trim() {
+        "" => Ok(None),
+        sha256 if sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(Some(sha256.to_ascii_lowercase()))
+        }
+        _ => Err(AppError::InvalidArgument {
+            field: "sha256".to_owned(),
+            reason: "must be 64 hex digits".to_owned(),
+        }),
+    }
+}
+
+impl FileStore {
+    /// Open the store in `root`, created if missing
+    pub async fn open(root: impl Into<PathBuf>) -> crate::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)
+            .await
+            .map_err(|err| file_error(&root, err))?;
+
+        let blobs = BlobStore::open(&root).await?;
+
+        Ok(Self {
+            root,
+            blobs,
+            shard_size: DEFAULT_SHARD_SIZE,
+            active: Arc::default(),
+        })
+    }
+
+    /// Download shards of `shard_size` bytes, at most `MAX_SHARD_SIZE`
+    pub fn with_shard_size(self, shard_size: usize) -> Self {
+        Self {
+            shard_size: shard_size.clamp(1, MAX_SHARD_SIZE),
+            ..self
+        }
+    }
+
+    /// Store in "UPLOAD_DIR", downloaded in shards of "DOWNLOAD_SHARD_SIZE" (e.g. "64kb")
+    pub async fn from_settings() -> crate::Result<Self> {
+        let root = Settings::get_config_item("UPLOAD_DIR")
+            .await
+            .filter(|dir| !dir.trim().is_empty())
+            .unwrap_or(DEFAULT_UPLOAD_DIR.to_owned());
+
+        let shard_size = match Settings::get_config_item("DOWNLOAD_SHARD_SIZE").await {
+            Some(size) => match eviction::parse_memory_size(&size) {
+                Ok(shard_size) if shard_size > 0 && shard_size <= MAX_SHARD_SIZE => shard_size,
+                _ => {
+                    return Err(AppError::InvalidSetting {
+                        key: "DOWNLOAD_SHARD_SIZE".to_owned(),
+                        value: size,
+                    })
+                }
+            },
+            None => DEFAULT_SHARD_SIZE,
+        };
+
+        let store = Self::open(&root).await.map_err(|err| {
+            error!(error = format!("{:?}", err));
+            AppError::InvalidSetting {
+                key: "UPLOAD_DIR".to_owned(),
+                value: root.clone(),
+            }
+        })?;
+
+        Ok(store.with_shard_size(shard_size))
+    }
+
+    /// Move files stored flat in the root, before the blob store, into it. Run once,
+    /// by `simply-server import-files`. Returns how many.
+    pub async fn import_files(&self) -> crate::Result<usize> {
+        self.blobs.import_dir(&self.root).await
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn shard_size(&self) -> usize {
+        self.shard_size
+    }
+
+    /// Content of the files, and their names
+    pub fn blobs(&self) -> &BlobStore {
+        &self.blobs
+    }
+
+    /// Partial file, and metadata, of a session
+    fn session_paths(&self, session_id: &str) -> crate::Result<(PathBuf, PathBuf)> {
+        uuid::Uuid::parse_str(session_id)
+            .map_err(|_| AppError::UploadSessionNotFound(session_id.to_owned()))?;
+
+        Ok((
+            self.root.join(format!(".{}.part", session_id)),
+            self.root.join(format!(".{}.json", session_id)),
+        ))
+    }
+
+    /// Whether the directory exists, for health checks
+    pub async fn is_available(&self) -> bool {
+        fs::metadata(&self.root)
+            .await
+            .map(|metadata| metadata.is_dir())
+            .unwrap_or(false)
+    }
+
+    /// Start a new upload session. The file replaces any file of the same name on
+    /// commit.
+    pub async fn begin(
+        &self,
+        filename: &str,
+        file_type: &str,
+        size: Option<u64>,
+        sha256: &str,
+    ) -> crate::Result<FileWriter> {
+        validate_filename(filename)?;
+        let session = UploadSession {
+            id: uuid::Uuid::new_v4().to_string(),
+            filename: filename.to_owned(),
+            file_type: file_type.to_owned(),
+            size,
+            sha256: validate_sha256(sha256)?,
+        };
+
+        let (part, meta) = self.session_paths(&session.id)?;
+        let json = serde_json::to_vec(&session)
+            .map_err(|err| AppError::FileError(format!("{}: {}", meta.display(), err)))?;
+        fs::write(&meta, json)
+            .await
+            .map_err(|err| file_error(&meta, err))?;
+        let file = File::create(&part)
+            .await
+            .map_err(|err| file_error(&part, err))?;
+
+        self.lock(&session.id)?;
+        Ok(FileWriter {
+            store: self.clone(),
+            session,
+            file,
+            offset: 0,
+        })
+    }
+
+    /// Resume an upload session, from its uploaded offset
+    pub async fn resume(&self, session_id: &str) -> crate::Result<FileWriter> {
+        self.lock(session_id)?;
+        let opened = async {
+            let (session, offset) = self.session(session_id).await?;
+            let (part, _) = self.session_paths(session_id)?;
+            let file = OpenOptions::new()
+                .append(true)
+                .open(&part)
+                .await
+                .map_err(|err| file_error(&part, err))?;
+            Ok::<_, AppError>((session, file, offset))
+        };
+
+        match opened.await {
+            Ok((session, file, offset)) => Ok(FileWriter {
+                store: self.clone(),
+                session,
+                file,
+                offset,
+            }),
+            Err(err) => {
+                self.unlock(session_id);
+                Err(err)
+            }
+        }
+    }
+
+    /// An upload session, and its uploaded offset
+    pub async fn session(&self, session_id: &str) -> crate::Result<(UploadSession, u64)> {
+        let (part, meta) = self.session_paths(session_id)?;
+        let not_found = |_: std::io::Error| AppError::UploadSessionNotFound(session_id.to_owned());
+
+        let json = fs::read(&meta).await.map_err(not_found)?;
+        let session: UploadSession = serde_json::from_slice(&json)
+            .map_err(|err| AppError::FileError(format!("{}: {}", meta.display(), err)))?;
+        let offset = fs::metadata(&part).await.map_err(not_found)?.len();
+
+        Ok((session, offset))
+    }
+
+    /// One stream at a time per session
+    fn lock(&self, session_id: &str) -> crate::Result<()> {
+        let mut active = self
+            .active
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        match active.insert(session_id.to_owned()) {
+            true => Ok(()),
+            false => Err(AppError::InvalidArgument {
+                field: "session_id".to_owned(),
+                reason: format!("session {} is already being uploaded", session_id),
+            }),
+        }
+    }
+
+    fn unlock(&self, session_id: &str) {
+        self.active
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(session_id);
+    }
+
+    async fn remove_session(&self, session_id: &str) {
+        if let Ok((part, meta)) = self.session_paths(session_id) {
+            let _ = fs::remove_file(part).await;
+            let _ = fs::remove_file(meta).await;
+        }
+    }
+
+    /// Discard sessions untouched for longer than `ttl`. Returns how many.
+    pub async fn expire_sessions(&self, ttl: Duration) -> crate::Result<usize> {
+        let mut entries = fs::read_dir(&self.root)
+            .await
+            .map_err(|err| file_error(&self.root, err))?;
+        let mut expired = 0;
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|err| file_error(&self.root, err))?
+        {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let session_id = match name
+                .strip_prefix('.')
+                .and_then(|name| name.strip_suffix(".part"))
+            {
+                Some(session_id) => session_id.to_owned(),
+                None => continue,
+            };
+
+            let modified = entry.metadata().await.and_then(|meta| meta.modified());
+            let idle = modified
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .unwrap_or_default();
+            let is_active = self
+                .active
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .contains(&session_id);
+
+            if idle > ttl && !is_active {
+                self.remove_session(&session_id).await;
+                expired += 1;
+            }
+        }
+
+        Ok(expired)
+    }
+
+    /// Expire stale upload sessions, and collect unreferenced blobs, every hour
+    pub async fn watch_storage(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            match self.expire_sessions(UPLOAD_SESSION_TTL).await {
+                Ok(0) => {}
+                Ok(expired) => info!(
+                    message = "expired upload sessions".blue().to_string(),
+                    expired
+                ),
+                Err(err) => error!(error = format!("{:?}", err)),
+            }
+            match self.blobs.gc().await {
+                Ok(0) => {}
+                Ok(removed) => info!(
+                    message = "removed unreferenced blobs".blue().to_string(),
+                    removed
+                ),
+                Err(err) => error!(error = format!("{:?}", err)),
+            }
+        }
+    }
+
+    /// Open `length` bytes of `filename` from `offset`; up to the end if `length` is 0
+    pub async fn open_range(
+        &self,
+        filename: &str,
+        offset: u64,
+        length: u64,
+    ) -> crate::Result<(FileEntry, Take<File>)> {
+        let (entry, mut file) = self.blobs.open_file(filename).await?;
+        if offset > entry.size {
+            return Err(AppError::InvalidArgument {
+                field: "offset".to_owned(),
+                reason: format!("{} is past the end of {} bytes", offset, entry.size),
+            });
+        }
+
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|err| AppError::FileError(format!("{}: {}", filename, err)))?;
+        let length = match length {
+            0 => entry.size - offset,
+            length => length.min(entry.size - offset),
+        };
+
+        Ok((entry, file.take(length)))
+    }
+}
+
+impl FileWriter {
+    pub fn session(&self) -> &UploadSession {
+        &self.session
+    }
+
+    /// Bytes uploaded so far
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Whether the announced size is reached. Always, if the size is unknown.
+    pub fn is_complete(&self) -> bool {
+        self.session.size.map_or(true, |size| self.offset >= size)
+    }
+
+    /// Append unverified content
+    async fn write(&mut self, bytes: &[u8]) -> crate::Result<()> {
+        if let Some(size) = self.session.size {
+            if self.offset + bytes.len() as u64 > size {
+                return Err(AppError::InvalidArgument {
+                    field: "content".to_owned(),
+                    reason: format!("exceeds the announced size of {} bytes", size),
+                });
+            }
+        }
+
+        let (part, _) = self.store.session_paths(&self.session.id)?;
+        self.file
+            .write_all(bytes)
+            .await
+            .map_err(|err| file_error(&part, err))?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Append a chunk, if it starts at the uploaded offset and matches its checksum
+    pub async fn write_chunk(
+        &mut self,
+        offset: u64,
+        bytes: &[u8],
+        sha256: &str,
+    ) -> crate::Result<()> {
+        if offset != self.offset {
+            return Err(AppError::UploadOutOfOrder {
+                expected: self.offset,
+                found: offset,
+            });
+        }
+        if let Some(expected) = validate_sha256(sha256)? {
+            let found = sha256_hex(bytes);
+            if found != expected {
+                return Err(AppError::ChecksumMismatch { expected, found });
+            }
+        }
+
+        self.write(bytes).await
+    }
+
+    /// Expect the whole file to match `sha256`, declared once its size is known
+    pub async fn expect_sha256(&mut self, sha256: &str) -> crate::Result<()> {
+        let sha256 = validate_sha256(sha256)?.ok_or_else(|| AppError::InvalidArgument {
+            field: "sha256".to_owned(),
+            reason: "must not be empty".to_owned(),
+        })?;
+        if let Some(expected) = self.session.sha256.clone() {
+            if expected != sha256 {
+                return Err(AppError::ChecksumMismatch {
+                    expected,
+                    found: sha256,
+                });
+            }
+        }
+
+        self.session.sha256 = Some(sha256);
+        let (_, meta) = self.store.session_paths(&self.session.id)?;
+        let json = serde_json::to_vec(&self.session)
+            .map_err(|err| AppError::FileError(format!("{}: {}", meta.display(), err)))?;
+        fs::write(&meta, json)
+            .await
+            .map_err(|err| file_error(&meta, err))
+    }
+
+    /// Verify the whole file, flush it to disk, and move it into the blob store. A
+    /// file not matching its checksum is discarded; one of neither known size nor
+    /// checksum is refused, as a truncated upload would pass.
+    pub async fn commit(self) -> crate::Result<FileEntry> {
+        if self.session.size.is_none() && self.session.sha256.is_none() {
+            return Err(AppError::InvalidArgument {
+                field: "sha256".to_owned(),
+                reason: "required when the size is unknown".to_owned(),
+            });
+        }
+        let (part, meta) = self.store.session_paths(&self.session.id)?;
+        self.file
+            .sync_all()
+            .await
+            .map_err(|err| file_error(&part, err))?;
+
+        if !self.is_complete() {
+            return Err(AppError::InvalidArgument {
+                field: "size".to_owned(),
+                reason: format!(
+                    "{} of {} bytes uploaded",
+                    self.offset,
+                    self.session.size.unwrap_or_default()
+                ),
+            });
+        }
+
+        let (found, size) = digest_file(&part).await?;
+        if let Some(expected) = self.session.sha256.clone() {
+            if found != expected {
+                self.store.remove_session(&self.session.id).await;
+                return Err(AppError::ChecksumMismatch { expected, found });
+            }
+        }
+
+        let entry = self
+            .store
+            .blobs
+            .put(
+                &part,
+                &self.session.filename,
+                &self.session.file_type,
+                &found,
+                size,
+            )
+            .await?;
+        let _ = fs::remove_file(&meta).await;
+
+        Ok(entry)
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        self.store.unlock(&self.session.id);
+    }
+}
+
+#[test]
+fn test_validate_filename() {
+    for filename in ["report.pdf", "a b.txt", "archive.tar.gz"] {
+        assert!(validate_filename(filename).is_ok(), "{}", filename);
+    }
+    for filename in ["", " ", ".env", "../etc/passwd", "a/b", "a\\b"] {
+        assert!(validate_filename(filename).is_err(), "{:?}", filename);
+    }
+}
+
+#[test]
+fn test_resumable_upload() {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let root = std::env::temp_dir().join(format!("simply-files-{}", uuid::Uuid::new_v4()));
+            let store = FileStore::open(&root).await.unwrap();
+
+            let content = b"hello world";
+            let mut writer = store
+                .begin("kept.txt", "text/plain", Some(11), &sha256_hex(content))
+                .await
+                .unwrap();
+            let session_id = writer.session().id.clone();
+            writer
+                .write_chunk(0, b"hello ", &sha256_hex(b"hello "))
+                .await
+                .unwrap();
+            // corrupted, then out of order
+            assert!(matches!(
+                writer.write_chunk(6, b"world", &sha256_hex(b"w0rld")).await,
+                Err(AppError::ChecksumMismatch { .. })
+            ));
+            assert!(matches!(
+                writer.write_chunk(7, b"orld", "").await,
+                Err(AppError::UploadOutOfOrder {
+                    expected: 6,
+                    found: 7
+                })
+            ));
+            // interrupted
+            drop(writer);
+            assert!(matches!(
+                store.open_range("kept.txt", 0, 0).await,
+                Err(AppError::FileNotFound(_))
+            ));
+
+            let mut writer = store.resume(&session_id).await.unwrap();
+            assert_eq!(writer.offset(), 6);
+            assert!(store.resume(&session_id).await.is_err());
+            writer
+                .write_chunk(6, b"world", &sha256_hex(b"world"))
+                .await
+                .unwrap();
+            let entry = writer.commit().await.unwrap();
+            assert_eq!(entry.sha256, sha256_hex(content));
+            assert_eq!(entry.size, 11);
+            let read = |offset, length| {
+                let store = store.clone();
+                async move {
+                    let (_, mut file) = store.open_range("kept.txt", offset, length).await?;
+                    let mut stored = Vec::new();
+                    file.read_to_end(&mut stored).await.unwrap();
+                    Ok::<_, AppError>(stored)
+                }
+            };
+            assert_eq!(read(0, 0).await.unwrap(), content);
+            assert_eq!(read(6, 3).await.unwrap(), b"wor");
+            assert_eq!(read(6, 100).await.unwrap(), b"world");
+            assert_eq!(read(11, 0).await.unwrap(), b"");
+            assert!(read(12, 0).await.is_err());
+            assert!(store.session(&session_id).await.is_err());
+
+            // neither size nor checksum, then a checksum declared at the end
+            let mut writer = store.begin("late.txt", "", None, "").await.unwrap();
+            writer.write(b"content").await.unwrap();
+            let session_id = writer.session().id.clone();
+            assert!(matches!(
+                writer.commit().await,
+                Err(AppError::InvalidArgument { .. })
+            ));
+            let mut writer = store.resume(&session_id).await.unwrap();
+            writer.expect_sha256(&sha256_hex(b"content")).await.unwrap();
+            writer.commit().await.unwrap();
+
+            // a file not matching its checksum is discarded
+            let mut writer = store
+                .begin("bad.txt", "", None, &sha256_hex(b"other"))
+                .await
+                .unwrap();
+            writer.write(b"content").await.unwrap();
+            assert!(writer.expect_sha256(&sha256_hex(b"content")).await.is_err());
+            assert!(matches!(
+                writer.commit().await,
+                Err(AppError::ChecksumMismatch { .. })
+            ));
+
+            // a long filename, and files stored before the blob store, imported once
+            let long = "l".repeat(255);
+            let mut writer = store.begin(&long, "", Some(4), "").await.unwrap();
+            writer.write_chunk(0, b"long", "").await.unwrap();
+            assert_eq!(writer.commit().await.unwrap().filename, long);
+            fs::write(root.join("flat.txt"), b"flat").await.unwrap();
+            let store = FileStore::open(&root).await.unwrap();
+            assert!(store.open_range("flat.txt", 0, 0).await.is_err());
+            assert_eq!(store.import_files().await.unwrap(), 1);
+            assert_eq!(store.import_files().await.unwrap(), 0);
+            assert!(store.open_range("flat.txt", 0, 0).await.is_ok());
+
+            let mut entries = fs::read_dir(&root).await.unwrap();
+            let mut names = Vec::new();
+            while let Some(entry) = entries.next_entry().await.unwrap() {
+                names.push(entry.file_name().into_string().unwrap());
+            }
+            // no session left behind
+            names.sort();
+            assert_eq!(names, vec!["blobs".to_owned(), "names".to_owned()]);
+
+            fs::remove_dir_all(&root).await.unwrap();
+        });
+}
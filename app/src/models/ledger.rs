@@ -0,0 +1,977 @@
+//!
+//! Simulated bitcoin ledger: payments spend unspent outputs (UTXOs) of the payer,
+//! wait in a mempool, and are confirmed by blocks mined on a timer
+//!
+
+use crate::{
+    models::{
+        bitcoin::{parse_address, Network},
+        sha256_hex,
+        utxo::{select_coins, Utxo, MEMPOOL_HEIGHT, UTXO_TABLE},
+    },
+    replicas::now_millis,
+    AppError, Connection, InMemoryDatabase, Settings,
+};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+/// Table of payments, keyed by txid
+pub const PAYMENT_TABLE: &str = "payment";
+
+/// Table of outcomes of idempotent payments, keyed by idempotency key
+pub const PAYMENT_OUTCOME_TABLE: &str = "payment_outcome";
+
+/// Table of the chain tip, a single `tip` record
+pub const CHAIN_TABLE: &str = "chain";
+
+/// Page size of `list_payments`, when none is requested
+pub const DEFAULT_PAYMENT_PAGE_SIZE: usize = 50;
+pub const MAX_PAYMENT_PAGE_SIZE: usize = 1000;
+
+/// Fee rate in sat/vB, when "BITCOIN_FEE_RATE" is not set
+pub const DEFAULT_FEE_RATE: u64 = 1;
+
+/// Interval of mined blocks, when "BITCOIN_BLOCK_INTERVAL_SECS" is not set
+pub const DEFAULT_BLOCK_INTERVAL: Duration = Duration::from_secs(10);
+
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 128;
+
+/// Balances of an address
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Account {
+    pub address: String,
+    /// satoshis of confirmed, unspent outputs
+    pub balance: i64,
+    /// satoshis of unspent outputs in the mempool
+    pub unconfirmed_balance: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Payment {
+    /// txid, stored as `payment_id` next to the record id
+    #[serde(rename = "payment_id")]
+    pub id: String,
+    pub from_address: String,
+    pub to_address: String,
+    /// satoshis, in output 0
+    pub amount: i64,
+    #[serde(default)]
+    pub fee: i64,
+    /// satoshis back to the payer, in output 1 if any
+    #[serde(default)]
+    pub change: i64,
+    /// outpoints spent
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    /// block of the payment, or MEMPOOL_HEIGHT
+    #[serde(default)]
+    pub height: i64,
+    pub created_at_ms: i64,
+}
+
+/// Outcome of a payment request; replayed as is on a retry with the same
+/// idempotency key
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct PaymentOutcome {
+    pub idempotency_key: String,
+    pub from_address: String,
+    pub to_address: String,
+    pub amount: i64,
+    pub successful: bool,
+    /// why the payment was rejected, or the payment id
+    pub message: String,
+    /// empty unless successful
+    pub payment_id: String,
+    #[serde(default)]
+    pub fee: i64,
+    pub created_at_ms: i64,
+}
+
+impl PaymentOutcome {
+    fn is_retry_of(&self, from: &str, to: &str, amount: i64) -> bool {
+        self.from_address == from && self.to_address == to && self.amount == amount
+    }
+}
+
+/// Filters of `list_payments`; a payment is listed if it matches all of them
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PaymentFilter {
+    /// payments from or to this address
+    pub address: Option<String>,
+    /// made at or after, in milliseconds since the unix epoch
+    pub after_ms: Option<i64>,
+    /// made before, in milliseconds since the unix epoch
+    pub before_ms: Option<i64>,
+}
+
+/// Position after the last payment of a page, newest first: stable while payments
+/// are added, unlike an offset. Its page token is `<created_at_ms>:<payment_id>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentCursor {
+    pub created_at_ms: i64,
+    pub payment_id: String,
+}
+
+impl From<&Payment> for PaymentCursor {
+    fn from(payment: &Payment) -> Self {
+        Self {
+            created_at_ms: payment.created_at_ms,
+            payment_id: payment.id.clone(),
+        }
+    }
+}
+
+impl FromStr for PaymentCursor {
+    type Err = AppError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        match token.split_once(':') {
+            Some((created_at_ms, payment_id)) if !payment_id.is_empty() => Ok(Self {
+                created_at_ms: created_at_ms
+                    .parse()
+                    .map_err(|_| invalid("page_token", "invalid"))?,
+                payment_id: payment_id.to_owned(),
+            }),
+            _ => Err(invalid("page_token", "invalid")),
+        }
+    }
+}
+
+impl std::fmt::Display for PaymentCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.created_at_ms, self.payment_id)
+    }
+}
+
+/// A page of payments, and the cursor of the next page if any
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentPage {
+    pub items: Vec<Payment>,
+    pub next: Option<PaymentCursor>,
+}
+
+impl PaymentFilter {
+    /// Conditions of the filter, and of payments after a cursor, if `after_cursor`
+    fn condition(&self, after_cursor: bool) -> String {
+        let mut conditions = Vec::new();
+        if self.address.is_some() {
+            conditions.push("(from_address = $address OR to_address = $address)");
+        }
+        if self.after_ms.is_some() {
+            conditions.push("created_at_ms >= $after");
+        }
+        if self.before_ms.is_some() {
+            conditions.push("created_at_ms < $before");
+        }
+        // in the order of ORDER BY created_at_ms DESC, payment_id
+        if after_cursor {
+            conditions.push(
+                "(created_at_ms < $cursor_at OR \
+                 (created_at_ms = $cursor_at AND payment_id > $cursor_id))",
+            );
+        }
+
+        match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" WHERE {}", conditions.join(" AND ")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct ChainTip {
+    height: i64,
+}
+
+fn invalid(field: &str, reason: impl ToString) -> AppError {
+    AppError::InvalidArgument {
+        field: field.to_owned(),
+        reason: reason.to_string(),
+    }
+}
+
+fn validate_idempotency_key(key: &str) -> crate::Result<()> {
+    if key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err(invalid("idempotency_key", "too long"));
+    }
+    match key.chars().all(|c| c.is_ascii_graphic()) {
+        true => Ok(()),
+        false => Err(invalid("idempotency_key", "not printable ascii")),
+    }
+}
+
+/// A new, random txid
+fn new_txid() -> String {
+    sha256_hex(uuid::Uuid::new_v4().as_bytes())
+}
+
+/// Ledger of unspent outputs. Payments and blocks are serialized, so that the
+/// outputs selected are the outputs spent; each commits in a single transaction.
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    /// of the accepted addresses
+    network: Network,
+    /// sat/vB
+    fee_rate: u64,
+    block_interval: Duration,
+    transfers: Arc<Mutex<()>>,
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Self::new(Network::default())
+    }
+}
+
+impl Ledger {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            fee_rate: DEFAULT_FEE_RATE,
+            block_interval: DEFAULT_BLOCK_INTERVAL,
+            transfers: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn with_fee_rate(self, fee_rate: u64) -> Self {
+        Self { fee_rate, ..self }
+    }
+
+    pub fn with_block_interval(self, block_interval: Duration) -> Self {
+        Self {
+            block_interval,
+            ..self
+        }
+    }
+
+    /// Ledger of addresses of "BITCOIN_NETWORK", paying "BITCOIN_FEE_RATE" sat/vB,
+    /// with a block mined every "BITCOIN_BLOCK_INTERVAL_SECS"
+    pub async fn from_settings() -> crate::Result<Self> {
+        let ledger = Self::new(Network::from_settings().await?);
+
+        let fee_rate = match Settings::get_config_item("BITCOIN_FEE_RATE").await {
+            Some(rate) => rate
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| AppError::InvalidSetting {
+                    key: "BITCOIN_FEE_RATE".to_owned(),
+                    value: rate,
+                })?,
+            None => DEFAULT_FEE_RATE,
+        };
+        let block_interval = match Settings::get_config_item("BITCOIN_BLOCK_INTERVAL_SECS").await {
+            Some(secs) => match secs.trim().parse::<u64>() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => {
+                    return Err(AppError::InvalidSetting {
+                        key: "BITCOIN_BLOCK_INTERVAL_SECS".to_owned(),
+                        value: secs,
+                    })
+                }
+            },
+            None => DEFAULT_BLOCK_INTERVAL,
+        };
+
+        Ok(ledger
+            .with_fee_rate(fee_rate)
+            .with_block_interval(block_interval))
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn fee_rate(&self) -> u64 {
+        self.fee_rate
+    }
+
+    /// Canonical form of `address`, lowercase if bech32, so that an address is
+    /// stored and compared one way; rejected unless it is a valid bitcoin address
+    /// of the ledger network
+    pub fn check_address(&self, field: &str, address: &str) -> crate::Result<String> {
+        match parse_address(address, self.network) {
+            Ok(kind) if kind.is_segwit() => Ok(address.to_lowercase()),
+            Ok(_) => Ok(address.to_owned()),
+            Err(reason) => Err(invalid(field, format!("`{}`: {}", address, reason))),
+        }
+    }
+
+    /// Height of the last mined block; 0 before the first
+    pub async fn tip<C>(&self, conn: &C) -> crate::Result<i64>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let record: surrealdb::Result<Option<ChainTip>> =
+            conn.get_db().db.select((CHAIN_TABLE, "tip")).await;
+
+        match record {
+            Ok(tip) => Ok(tip.unwrap_or_default().height),
+            Err(err) => Err(AppError::SurrealdbGetError(err)),
+        }
+    }
+
+    /// Outputs paying `address`, unspent unless `include_spent`
+    pub async fn utxos<C>(
+        &self,
+        conn: &C,
+        address: &str,
+        include_spent: bool,
+    ) -> crate::Result<Vec<Utxo>>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let sql = format!(
+            "SELECT * FROM type::table($table) WHERE address = $address{} ORDER BY outpoint",
+            match include_spent {
+                true => "",
+                false => " AND spent_by = ''",
+            }
+        );
+        let records: surrealdb::Result<Vec<Utxo>> = match conn
+            .get_db()
+            .db
+            .query(sql)
+            .bind(("table", UTXO_TABLE))
+            .bind(("address", address))
+            .await
+        {
+            Ok(mut response) => response.take(0),
+            Err(err) => Err(err),
+        };
+
+        records.map_err(AppError::SurrealdbGetError)
+    }
+
+    /// Balances of `address`, zero if it was never paid
+    pub async fn account<C>(&self, conn: &C, address: &str) -> crate::Result<Account>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let mut account = Account {
+            address: address.to_owned(),
+            ..Default::default()
+        };
+        for utxo in self.utxos(conn, address, false).await? {
+            match utxo.is_confirmed() {
+                true => account.balance += utxo.amount,
+                false => account.unconfirmed_balance += utxo.amount,
+            }
+        }
+
+        Ok(account)
+    }
+
+    /// Set the balance of `address`: its unspent outputs are replaced by a single
+    /// output of `balance`, confirmed at the tip
+    pub async fn seed<C>(&self, conn: &C, address: &str, balance: u64) -> crate::Result<Account>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let address = &self.check_address("address", address)?;
+        let balance = i64::try_from(balance).map_err(|_| invalid("balance", "too large"))?;
+
+        // not during a payment, which could spend the replaced outputs
+        let _transfer = self.transfers.lock().await;
+        let txid = new_txid();
+        let output = Utxo::new(&txid, 0, address, balance, self.tip(conn).await?);
+        let create_output = match balance {
+            0 => "",
+            _ => "CREATE type::thing($table, $outpoint) CONTENT $output;\n",
+        };
+
+        conn.get_db()
+            .db
+            .query(format!(
+                "BEGIN TRANSACTION;\n\
+                 UPDATE type::table($table) SET spent_by = $txid \
+                 WHERE address = $address AND spent_by = '';\n\
+                 {}COMMIT TRANSACTION;",
+                create_output
+            ))
+            .bind(("table", UTXO_TABLE))
+            .bind(("txid", &txid))
+            .bind(("address", address))
+            .bind(("outpoint", &output.outpoint))
+            .bind(("output", &output))
+            .await
+            .and_then(|response| response.check())
+            .map_err(AppError::SurrealdbSetError)?;
+
+        self.account(conn, address).await
+    }
+
+    /// Pay `amount` satoshis from one address to another, spending confirmed outputs
+    /// of the payer, plus the fee; the payment waits in the mempool for a block.
+    /// With an `idempotency_key`, the outcome is recorded too: a retry returns it,
+    /// instead of paying again. Insufficient funds are an outcome, not an error.
+    pub async fn send<C>(
+        &self,
+        conn: &C,
+        from: &str,
+        to: &str,
+        amount: u64,
+        idempotency_key: &str,
+    ) -> crate::Result<PaymentOutcome>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let from = &self.check_address("from_add", from)?;
+        let to = &self.check_address("to_add", to)?;
+        validate_idempotency_key(idempotency_key)?;
+        if from == to {
+            return Err(invalid("to_add", "same as from_add"));
+        }
+        if amount == 0 {
+            return Err(invalid("amount", "must be positive"));
+        }
+        let signed_amount = i64::try_from(amount).map_err(|_| invalid("amount", "too large"))?;
+
+        let _transfer = self.transfers.lock().await;
+        if !idempotency_key.is_empty() {
+            if let Some(outcome) = self.outcome(conn, idempotency_key).await? {
+                return match outcome.is_retry_of(from, to, signed_amount) {
+                    true => Ok(outcome),
+                    false => Err(invalid(
+                        "idempotency_key",
+                        "already used by a different payment",
+                    )),
+                };
+            }
+        }
+
+        let mut outcome = PaymentOutcome {
+            idempotency_key: idempotency_key.to_owned(),
+            from_address: from.to_owned(),
+            to_address: to.to_owned(),
+            amount: signed_amount,
+            created_at_ms: now_millis(),
+            ..Default::default()
+        };
+
+        let utxos = self.utxos(conn, from, false).await?;
+        let selection = match select_coins(utxos.clone(), amount, self.fee_rate) {
+            Ok(selection) => selection,
+            Err(missing) => {
+                let balance: i64 = utxos
+                    .iter()
+                    .filter(|utxo| utxo.is_confirmed())
+                    .map(|utxo| utxo.amount)
+                    .sum();
+                outcome.message = AppError::InsufficientFunds {
+                    address: from.to_owned(),
+                    balance,
+                    amount: balance + missing as i64,
+                }
+                .to_string();
+                self.record_outcome(conn, &outcome).await?;
+                return Ok(outcome);
+            }
+        };
+
+        let txid = new_txid();
+        let payment = Payment {
+            id: txid.clone(),
+            from_address: from.to_owned(),
+            to_address: to.to_owned(),
+            amount: signed_amount,
+            fee: selection.fee as i64,
+            change: selection.change as i64,
+            inputs: selection
+                .inputs
+                .iter()
+                .map(|utxo| utxo.outpoint.clone())
+                .collect(),
+            height: MEMPOOL_HEIGHT,
+            created_at_ms: outcome.created_at_ms,
+        };
+        let mut outputs = vec![Utxo::new(&txid, 0, to, signed_amount, MEMPOOL_HEIGHT)];
+        if selection.change > 0 {
+            outputs.push(Utxo::new(
+                &txid,
+                1,
+                from,
+                selection.change as i64,
+                MEMPOOL_HEIGHT,
+            ));
+        }
+        outcome.successful = true;
+        outcome.message = format!("payment {} sent to the mempool", txid);
+        outcome.payment_id = txid.clone();
+        outcome.fee = payment.fee;
+
+        // the outcome commits with the payment, so that a retry never pays twice
+        let create_outputs: String = (0..outputs.len())
+            .map(|vout| {
+                format!(
+                    "CREATE type::thing($utxo, $outputs[{0}].outpoint) CONTENT $outputs[{0}];\n",
+                    vout
+                )
+            })
+            .collect();
+        let record_outcome = match idempotency_key.is_empty() {
+            true => "",
+            false => "CREATE type::thing($outcome_table, $key) CONTENT $outcome;\n",
+        };
+        conn.get_db()
+            .db
+            .query(format!(
+                "BEGIN TRANSACTION;\n\
+                 UPDATE type::table($utxo) SET spent_by = $txid \
+                 WHERE outpoint INSIDE $inputs AND spent_by = '';\n\
+                 {}CREATE type::thing($payment, $txid) CONTENT $record;\n\
+                 {}COMMIT TRANSACTION;",
+                create_outputs, record_outcome
+            ))
+            .bind(("utxo", UTXO_TABLE))
+            .bind(("payment", PAYMENT_TABLE))
+            .bind(("outcome_table", PAYMENT_OUTCOME_TABLE))
+            .bind(("txid", &txid))
+            .bind(("inputs", &payment.inputs))
+            .bind(("outputs", &outputs))
+            .bind(("record", &payment))
+            .bind(("key", idempotency_key))
+            .bind(("outcome", &outcome))
+            .await
+            .and_then(|response| response.check())
+            .map_err(AppError::SurrealdbSetError)?;
+
+        Ok(outcome)
+    }
+
+    async fn outcome<C>(
+        &self,
+        conn: &C,
+        idempotency_key: &str,
+    ) -> crate::Result<Option<PaymentOutcome>>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        conn.get_db()
+            .db
+            .select((PAYMENT_OUTCOME_TABLE, idempotency_key))
+            .await
+            .map_err(AppError::SurrealdbGetError)
+    }
+
+    /// Record a rejected payment, if it has an idempotency key
+    async fn record_outcome<C>(&self, conn: &C, outcome: &PaymentOutcome) -> crate::Result<()>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        if outcome.idempotency_key.is_empty() {
+            return Ok(());
+        }
+
+        let record: surrealdb::Result<Option<PaymentOutcome>> = conn
+            .get_db()
+            .db
+            .create((PAYMENT_OUTCOME_TABLE, outcome.idempotency_key.as_str()))
+            .content(outcome)
+            .await;
+
+        match record {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AppError::SurrealdbSetError(err)),
+        }
+    }
+
+    /// Payment of `txid`
+    pub async fn payment<C>(&self, conn: &C, txid: &str) -> crate::Result<Payment>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let record: surrealdb::Result<Option<Payment>> =
+            conn.get_db().db.select((PAYMENT_TABLE, txid)).await;
+
+        match record {
+            Ok(Some(payment)) => Ok(payment),
+            Ok(None) => Err(AppError::PaymentNotFound(txid.to_owned())),
+            Err(err) => Err(AppError::SurrealdbGetError(err)),
+        }
+    }
+
+    /// Payments matching `filter`, newest first, after `cursor` if any
+    pub async fn list_payments<C>(
+        &self,
+        conn: &C,
+        filter: &PaymentFilter,
+        cursor: Option<&PaymentCursor>,
+        limit: usize,
+    ) -> crate::Result<PaymentPage>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let address = match &filter.address {
+            Some(address) => Some(self.check_address("address", address)?),
+            None => None,
+        };
+        let limit = limit.clamp(1, MAX_PAYMENT_PAGE_SIZE);
+
+        // one extra row tells whether there is a next page
+        let sql = format!(
+            "SELECT * FROM type::table($table){} ORDER BY created_at_ms DESC, payment_id \
+             LIMIT {}",
+            filter.condition(cursor.is_some()),
+            limit + 1
+        );
+        let db = conn.get_db().db;
+        let mut query = db.query(sql).bind(("table", PAYMENT_TABLE));
+        if let Some(address) = &address {
+            query = query.bind(("address", address));
+        }
+        if let Some(after) = filter.after_ms {
+            query = query.bind(("after", after));
+        }
+        if let Some(before) = filter.before_ms {
+            query = query.bind(("before", before));
+        }
+        if let Some(cursor) = cursor {
+            query = query
+                .bind(("cursor_at", cursor.created_at_ms))
+                .bind(("cursor_id", &cursor.payment_id));
+        }
+
+        let records: surrealdb::Result<Vec<Payment>> = match query.await {
+            Ok(mut response) => response.take(0),
+            Err(err) => Err(err),
+        };
+
+        match records {
+            Ok(mut items) => {
+                let next = match items.len() > limit {
+                    true => {
+                        items.truncate(limit);
+                        items.last().map(PaymentCursor::from)
+                    }
+                    false => None,
+                };
+                Ok(PaymentPage { items, next })
+            }
+            Err(err) => Err(AppError::SurrealdbGetError(err)),
+        }
+    }
+
+    /// Mine a block, confirming every payment of the mempool, and their outputs.
+    /// Returns the height of the block, and its payments.
+    pub async fn mine_block<C>(&self, conn: &C) -> crate::Result<(i64, Vec<String>)>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let _transfer = self.transfers.lock().await;
+        let height = self.tip(conn).await? + 1;
+
+        let mempool: surrealdb::Result<Vec<Payment>> = match conn
+            .get_db()
+            .db
+            .query("SELECT * FROM type::table($table) WHERE height = $mempool")
+            .bind(("table", PAYMENT_TABLE))
+            .bind(("mempool", MEMPOOL_HEIGHT))
+            .await
+        {
+            Ok(mut response) => response.take(0),
+            Err(err) => Err(err),
+        };
+        let txids: Vec<String> = mempool
+            .map_err(AppError::SurrealdbGetError)?
+            .into_iter()
+            .map(|payment| payment.id)
+            .collect();
+
+        conn.get_db()
+            .db
+            .query(
+                "BEGIN TRANSACTION;\n\
+                 UPDATE type::thing($chain, 'tip') SET height = $height;\n\
+                 UPDATE type::table($payment) SET height = $height \
+                 WHERE payment_id INSIDE $txids;\n\
+                 UPDATE type::table($utxo) SET height = $height WHERE txid INSIDE $txids;\n\
+                 COMMIT TRANSACTION;",
+            )
+            .bind(("chain", CHAIN_TABLE))
+            .bind(("payment", PAYMENT_TABLE))
+            .bind(("utxo", UTXO_TABLE))
+            .bind(("height", height))
+            .bind(("txids", &txids))
+            .await
+            .and_then(|response| response.check())
+            .map_err(AppError::SurrealdbSetError)?;
+
+        Ok((height, txids))
+    }
+
+    /// Mine a block every block interval, for ever
+    pub async fn watch_blocks<C>(self, connection: C)
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let mut interval = tokio::time::interval(self.block_interval);
+        // the first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            match self.mine_block(&connection).await {
+                Ok((height, txids)) if !txids.is_empty() => info!(
+                    message = "Mined block".blue().to_string(),
+                    height,
+                    payments = txids.len()
+                ),
+                Ok(_) => {}
+                Err(err) => error!(error = format!("{:?}", err)),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_check_address() {
+    let ledger = Ledger::new(Network::Regtest);
+    let address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+    assert_eq!(ledger.check_address("from_add", address).unwrap(), address);
+    // bech32 in uppercase is the same address
+    assert_eq!(
+        ledger
+            .check_address("from_add", &address.to_uppercase())
+            .unwrap(),
+        address
+    );
+    assert!(ledger
+        .check_address("from_add", "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7KYGT080")
+        .is_err());
+    // base58 is case sensitive
+    let address = "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r";
+    assert_eq!(ledger.check_address("to_add", address).unwrap(), address);
+    assert!(ledger.check_address("from_add", "").is_err());
+    assert!(matches!(
+        ledger.check_address("to_add", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+        Err(AppError::InvalidArgument { field, .. }) if field == "to_add"
+    ));
+}
+
+#[test]
+fn test_payment_filter() {
+    assert_eq!(PaymentFilter::default().condition(false), "");
+    let filter = PaymentFilter {
+        address: Some("alice".to_owned()),
+        before_ms: Some(1_700_000_000_000),
+        ..Default::default()
+    };
+    assert_eq!(
+        filter.condition(false),
+        " WHERE (from_address = $address OR to_address = $address) AND created_at_ms < $before"
+    );
+    assert!(PaymentFilter::default()
+        .condition(true)
+        .starts_with(" WHERE (created_at_ms < $cursor_at OR"));
+
+    let cursor: PaymentCursor = "1700000000000:ab12".parse().unwrap();
+    assert_eq!(cursor.created_at_ms, 1_700_000_000_000);
+    assert_eq!(cursor.to_string(), "1700000000000:ab12");
+    for token in ["", "1700000000000", "x:ab12", "1700000000000:"] {
+        assert!(token.parse::<PaymentCursor>().is_err(), "{:?}", token);
+    }
+
+    assert!(validate_idempotency_key("retry-7f3a").is_ok());
+    assert!(validate_idempotency_key("with space").is_err());
+}
+
+#[tokio::test]
+async fn test_send_and_seed() {
+    let conn = <InMemoryDatabase as Connection>::new().await.unwrap();
+    let ledger = Ledger::new(Network::Regtest);
+    let alice = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+    let bob = "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r";
+
+    assert_eq!(
+        ledger.seed(&conn, alice, 10_000).await.unwrap().balance,
+        10_000
+    );
+    // seeding again replaces the balance
+    assert_eq!(
+        ledger.seed(&conn, alice, 5_000).await.unwrap().balance,
+        5_000
+    );
+    assert_eq!(ledger.utxos(&conn, alice, false).await.unwrap().len(), 1);
+
+    // the payer is debited, and the payee credited, in the same transaction
+    let outcome = ledger.send(&conn, alice, bob, 1_200, "").await.unwrap();
+    assert!(outcome.successful, "{}", outcome.message);
+    let payment = ledger.payment(&conn, &outcome.payment_id).await.unwrap();
+    assert_eq!(payment.amount + payment.fee + payment.change, 5_000);
+    let from = ledger.account(&conn, alice).await.unwrap();
+    let to = ledger.account(&conn, bob).await.unwrap();
+    assert_eq!(
+        (from.balance, from.unconfirmed_balance),
+        (0, payment.change)
+    );
+    assert_eq!((to.balance, to.unconfirmed_balance), (0, 1_200));
+
+    // unconfirmed change cannot be spent yet: insufficient funds, nothing moved
+    let outcome = ledger.send(&conn, alice, bob, 100, "").await.unwrap();
+    assert!(!outcome.successful);
+    assert!(outcome.payment_id.is_empty());
+    assert_eq!(ledger.account(&conn, alice).await.unwrap(), from);
+    assert_eq!(ledger.account(&conn, bob).await.unwrap(), to);
+
+    assert!(ledger.send(&conn, alice, alice, 100, "").await.is_err());
+    assert!(ledger
+        .send(&conn, alice, &alice.to_uppercase(), 100, "")
+        .await
+        .is_err());
+    assert!(ledger.send(&conn, alice, bob, 0, "").await.is_err());
+
+    // stored in the canonical form, whatever the case of the request
+    let seeded = ledger
+        .seed(&conn, &alice.to_uppercase(), 1_000)
+        .await
+        .unwrap();
+    assert_eq!(seeded.address, alice);
+    assert_eq!(ledger.account(&conn, alice).await.unwrap().balance, 1_000);
+}
+
+#[tokio::test]
+async fn test_confirmations() {
+    use crate::models::utxo::confirmations;
+
+    let conn = <InMemoryDatabase as Connection>::new().await.unwrap();
+    let ledger = Ledger::new(Network::Regtest);
+    let alice = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+    let bob = "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r";
+
+    ledger.seed(&conn, alice, 10_000).await.unwrap();
+    let txid = ledger
+        .send(&conn, alice, bob, 1_000, "")
+        .await
+        .unwrap()
+        .payment_id;
+
+    // in the mempool: unconfirmed
+    let payment = ledger.payment(&conn, &txid).await.unwrap();
+    assert_eq!(payment.height, MEMPOOL_HEIGHT);
+    assert_eq!(
+        confirmations(payment.height, ledger.tip(&conn).await.unwrap()),
+        0
+    );
+    let account = ledger.account(&conn, bob).await.unwrap();
+    assert_eq!((account.balance, account.unconfirmed_balance), (0, 1_000));
+
+    // mined, then buried by a second block
+    assert_eq!(
+        ledger.mine_block(&conn).await.unwrap(),
+        (1, vec![txid.clone()])
+    );
+    let payment = ledger.payment(&conn, &txid).await.unwrap();
+    assert_eq!(payment.height, 1);
+    assert_eq!(confirmations(payment.height, 1), 1);
+    let account = ledger.account(&conn, bob).await.unwrap();
+    assert_eq!((account.balance, account.unconfirmed_balance), (1_000, 0));
+    let change = ledger.account(&conn, alice).await.unwrap();
+    assert_eq!(change.balance, payment.change);
+    assert_eq!(change.unconfirmed_balance, 0);
+
+    assert_eq!(ledger.mine_block(&conn).await.unwrap(), (2, Vec::new()));
+    let utxos = ledger.utxos(&conn, bob, false).await.unwrap();
+    assert_eq!(utxos.len(), 1);
+    assert_eq!(utxos[0].confirmations(ledger.tip(&conn).await.unwrap()), 2);
+}
+
+#[tokio::test]
+async fn test_idempotent_retry() {
+    let conn = <InMemoryDatabase as Connection>::new().await.unwrap();
+    let ledger = Ledger::new(Network::Regtest);
+    let alice = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+    let bob = "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r";
+
+    // rejected, then retried once funded: the rejection is replayed
+    let rejected = ledger
+        .send(&conn, alice, bob, 1_000, "order-0")
+        .await
+        .unwrap();
+    assert!(!rejected.successful);
+    ledger.seed(&conn, alice, 10_000).await.unwrap();
+    assert_eq!(
+        ledger
+            .send(&conn, alice, bob, 1_000, "order-0")
+            .await
+            .unwrap(),
+        rejected
+    );
+
+    let sent = ledger
+        .send(&conn, alice, bob, 1_000, "order-1")
+        .await
+        .unwrap();
+    assert!(sent.successful, "{}", sent.message);
+    let account = ledger.account(&conn, alice).await.unwrap();
+    // paid once: same outcome, no second debit
+    assert_eq!(
+        ledger
+            .send(&conn, alice, bob, 1_000, "order-1")
+            .await
+            .unwrap(),
+        sent
+    );
+    assert_eq!(ledger.account(&conn, alice).await.unwrap(), account);
+    assert_eq!(
+        ledger
+            .account(&conn, bob)
+            .await
+            .unwrap()
+            .unconfirmed_balance,
+        1_000
+    );
+    let page = ledger
+        .list_payments(&conn, &PaymentFilter::default(), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(page.items.len(), 1);
+
+    assert!(matches!(
+        ledger.send(&conn, alice, bob, 2_000, "order-1").await,
+        Err(AppError::InvalidArgument { field, .. }) if field == "idempotency_key"
+    ));
+}
+
+#[tokio::test]
+async fn test_list_payments_by_cursor() {
+    let conn = <InMemoryDatabase as Connection>::new().await.unwrap();
+    let ledger = Ledger::new(Network::Regtest);
+    let alice = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+    let bob = "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r";
+
+    ledger.seed(&conn, alice, 100_000).await.unwrap();
+    let mut sent = Vec::new();
+    async fn send(ledger: &Ledger, conn: &InMemoryDatabase, from: &str, to: &str) -> String {
+        // one payment per millisecond, for a predictable order
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let outcome = ledger.send(conn, from, to, 1_000, "").await.unwrap();
+        // the change is spendable once mined
+        ledger.mine_block(conn).await.unwrap();
+        outcome.payment_id
+    }
+    for _ in 0..3 {
+        sent.push(send(&ledger, &conn, alice, bob).await);
+    }
+
+    let mut listed = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = ledger
+            .list_payments(&conn, &PaymentFilter::default(), cursor.as_ref(), 2)
+            .await
+            .unwrap();
+        listed.extend(page.items.into_iter().map(|payment| payment.id));
+        // a payment made meanwhile neither shifts nor repeats the next page
+        send(&ledger, &conn, alice, bob).await;
+        match page.next {
+            Some(next) => cursor = Some(next.to_string().parse().unwrap()),
+            None => break,
+        }
+    }
+
+    sent.reverse();
+    assert_eq!(listed, sent);
+}
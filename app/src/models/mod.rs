@@ -9,3 +9,41 @@ pub use repository::*;
 #[cfg(feature = "default")]
 pub mod model;
 pub use model::*;
+
+#[cfg(feature = "default")]
+pub mod eviction;
+pub use eviction::{EvictionPolicy, MemoryStats};
+
+#[cfg(feature = "default")]
+pub mod compression;
+pub use compression::{Codec, CompressionStats};
+
+#[cfg(feature = "default")]
+pub mod encryption;
+pub use encryption::Keyring;
+
+#[cfg(feature = "default")]
+pub mod person;
+pub use person::{Person, PersonFilter, PersonStore, DEFAULT_PAGE_SIZE};
+
+#[cfg(feature = "default")]
+pub mod blobs;
+pub use blobs::{BlobStat, BlobStore, FileEntry};
+
+#[cfg(feature = "default")]
+pub mod files;
+pub use files::{sha256_hex, FileStore, FileWriter, UploadSession};
+
+#[cfg(feature = "default")]
+pub mod bitcoin;
+pub use bitcoin::{AddressKind, Network};
+
+#[cfg(feature = "default")]
+pub mod utxo;
+pub use utxo::Utxo;
+
+#[cfg(feature = "default")]
+pub mod ledger;
+pub use ledger::{
+    Account, Ledger, Payment, PaymentCursor, PaymentFilter, PaymentOutcome, PaymentPage,
+};
@@ -5,4 +5,30 @@ use std::borrow::Cow;
 pub struct KeyValue<'a> {
     pub key: Cow<'a, str>,
     pub value: Cow<'a, str>,
+    /// nonce of the encrypted value, base64 encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// id of the data key `value` is encrypted with, `None` if stored in clear
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    /// expiry, in milliseconds since unix epoch, `None` if the key does not expire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at_ms: Option<i64>,
+}
+
+impl<'a> KeyValue<'a> {
+    pub fn new(key: impl Into<Cow<'a, str>>, value: impl Into<Cow<'a, str>>) -> Self {
+        Self {
+            key: key.into(),
+            value: value.into(),
+            nonce: None,
+            key_id: None,
+            expires_at_ms: None,
+        }
+    }
+
+    /// The key has a time to live, which elapsed at `now_ms`
+    pub fn is_expired(&self, now_ms: i64) -> bool {
+        matches!(self.expires_at_ms, Some(expires_at_ms) if expires_at_ms <= now_ms)
+    }
 }
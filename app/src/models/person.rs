@@ -0,0 +1,609 @@
+use crate::{models::PersonRepository, AppError, Connection, InMemoryDatabase};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Table of person records
+pub const PERSON_TABLE: &str = "person";
+
+/// Page size of `list_persons`, when none is requested
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LENGTH: usize = 32;
+/// Earliest accepted birth year
+const MIN_BIRTH_YEAR: i64 = 1850;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Person {
+    /// stored as `person_id`, next to the record id
+    #[serde(rename = "person_id")]
+    pub id: String,
+    pub name: String,
+    pub email: String,
+    /// calendar date, formatted as YYYY-MM-DD
+    pub birth_date: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Filters of `list_persons`; a person is listed if it matches all of them
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PersonFilter {
+    /// case-insensitive substring of the name
+    pub name: Option<String>,
+    pub email: Option<String>,
+    /// persons having all of these tags
+    pub tags: Vec<String>,
+    /// born on or after, YYYY-MM-DD
+    pub born_after: Option<String>,
+    /// born on or before, YYYY-MM-DD
+    pub born_before: Option<String>,
+}
+
+/// A page of results, and the offset of the next page if any
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_offset: Option<usize>,
+}
+
+fn invalid(field: &str, reason: impl ToString) -> AppError {
+    AppError::InvalidArgument {
+        field: field.to_owned(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parse a YYYY-MM-DD date into days since 1970-01-01
+pub fn parse_date(date: &str) -> Option<i64> {
+    let bytes = date.as_bytes();
+    if bytes.len() != 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        match bytes[range.clone()].iter().all(u8::is_ascii_digit) {
+            true => date[range].parse().ok(),
+            false => None,
+        }
+    };
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+
+    match (1..=days_in_month).contains(&day) {
+        true => Some(days_from_civil(year, month, day)),
+        false => None,
+    }
+}
+
+fn validate_date(field: &str, date: &str) -> crate::Result<()> {
+    match parse_date(date) {
+        Some(_) => Ok(()),
+        None => Err(invalid(
+            field,
+            format!("`{}` is not a YYYY-MM-DD date", date),
+        )),
+    }
+}
+
+fn validate_email(email: &str) -> crate::Result<()> {
+    let (local, domain) = email
+        .rsplit_once('@')
+        .ok_or_else(|| invalid("email", "missing @"))?;
+
+    if email.len() > MAX_EMAIL_LENGTH {
+        return Err(invalid("email", "too long"));
+    }
+    if local.is_empty() || local.chars().any(|c| c.is_whitespace() || c == '@') {
+        return Err(invalid(
+            "email",
+            format!("malformed local part `{}`", local),
+        ));
+    }
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let valid_label = |label: &&str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if labels.len() < 2 || !labels.iter().all(valid_label) {
+        return Err(invalid("email", format!("malformed domain `{}`", domain)));
+    }
+
+    Ok(())
+}
+
+impl Person {
+    /// Normalize, then validate the person: trims the name, lower-cases the email
+    /// and the tags, and removes duplicate tags
+    pub fn validated(self) -> crate::Result<Self> {
+        let name = self.name.trim().to_owned();
+        if name.is_empty() {
+            return Err(invalid("name", "must not be empty"));
+        }
+        if name.chars().count() > MAX_NAME_LENGTH {
+            return Err(invalid(
+                "name",
+                format!("longer than {} characters", MAX_NAME_LENGTH),
+            ));
+        }
+
+        let email = self.email.trim().to_lowercase();
+        validate_email(&email)?;
+
+        let birth_date = self.birth_date.trim().to_owned();
+        let days = parse_date(&birth_date).ok_or_else(|| {
+            invalid(
+                "birth_date",
+                format!("`{}` is not a YYYY-MM-DD date", birth_date),
+            )
+        })?;
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64 / 86_400)
+            .unwrap_or_default();
+        if days > today {
+            return Err(invalid("birth_date", "is in the future"));
+        }
+        if days < days_from_civil(MIN_BIRTH_YEAR, 1, 1) {
+            return Err(invalid(
+                "birth_date",
+                format!("is before {}", MIN_BIRTH_YEAR),
+            ));
+        }
+
+        let mut tags: Vec<String> = Vec::with_capacity(self.tags.len());
+        for tag in self.tags.iter().map(|tag| tag.trim().to_lowercase()) {
+            if tag.is_empty() || tag.len() > MAX_TAG_LENGTH {
+                return Err(invalid(
+                    "tags",
+                    format!("tags are 1 to {} characters long", MAX_TAG_LENGTH),
+                ));
+            }
+            if !tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(invalid("tags", format!("`{}` has invalid characters", tag)));
+            }
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        if tags.len() > MAX_TAGS {
+            return Err(invalid("tags", format!("more than {} tags", MAX_TAGS)));
+        }
+
+        Ok(Self {
+            id: self.id,
+            name,
+            email,
+            birth_date,
+            tags,
+        })
+    }
+}
+
+impl PersonFilter {
+    pub fn validate(&self) -> crate::Result<()> {
+        if let Some(born_after) = &self.born_after {
+            validate_date("born_after", born_after)?;
+        }
+        if let Some(born_before) = &self.born_before {
+            validate_date("born_before", born_before)?;
+        }
+
+        Ok(())
+    }
+
+    /// SurrealQL condition of the filter, binding `$name`, `$email`, `$tags`,
+    /// `$born_after` and `$born_before`
+    fn condition(&self) -> String {
+        let mut conditions = Vec::new();
+        if self.name.is_some() {
+            conditions.push("string::lowercase(name) CONTAINS $name");
+        }
+        if self.email.is_some() {
+            conditions.push("email = $email");
+        }
+        if !self.tags.is_empty() {
+            conditions.push("tags CONTAINSALL $tags");
+        }
+        // YYYY-MM-DD dates sort as strings
+        if self.born_after.is_some() {
+            conditions.push("birth_date >= $born_after");
+        }
+        if self.born_before.is_some() {
+            conditions.push("birth_date <= $born_before");
+        }
+
+        match conditions.is_empty() {
+            true => String::new(),
+            false => format!(" WHERE {}", conditions.join(" AND ")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+pub trait PersonStore {
+    /// create `person` under a new id, and return it
+    async fn create_person<C>(&self, conn: &C, person: Person) -> crate::Result<Person>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync;
+
+    async fn get_person<C>(&self, conn: &C, id: &str) -> crate::Result<Person>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync;
+
+    /// replace the existing person of the same id
+    async fn update_person<C>(&self, conn: &C, person: Person) -> crate::Result<Person>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync;
+
+    /// remove a person, and return it
+    async fn delete_person<C>(&self, conn: &C, id: &str) -> crate::Result<Person>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync;
+
+    /// list persons matching `filter`, ordered by id
+    async fn list_persons<C>(
+        &self,
+        conn: &C,
+        filter: &PersonFilter,
+        offset: usize,
+        limit: usize,
+    ) -> crate::Result<Page<Person>>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync;
+}
+
+#[tonic::async_trait]
+impl PersonStore for PersonRepository {
+    async fn create_person<C>(&self, conn: &C, person: Person) -> crate::Result<Person>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let person = Person {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            ..person.validated()?
+        };
+
+        let record: surrealdb::Result<Option<Person>> = conn
+            .get_db()
+            .db
+            .create((PERSON_TABLE, person.id.as_str()))
+            .content(&person)
+            .await;
+
+        match record {
+            Ok(_) => Ok(person),
+            Err(err) => Err(AppError::SurrealdbSetError(err)),
+        }
+    }
+
+    async fn get_person<C>(&self, conn: &C, id: &str) -> crate::Result<Person>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let record: surrealdb::Result<Option<Person>> =
+            conn.get_db().db.select((PERSON_TABLE, id)).await;
+
+        match record {
+            Ok(Some(person)) => Ok(person),
+            Ok(None) => Err(AppError::PersonNotFound(id.to_owned())),
+            Err(err) => Err(AppError::SurrealdbGetError(err)),
+        }
+    }
+
+    async fn update_person<C>(&self, conn: &C, person: Person) -> crate::Result<Person>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let person = person.validated()?;
+
+        // the condition fails on a missing record, which is then left alone
+        let record: surrealdb::Result<Option<Person>> = match conn
+            .get_db()
+            .db
+            .query("UPDATE type::thing($table, $id) CONTENT $person WHERE person_id = $id")
+            .bind(("table", PERSON_TABLE))
+            .bind(("id", &person.id))
+            .bind(("person", &person))
+            .await
+        {
+            Ok(mut response) => response.take(0),
+            Err(err) => Err(err),
+        };
+
+        match record {
+            Ok(Some(_)) => Ok(person),
+            Ok(None) => Err(AppError::PersonNotFound(person.id)),
+            Err(err) => Err(AppError::SurrealdbSetError(err)),
+        }
+    }
+
+    async fn delete_person<C>(&self, conn: &C, id: &str) -> crate::Result<Person>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let person = self.get_person(conn, id).await?;
+
+        let deleted: surrealdb::Result<Option<Person>> =
+            conn.get_db().db.delete((PERSON_TABLE, id)).await;
+
+        match deleted {
+            Ok(_) => Ok(person),
+            Err(err) => Err(AppError::SurrealdbSetError(err)),
+        }
+    }
+
+    async fn list_persons<C>(
+        &self,
+        conn: &C,
+        filter: &PersonFilter,
+        offset: usize,
+        limit: usize,
+    ) -> crate::Result<Page<Person>>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        filter.validate()?;
+        let limit = limit.clamp(1, MAX_PAGE_SIZE);
+
+        // one extra row tells whether there is a next page
+        let sql = format!(
+            "SELECT * FROM type::table($table){} ORDER BY person_id LIMIT {} START {}",
+            filter.condition(),
+            limit + 1,
+            offset
+        );
+        let db = conn.get_db().db;
+        let mut query = db.query(sql).bind(("table", PERSON_TABLE));
+        if let Some(name) = &filter.name {
+            query = query.bind(("name", name.to_lowercase()));
+        }
+        if let Some(email) = &filter.email {
+            query = query.bind(("email", email.trim().to_lowercase()));
+        }
+        if !filter.tags.is_empty() {
+            let tags: Vec<String> = filter.tags.iter().map(|tag| tag.to_lowercase()).collect();
+            query = query.bind(("tags", tags));
+        }
+        if let Some(born_after) = &filter.born_after {
+            query = query.bind(("born_after", born_after));
+        }
+        if let Some(born_before) = &filter.born_before {
+            query = query.bind(("born_before", born_before));
+        }
+
+        let records: surrealdb::Result<Vec<Person>> = match query.await {
+            Ok(mut response) => response.take(0),
+            Err(err) => Err(err),
+        };
+
+        match records {
+            Ok(mut items) => {
+                let next_offset = match items.len() > limit {
+                    true => {
+                        items.truncate(limit);
+                        Some(offset + limit)
+                    }
+                    false => None,
+                };
+                Ok(Page { items, next_offset })
+            }
+            Err(err) => Err(AppError::SurrealdbGetError(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn person() -> Person {
+        Person {
+            id: String::new(),
+            name: " Ada Lovelace ".to_owned(),
+            email: "Ada@Example.com".to_owned(),
+            birth_date: "1815-12-10".to_owned(),
+            tags: vec!["Math".to_owned(), "math".to_owned(), "poet".to_owned()],
+        }
+    }
+
+    #[test]
+    fn test_validated_normalizes() {
+        let person = Person {
+            birth_date: "1915-12-10".to_owned(),
+            ..person()
+        }
+        .validated()
+        .unwrap();
+
+        assert_eq!(person.name, "Ada Lovelace");
+        assert_eq!(person.email, "ada@example.com");
+        assert_eq!(person.tags, vec!["math", "poet"]);
+    }
+
+    #[test_case("name", Person { name: "  ".to_owned(), ..person() } ; "empty name")]
+    #[test_case("email", Person { email: "ada.example.com".to_owned(), ..person() } ; "no at")]
+    #[test_case("email", Person { email: "ada@localhost".to_owned(), ..person() } ; "no tld")]
+    #[test_case("birth_date", Person { birth_date: "1915-02-29".to_owned(), ..person() } ; "no leap day")]
+    #[test_case("birth_date", Person { birth_date: "10/12/1915".to_owned(), ..person() } ; "format")]
+    #[test_case("birth_date", Person { birth_date: "2999-01-01".to_owned(), ..person() } ; "future")]
+    #[test_case("birth_date", person() ; "too old")]
+    #[test_case("tags", Person { birth_date: "1915-12-10".to_owned(), tags: vec!["a b".to_owned()], ..person() } ; "tag chars")]
+    fn test_validated_rejects(field: &str, person: Person) {
+        match person.validated() {
+            Err(AppError::InvalidArgument { field: invalid, .. }) => assert_eq!(invalid, field),
+            other => panic!("expected invalid {}, got {:?}", field, other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_person_store() {
+        let repository = PersonRepository::default();
+        let database = <InMemoryDatabase as Connection>::new().await.unwrap();
+        let born = |name: &str, birth_date: &str, tags: &[&str]| Person {
+            name: name.to_owned(),
+            email: format!("{}@example.com", name.to_lowercase().replace(' ', ".")),
+            birth_date: birth_date.to_owned(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..Default::default()
+        };
+
+        let ada = repository
+            .create_person(
+                &database,
+                born("Ada Lovelace", "1915-12-10", &["Math", "poet"]),
+            )
+            .await
+            .unwrap();
+        for (name, birth_date, tags) in [
+            ("Grace Hopper", "1906-12-09", &["math", "navy"][..]),
+            ("Alan Turing", "1912-06-23", &["math"][..]),
+        ] {
+            repository
+                .create_person(&database, born(name, birth_date, tags))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            repository.get_person(&database, &ada.id).await.unwrap(),
+            ada
+        );
+
+        let updated = repository
+            .update_person(
+                &database,
+                Person {
+                    email: "ada@analytical.engine".to_owned(),
+                    ..ada.clone()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            repository.get_person(&database, &ada.id).await.unwrap(),
+            updated
+        );
+        assert!(matches!(
+            repository
+                .update_person(
+                    &database,
+                    Person {
+                        id: "missing".to_owned(),
+                        ..ada.clone()
+                    }
+                )
+                .await,
+            Err(AppError::PersonNotFound(_))
+        ));
+
+        // two pages of persons tagged math, in id order
+        let math = PersonFilter {
+            tags: vec!["MATH".to_owned()],
+            ..Default::default()
+        };
+        let first = repository
+            .list_persons(&database, &math, 0, 2)
+            .await
+            .unwrap();
+        assert_eq!(first.items.len(), 2);
+        assert_eq!(first.next_offset, Some(2));
+        let second = repository
+            .list_persons(&database, &math, 2, 2)
+            .await
+            .unwrap();
+        assert_eq!(second.items.len(), 1);
+        assert_eq!(second.next_offset, None);
+        let mut ids: Vec<String> = first
+            .items
+            .into_iter()
+            .chain(second.items)
+            .map(|p| p.id)
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+
+        let names = |page: Page<Person>| -> Vec<String> {
+            let mut names: Vec<String> = page.items.into_iter().map(|p| p.name).collect();
+            names.sort();
+            names
+        };
+        let filtered = |filter: PersonFilter| {
+            let repository = repository.clone();
+            let database = database.get_db();
+            async move {
+                repository
+                    .list_persons(&database, &filter, 0, 10)
+                    .await
+                    .unwrap()
+            }
+        };
+        let by_name = PersonFilter {
+            name: Some("LOVE".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(names(filtered(by_name).await), vec!["Ada Lovelace"]);
+        let by_tags = PersonFilter {
+            tags: vec!["math".to_owned(), "navy".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(names(filtered(by_tags).await), vec!["Grace Hopper"]);
+        let by_birth = PersonFilter {
+            born_after: Some("1910-01-01".to_owned()),
+            born_before: Some("1913-01-01".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(names(filtered(by_birth).await), vec!["Alan Turing"]);
+        let by_email = PersonFilter {
+            email: Some(" ADA@analytical.engine".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(names(filtered(by_email).await), vec!["Ada Lovelace"]);
+
+        assert_eq!(
+            repository.delete_person(&database, &ada.id).await.unwrap(),
+            updated
+        );
+        assert!(matches!(
+            repository.get_person(&database, &ada.id).await,
+            Err(AppError::PersonNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2000-02-29"), Some(11_016));
+        assert_eq!(parse_date("1900-02-29"), None);
+        assert_eq!(parse_date("2023-13-01"), None);
+        assert_eq!(parse_date("2023-1-01"), None);
+    }
+}
@@ -1,10 +1,37 @@
-use crate::{models::KeyValue, AppError, Connection, InMemoryDatabase};
+use crate::{
+    models::{
+        compression::{Codec, CompressionStats, Compressor},
+        encryption::Keyring,
+        eviction::{self, Admission, EvictionPolicy, MemoryStats, MemoryTracker},
+        KeyValue,
+    },
+    replicas::{now_millis, Replication},
+    AppError, Connection, InMemoryDatabase, Settings,
+};
+use colored::*;
+use std::{
+    sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard},
+    time::Duration,
+};
+use tracing::{debug, error, info, warn};
 
 // NOTE:
 // https://github.com/surrealdb/surrealdb/tree/main/lib
 
+/// How often to check "ENCRYPTION_KEY_ID" for a key rotation
+const KEY_ROTATION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often to remove the keys whose time to live elapsed
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Default, Clone)]
-pub struct PersonRepository {}
+pub struct PersonRepository {
+    memory: Arc<Mutex<MemoryTracker>>,
+    compressor: Arc<Compressor>,
+    keyring: Arc<RwLock<Keyring>>,
+    /// replication of the writes, if any
+    replication: Option<Replication>,
+}
 
 #[tonic::async_trait]
 pub trait KeyValueStore<'a> {
@@ -22,6 +49,523 @@ pub trait KeyValueStore<'a> {
     ) -> crate::Result<Self::Output>
     where
         C: Connection<Output = InMemoryDatabase> + Send + Sync;
+
+    /// create or overwrite the value of `key`
+    async fn put_value<C>(
+        &self,
+        conn: &'a C,
+        key: &'a str,
+        value: &'a str,
+    ) -> crate::Result<Self::Output>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync;
+
+    /// remove `key`
+    async fn delete_value<C>(&self, conn: &'a C, key: &'a str) -> crate::Result<()>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync;
+
+    /// list every key-value pair
+    async fn list_values<C>(&self, conn: &'a C) -> crate::Result<Vec<Self::Output>>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync;
+
+    /// remove every key-value pair
+    async fn clear_values<C>(&self, conn: &'a C) -> crate::Result<()>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync;
+}
+
+impl PersonRepository {
+    /// Bound the repository to `max_memory` bytes of stored records, 0 for unlimited
+    pub fn with_memory_limit(self, max_memory: usize, policy: EvictionPolicy) -> Self {
+        Self {
+            memory: Arc::new(Mutex::new(MemoryTracker::new(max_memory, policy))),
+            ..self
+        }
+    }
+
+    /// Compress values of at least `threshold` bytes with `codec`
+    pub fn with_compression(self, codec: Codec, threshold: usize) -> Self {
+        Self {
+            compressor: Arc::new(Compressor::new(codec, threshold)),
+            ..self
+        }
+    }
+
+    /// Encrypt values with the active key of `keyring`
+    pub fn with_keyring(self, keyring: Keyring) -> Self {
+        Self {
+            keyring: Arc::new(RwLock::new(keyring)),
+            ..self
+        }
+    }
+
+    /// Publish writes to the followers of `replication`, when it is the leader
+    pub fn with_replication(self, replication: Replication) -> Self {
+        Self {
+            replication: Some(replication),
+            ..self
+        }
+    }
+
+    /// Repository bounded by "MAX_MEMORY" (e.g. "64mb") and "MAXMEMORY_POLICY"
+    /// (noeviction, allkeys-lru, allkeys-lfu or volatile-ttl) settings, compressing
+    /// values per "COMPRESSION_CODEC" (none, lz4 or zstd) and "COMPRESSION_THRESHOLD",
+    /// and encrypting them per "ENCRYPTION_*" settings (see `Keyring::from_settings`)
+    pub async fn from_settings() -> crate::Result<Self> {
+        let max_memory = match Settings::get_config_item("MAX_MEMORY").await {
+            Some(size) => eviction::parse_memory_size(&size)?,
+            None => 0,
+        };
+
+        let policy = match Settings::get_config_item("MAXMEMORY_POLICY").await {
+            Some(policy) => policy.parse()?,
+            None => EvictionPolicy::default(),
+        };
+
+        info!(
+            message = "Memory limit".blue().to_string(),
+            max_memory,
+            policy = %policy
+        );
+
+        let codec = match Settings::get_config_item("COMPRESSION_CODEC").await {
+            Some(codec) => codec.parse()?,
+            None => Codec::default(),
+        };
+
+        let threshold = match Settings::get_config_item("COMPRESSION_THRESHOLD").await {
+            Some(size) => {
+                eviction::parse_memory_size(&size).map_err(|_| AppError::InvalidSetting {
+                    key: "COMPRESSION_THRESHOLD".to_owned(),
+                    value: size,
+                })?
+            }
+            None => 0,
+        };
+
+        info!(
+            message = "Value compression".blue().to_string(),
+            codec = %codec,
+            threshold
+        );
+
+        let keyring = Keyring::from_settings().await?;
+        info!(
+            message = "Encryption at rest".blue().to_string(),
+            key_id = ?keyring.active(),
+            key_ids = ?keyring.key_ids()
+        );
+
+        Ok(Self::default()
+            .with_memory_limit(max_memory, policy)
+            .with_compression(codec, threshold)
+            .with_keyring(keyring))
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.memory().stats()
+    }
+
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compressor.stats()
+    }
+
+    /// Compress, then encrypt `value` into the record stored under `key`
+    fn encode<'v>(&self, key: &'v str, value: &str) -> crate::Result<KeyValue<'v>> {
+        let stored = self.compressor.encode(value)?;
+
+        if stored.len() < value.len() {
+            let stats = self.compression_stats();
+            debug!(
+                message = "compressed value".to_string(),
+                key,
+                codec = %self.compressor.codec(),
+                raw_size = value.len(),
+                stored_size = stored.len(),
+                ratio = stats.ratio()
+            );
+        }
+
+        self.seal(key, &stored)
+    }
+
+    /// Decrypt, then decompress a stored record
+    fn decode<'v>(&self, record: KeyValue<'v>) -> crate::Result<KeyValue<'v>> {
+        let stored = self.open(&record)?;
+        let value = self.compressor.decode(&stored)?.into_owned();
+
+        Ok(KeyValue {
+            expires_at_ms: record.expires_at_ms,
+            ..KeyValue::new(record.key, value)
+        })
+    }
+
+    /// Encrypt `stored` with the active key, if encryption is on
+    fn seal<'v>(&self, key: &'v str, stored: &str) -> crate::Result<KeyValue<'v>> {
+        match self.keyring().seal(key, stored)? {
+            Some(sealed) => Ok(KeyValue {
+                nonce: Some(sealed.nonce),
+                key_id: Some(sealed.key_id),
+                ..KeyValue::new(key, sealed.value)
+            }),
+            None => Ok(KeyValue::new(key, stored.to_owned())),
+        }
+    }
+
+    /// Decrypt a record, if it is encrypted
+    fn open(&self, record: &KeyValue) -> crate::Result<String> {
+        match (&record.nonce, &record.key_id) {
+            (Some(nonce), Some(key_id)) => {
+                self.keyring()
+                    .open(&record.key, &record.value, nonce, key_id)
+            }
+            _ => Ok(record.value.to_string()),
+        }
+    }
+
+    fn keyring(&self) -> RwLockReadGuard<Keyring> {
+        self.keyring
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Seal new values with `keyring`. The current keys are kept, so records sealed
+    /// with them remain readable, even if `keyring` lacks them; see `reencrypt`.
+    pub fn rotate_key(&self, keyring: Keyring) {
+        let mut current = self
+            .keyring
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *current = keyring.retaining(&current);
+    }
+
+    /// Re-encrypt every record not sealed with the active key, e.g. after a key
+    /// rotation. Records overwritten in the meantime are left alone. Returns the
+    /// number of records re-encrypted.
+    pub async fn reencrypt<C>(&self, conn: &C) -> crate::Result<usize>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let active = self.keyring().active().map(str::to_owned);
+        if active.is_none() {
+            return Ok(0);
+        }
+
+        let records: Vec<KeyValue> = conn
+            .get_db()
+            .db
+            .select("kv")
+            .await
+            .map_err(AppError::SurrealdbGetError)?;
+
+        let mut reencrypted = 0;
+        for record in records.iter().filter(|record| record.key_id != active) {
+            let sealed = match self
+                .open(record)
+                .and_then(|stored| self.seal(&record.key, &stored))
+            {
+                Ok(sealed) => sealed,
+                Err(err) => {
+                    error!(error = format!("{:?}", err), key = %record.key);
+                    continue;
+                }
+            };
+
+            // compare-and-set: nonces are unique, so is every encrypted value
+            let updated: surrealdb::Result<Vec<KeyValue>> = match conn
+                .get_db()
+                .db
+                .query(
+                    "UPDATE type::thing('kv', $key) \
+                     SET value = $value, nonce = $nonce, key_id = $key_id \
+                     WHERE value = $previous",
+                )
+                .bind(("key", &*record.key))
+                .bind(("value", &*sealed.value))
+                .bind(("nonce", sealed.nonce))
+                .bind(("key_id", sealed.key_id))
+                .bind(("previous", &*record.value))
+                .await
+            {
+                Ok(mut response) => response.take(0),
+                Err(err) => Err(err),
+            };
+
+            match updated {
+                Ok(updated) => reencrypted += updated.len(),
+                Err(err) => error!(error = format!("{:?}", err), key = %record.key),
+            }
+        }
+
+        Ok(reencrypted)
+    }
+
+    /// Follow key rotations: reload the keyring when "ENCRYPTION_KEY_ID" changes,
+    /// then re-encrypt the records sealed with other keys. Also re-encrypts records
+    /// left over from a rotation before the server restarted. Turning encryption off
+    /// requires a restart.
+    pub async fn watch_key_rotation<C>(self, conn: C)
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let mut interval = tokio::time::interval(KEY_ROTATION_INTERVAL);
+        let mut pending = true;
+
+        loop {
+            interval.tick().await;
+
+            let configured = Settings::get_config_item("ENCRYPTION_KEY_ID")
+                .await
+                .filter(|key_id| !key_id.is_empty());
+            let active = self.keyring().active().map(str::to_owned);
+
+            if configured.is_some() && configured != active {
+                match Keyring::from_settings().await {
+                    Ok(keyring) => {
+                        info!(
+                            message = "Rotated encryption key".blue().to_string(),
+                            from = ?active,
+                            to = ?configured
+                        );
+                        self.rotate_key(keyring);
+                        pending = true;
+                    }
+                    Err(err) => error!(error = format!("{:?}", err)),
+                }
+            }
+
+            if pending {
+                match self.reencrypt(&conn).await {
+                    Ok(count) => {
+                        if count > 0 {
+                            info!(
+                                message = "Re-encrypted records".blue().to_string(),
+                                count,
+                                key_id = ?self.keyring().active()
+                            );
+                        }
+                        pending = false;
+                    }
+                    Err(err) => error!(error = format!("{:?}", err)),
+                }
+            }
+        }
+    }
+
+    /// Remove the keys whose time to live elapsed, publishing their deletes when
+    /// leader. Returns the number of keys removed.
+    pub async fn remove_expired<C>(&self, conn: &C) -> crate::Result<usize>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let expired = self.memory().expired(now_millis());
+
+        let mut removed = 0;
+        for key in expired.iter() {
+            let _writes = self.lock_writes().await;
+            // unless written again meanwhile
+            if self.memory().is_expired(key, now_millis()) {
+                self.remove(conn, key).await?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Leader: remove keys once their time to live elapses, whether read or not.
+    /// Followers apply the deletes of the leader.
+    pub async fn watch_expiry<C>(self, conn: C)
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.remove_expired(&conn).await {
+                Ok(0) => {}
+                Ok(count) => debug!(message = "expired keys".to_string(), count),
+                Err(err) => error!(error = format!("{:?}", err)),
+            }
+        }
+    }
+
+    /// Set `key` to `value`, expiring after `ttl`, if any. The expiry is stored and
+    /// replicated with the value.
+    pub async fn set_value_with_ttl<'v, C>(
+        &self,
+        conn: &C,
+        key: &'v str,
+        value: &'v str,
+        ttl: Option<Duration>,
+    ) -> crate::Result<KeyValue<'v>>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let _writes = self.lock_writes().await;
+        let expires_at_ms = ttl.map(|ttl| now_millis() + ttl.as_millis() as i64);
+        let stored = KeyValue {
+            expires_at_ms,
+            ..self.encode(key, value)?
+        };
+        let admission = self.admit(key, &stored.value, expires_at_ms)?;
+
+        let record: Result<Option<KeyValue>, surrealdb::Error> =
+            conn.get_db().db.create(("kv", key)).content(stored).await;
+
+        match record {
+            Ok(_) => {
+                self.evict(conn, &admission.victims).await;
+                if let Some(replication) = self.leader() {
+                    replication.publish_write(key, value, expires_at_ms);
+                }
+                Ok(KeyValue {
+                    expires_at_ms,
+                    ..KeyValue::new(key, value)
+                })
+            }
+            Err(err) => {
+                self.memory().rollback(key, admission);
+                Err(AppError::SurrealdbSetError(err))
+            }
+        }
+    }
+
+    /// Replication to publish writes to, if this repository is the leader's
+    fn leader(&self) -> Option<&Replication> {
+        self.replication
+            .as_ref()
+            .filter(|replication| replication.leader().is_none())
+    }
+
+    /// Leader: hold off other writes until this one is published, so that followers
+    /// apply writes in the order they were applied here
+    async fn lock_writes(&self) -> Option<tokio::sync::MutexGuard<'_, ()>> {
+        match self.leader() {
+            Some(replication) => Some(replication.lock_writes().await),
+            None => None,
+        }
+    }
+
+    fn memory(&self) -> MutexGuard<MemoryTracker> {
+        self.memory
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Account the write of `value` to `key`, expiring at `expires_at_ms`, selecting
+    /// the keys to evict. The victims are deleted by `evict` once the write
+    /// succeeds; a failed write rolls the admission back.
+    fn admit(
+        &self,
+        key: &str,
+        value: &str,
+        expires_at_ms: Option<i64>,
+    ) -> crate::Result<Admission> {
+        let mut memory = self.memory();
+        match memory.admit(key, eviction::record_size(key, value)) {
+            Ok(admission) => {
+                if let Some(expires_at_ms) = expires_at_ms {
+                    memory.expire_at(key, expires_at_ms);
+                }
+                Ok(admission)
+            }
+            Err(err) => {
+                warn!(error = format!("{}", err), key);
+                Err(err)
+            }
+        }
+    }
+
+    /// Delete the `victims` of a write, and publish their deletes when leader
+    async fn evict<C>(&self, conn: &C, victims: &[String])
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        for victim in victims.iter() {
+            let deleted: surrealdb::Result<Option<KeyValue>> =
+                conn.get_db().db.delete(("kv", victim.as_str())).await;
+
+            match deleted {
+                Ok(_) => {
+                    if let Some(replication) = self.leader() {
+                        replication.publish_delete(victim);
+                    }
+                    let stats = self.memory_stats();
+                    info!(
+                        message = "evicted key".yellow().to_string(),
+                        key = victim,
+                        policy = %self.memory().policy(),
+                        used_memory = stats.used_memory,
+                        max_memory = stats.max_memory,
+                        evicted_keys = stats.evicted_keys
+                    );
+                }
+                Err(err) => error!(error = format!("{:?}", err), key = victim),
+            }
+        }
+    }
+
+    /// Delete `key`, and publish the delete when leader. Takes no write lock.
+    async fn remove<C>(&self, conn: &C, key: &str) -> crate::Result<()>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let result: surrealdb::Result<Option<KeyValue>> =
+            conn.get_db().db.delete(("kv", key)).await;
+
+        match result {
+            Ok(_) => {
+                self.memory().remove(key);
+                if let Some(replication) = self.leader() {
+                    replication.publish_delete(key);
+                }
+                Ok(())
+            }
+            Err(err) => Err(AppError::SurrealdbSetError(err)),
+        }
+    }
+
+    /// Follower: store a write replicated from the leader, expiring at
+    /// `expires_at_ms`. The local eviction policy does not apply: the leader made
+    /// room, and replicated its evictions.
+    pub async fn replicate_write<C>(
+        &self,
+        conn: &C,
+        key: &str,
+        value: &str,
+        expires_at_ms: Option<i64>,
+    ) -> crate::Result<()>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let stored = KeyValue {
+            expires_at_ms,
+            ..self.encode(key, value)?
+        };
+        let size = eviction::record_size(key, &stored.value);
+
+        let record: Result<Option<KeyValue>, surrealdb::Error> =
+            conn.get_db().db.update(("kv", key)).content(stored).await;
+
+        match record {
+            Ok(_) => {
+                self.memory().admit_replicated(key, size, expires_at_ms);
+                Ok(())
+            }
+            Err(err) => Err(AppError::SurrealdbSetError(err)),
+        }
+    }
+
+    /// Follower: remove a key deleted, expired or evicted on the leader
+    pub async fn replicate_delete<C>(&self, conn: &C, key: &str) -> crate::Result<()>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        self.remove(conn, key).await
+    }
 }
 
 #[tonic::async_trait]
@@ -32,11 +576,25 @@ impl<'a> KeyValueStore<'a> for PersonRepository {
     where
         C: Connection<Output = InMemoryDatabase> + Send + Sync,
     {
+        let expired = self.memory().is_expired(key, now_millis());
+        if expired {
+            let _writes = self.lock_writes().await;
+            // unless written again meanwhile
+            if self.memory().is_expired(key, now_millis()) {
+                self.remove(conn, key).await?;
+                return Err(AppError::KeyNotFound(key.to_owned()));
+            }
+        }
+
         let result: surrealdb::Result<Option<KeyValue>> =
             conn.get_db().db.select(("kv", key)).await;
 
         match result {
-            Ok(result) => Ok(result.unwrap()),
+            Ok(Some(result)) => {
+                self.memory().touch(key);
+                self.decode(result)
+            }
+            Ok(None) => Err(AppError::KeyNotFound(key.to_owned())),
             Err(err) => Err(AppError::SurrealdbGetError(err)),
         }
     }
@@ -50,19 +608,250 @@ impl<'a> KeyValueStore<'a> for PersonRepository {
     where
         C: Connection<Output = InMemoryDatabase> + Send + Sync,
     {
-        let record: Result<Option<KeyValue>, surrealdb::Error> = conn
-            .get_db()
-            .db
-            .create(("kv", key))
-            .content(KeyValue {
-                key: key.into(),
-                value: value.into(),
-            })
-            .await;
+        self.set_value_with_ttl(conn, key, value, None).await
+    }
+
+    async fn put_value<C>(
+        &self,
+        conn: &'a C,
+        key: &'a str,
+        value: &'a str,
+    ) -> crate::Result<Self::Output>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let _writes = self.lock_writes().await;
+        let stored = self.encode(key, value)?;
+        let admission = self.admit(key, &stored.value, None)?;
+
+        let record: Result<Option<KeyValue>, surrealdb::Error> =
+            conn.get_db().db.update(("kv", key)).content(stored).await;
 
         match record {
-            Ok(result) => Ok(result.unwrap()),
+            Ok(_) => {
+                self.evict(conn, &admission.victims).await;
+                if let Some(replication) = self.leader() {
+                    replication.publish_write(key, value, None);
+                }
+                Ok(KeyValue::new(key, value))
+            }
+            Err(err) => {
+                self.memory().rollback(key, admission);
+                Err(AppError::SurrealdbSetError(err))
+            }
+        }
+    }
+
+    async fn delete_value<C>(&self, conn: &'a C, key: &'a str) -> crate::Result<()>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let _writes = self.lock_writes().await;
+        self.remove(conn, key).await
+    }
+
+    async fn list_values<C>(&self, conn: &'a C) -> crate::Result<Vec<Self::Output>>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let result: surrealdb::Result<Vec<KeyValue>> = conn.get_db().db.select("kv").await;
+
+        // expired keys, not removed yet, are gone already
+        let now_ms = now_millis();
+        match result {
+            Ok(result) => result
+                .into_iter()
+                .filter(|record| !record.is_expired(now_ms))
+                .map(|record| self.decode(record))
+                .collect(),
+            Err(err) => Err(AppError::SurrealdbGetError(err)),
+        }
+    }
+
+    async fn clear_values<C>(&self, conn: &'a C) -> crate::Result<()>
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let result: surrealdb::Result<Vec<KeyValue>> = conn.get_db().db.delete("kv").await;
+
+        match result {
+            Ok(_) => {
+                self.memory().clear();
+                Ok(())
+            }
             Err(err) => Err(AppError::SurrealdbSetError(err)),
         }
     }
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_writes_are_published_in_order() {
+    use crate::protobuffer::replication::replication_event::Event;
+
+    let replication = Replication::default();
+    let (mut events, _) = replication.subscribe();
+    let repository = PersonRepository::default().with_replication(replication);
+    let database = <InMemoryDatabase as Connection>::new().await.unwrap();
+
+    let writes = (0..16).map(|i| {
+        let repository = repository.clone();
+        let conn = database.get_db();
+        tokio::spawn(async move {
+            let value = i.to_string();
+            repository.put_value(&conn, "foo", &value).await.map(|_| ())
+        })
+    });
+    for write in futures::future::join_all(writes).await {
+        write.unwrap().unwrap();
+    }
+
+    // followers apply the events in order, and end up with the leader's value
+    let mut last = None;
+    while let Ok(event) = events.try_recv() {
+        if let Some(Event::Write(write)) = event.event {
+            last = Some(write.value);
+        }
+    }
+    let stored = repository.get_value(&database, "foo").await.unwrap();
+    assert_eq!(Some(stored.value.into_owned()), last);
+}
+
+#[tokio::test]
+async fn test_evictions_are_replicated() {
+    use crate::{protobuffer::replication::replication_event::Event, replicas::Role};
+
+    let size = eviction::record_size("a", "1");
+    let replication = Replication::default();
+    let (mut events, _) = replication.subscribe();
+    let leader = PersonRepository::default()
+        .with_memory_limit(2 * size, EvictionPolicy::AllKeysLru)
+        .with_replication(replication);
+    let database = <InMemoryDatabase as Connection>::new().await.unwrap();
+    for key in ["a", "b", "c"] {
+        leader.put_value(&database, key, "1").await.unwrap();
+    }
+
+    let deletes: Vec<String> = std::iter::from_fn(|| events.try_recv().ok())
+        .filter_map(|event| match event.event {
+            Some(Event::Delete(delete)) => Some(delete.key),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(deletes, vec!["a"]);
+
+    // a full follower still applies the writes of the leader
+    let follower = PersonRepository::default()
+        .with_memory_limit(size, EvictionPolicy::NoEviction)
+        .with_replication(Replication::new(Role::Follower {
+            leader: "http://127.0.0.1:50051".to_owned(),
+        }));
+    let database = <InMemoryDatabase as Connection>::new().await.unwrap();
+    for key in ["b", "c"] {
+        follower
+            .replicate_write(&database, key, "1", None)
+            .await
+            .unwrap();
+    }
+    follower.replicate_delete(&database, "b").await.unwrap();
+    assert_eq!(follower.list_values(&database).await.unwrap().len(), 1);
+    assert_eq!(follower.memory_stats().evicted_keys, 0);
+}
+
+#[tokio::test]
+async fn test_failed_write_evicts_nothing() {
+    use crate::protobuffer::replication::replication_event::Event;
+
+    let size = eviction::record_size("a", "1");
+    let replication = Replication::default();
+    let (mut events, _) = replication.subscribe();
+    let leader = PersonRepository::default()
+        .with_memory_limit(2 * size, EvictionPolicy::AllKeysLru)
+        .with_replication(replication);
+    let database = <InMemoryDatabase as Connection>::new().await.unwrap();
+    for key in ["a", "b"] {
+        leader.put_value(&database, key, "1").await.unwrap();
+    }
+    while events.try_recv().is_ok() {}
+    let stats = leader.memory_stats();
+
+    // "b" would be evicted for the larger value, but "a" exists already
+    assert!(leader.set_value(&database, "a", "11").await.is_err());
+    assert_eq!(leader.get_value(&database, "b").await.unwrap().value, "1");
+    assert_eq!(leader.memory_stats(), stats);
+    assert!(!std::iter::from_fn(|| events.try_recv().ok())
+        .any(|event| matches!(event.event, Some(Event::Delete(_)))));
+}
+
+#[tokio::test]
+async fn test_expiry_is_stored_and_replicated() {
+    use crate::{protobuffer::replication::replication_event::Event, replicas::Role};
+
+    let replication = Replication::default();
+    let (mut events, _) = replication.subscribe();
+    let leader = PersonRepository::default().with_replication(replication);
+    let database = <InMemoryDatabase as Connection>::new().await.unwrap();
+    leader
+        .set_value_with_ttl(&database, "a", "1", Some(Duration::from_millis(50)))
+        .await
+        .unwrap();
+    leader.put_value(&database, "b", "1").await.unwrap();
+
+    let expires_at_ms = match events.try_recv().unwrap().event {
+        Some(Event::Write(write)) => write.expires_at_ms,
+        event => panic!("unexpected {:?}", event),
+    };
+    assert!(expires_at_ms > 0);
+    let values = leader.list_values(&database).await.unwrap();
+    let a = values.iter().find(|kv| kv.key == "a").unwrap();
+    assert_eq!(a.expires_at_ms, Some(expires_at_ms));
+
+    // removed once expired, without being read
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(leader.list_values(&database).await.unwrap().len(), 1);
+    assert_eq!(leader.remove_expired(&database).await.unwrap(), 1);
+    assert_eq!(leader.memory_stats().keys, 1);
+    let deletes: Vec<String> = std::iter::from_fn(|| events.try_recv().ok())
+        .filter_map(|event| match event.event {
+            Some(Event::Delete(delete)) => Some(delete.key),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(deletes, vec!["a"]);
+
+    // followers expire replicated keys too
+    let follower = PersonRepository::default().with_replication(Replication::new(Role::Follower {
+        leader: "http://127.0.0.1:50051".to_owned(),
+    }));
+    let database = <InMemoryDatabase as Connection>::new().await.unwrap();
+    follower
+        .replicate_write(&database, "a", "1", Some(expires_at_ms))
+        .await
+        .unwrap();
+    assert!(follower.get_value(&database, "a").await.is_err());
+}
+
+#[tokio::test]
+async fn test_rotation_keeps_the_current_key() {
+    use std::collections::HashMap;
+
+    let keyring = |key_id: &str, passphrase: &str| {
+        let passphrases = HashMap::from([(key_id.to_owned(), passphrase.to_owned())]);
+        Keyring::derive(key_id, &passphrases, "salt").unwrap()
+    };
+    let repository = PersonRepository::default().with_keyring(keyring("k1", "correct horse"));
+    let database = <InMemoryDatabase as Connection>::new().await.unwrap();
+    repository.put_value(&database, "foo", "bar").await.unwrap();
+
+    // a new key id and passphrase, without a keyfile of the retired key
+    repository.rotate_key(keyring("k2", "battery staple"));
+    assert_eq!(repository.keyring().key_ids(), vec!["k1", "k2"]);
+    assert_eq!(
+        repository.get_value(&database, "foo").await.unwrap().value,
+        "bar"
+    );
+    assert_eq!(repository.reencrypt(&database).await.unwrap(), 1);
+    assert_eq!(
+        repository.get_value(&database, "foo").await.unwrap().value,
+        "bar"
+    );
+}
@@ -0,0 +1,138 @@
+//!
+//! Unspent transaction outputs, and the coin selection of payments
+//!
+
+use serde::{Deserialize, Serialize};
+
+/// Table of transaction outputs, keyed by outpoint
+pub const UTXO_TABLE: &str = "utxo";
+
+/// Height of outputs and payments not mined yet
+pub const MEMPOOL_HEIGHT: i64 = -1;
+
+/// Outputs below this amount cost more to spend than they are worth: a smaller
+/// change goes to the fee instead
+pub const DUST_LIMIT: u64 = 546;
+
+/// Virtual size of a transaction, in vbytes, estimated for P2WPKH inputs and outputs
+pub fn estimate_vsize(inputs: usize, outputs: usize) -> u64 {
+    11 + 68 * inputs as u64 + 31 * outputs as u64
+}
+
+/// A transaction output, spendable by its address until spent
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Utxo {
+    /// `txid:vout`
+    pub outpoint: String,
+    pub txid: String,
+    pub vout: i64,
+    pub address: String,
+    /// satoshis
+    pub amount: i64,
+    /// block of the transaction, or MEMPOOL_HEIGHT
+    pub height: i64,
+    /// txid of the spending transaction, empty if unspent
+    #[serde(default)]
+    pub spent_by: String,
+}
+
+impl Utxo {
+    pub fn new(txid: &str, vout: i64, address: &str, amount: i64, height: i64) -> Self {
+        Self {
+            outpoint: format!("{}:{}", txid, vout),
+            txid: txid.to_owned(),
+            vout,
+            address: address.to_owned(),
+            amount,
+            height,
+            spent_by: String::new(),
+        }
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.height != MEMPOOL_HEIGHT
+    }
+
+    /// Blocks mined since, and including, the block of this output
+    pub fn confirmations(&self, tip: i64) -> u64 {
+        confirmations(self.height, tip)
+    }
+}
+
+/// Confirmations at `tip` of a transaction mined at `height`
+pub fn confirmations(height: i64, tip: i64) -> u64 {
+    match height {
+        MEMPOOL_HEIGHT => 0,
+        height => (tip - height + 1).max(0) as u64,
+    }
+}
+
+/// Inputs of a payment, its fee, and its change back to the payer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub inputs: Vec<Utxo>,
+    pub fee: u64,
+    /// 0 without a change output
+    pub change: u64,
+}
+
+/// Select confirmed outputs paying `amount`, and the fee at `fee_rate` sat/vB,
+/// largest first. Returns the missing satoshis when they cannot.
+pub fn select_coins(mut utxos: Vec<Utxo>, amount: u64, fee_rate: u64) -> Result<Selection, u64> {
+    utxos.retain(|utxo| utxo.is_confirmed() && utxo.spent_by.is_empty());
+    utxos.sort_by(|a, b| b.amount.cmp(&a.amount).then(a.outpoint.cmp(&b.outpoint)));
+
+    let mut total = 0;
+    for count in 1..=utxos.len() {
+        total += utxos[count - 1].amount.max(0) as u64;
+
+        // with a change output, unless the change would be dust
+        let fee = fee_rate * estimate_vsize(count, 2);
+        if total >= amount + fee + DUST_LIMIT {
+            utxos.truncate(count);
+            return Ok(Selection {
+                inputs: utxos,
+                fee,
+                change: total - amount - fee,
+            });
+        }
+        let fee = fee_rate * estimate_vsize(count, 1);
+        if total >= amount + fee {
+            utxos.truncate(count);
+            return Ok(Selection {
+                inputs: utxos,
+                fee: total - amount,
+                change: 0,
+            });
+        }
+    }
+
+    Err(amount + fee_rate * estimate_vsize(utxos.len().max(1), 1) - total)
+}
+
+#[test]
+fn test_select_coins() {
+    let utxo = |vout, amount, height| Utxo::new("a", vout, "bcrt1q", amount, height);
+    let utxos = vec![
+        utxo(0, 5_000, 0),
+        utxo(1, 20_000, 3),
+        utxo(2, 50_000, MEMPOOL_HEIGHT),
+    ];
+
+    // largest confirmed output first, with change
+    let selection = select_coins(utxos.clone(), 10_000, 2).unwrap();
+    assert_eq!(selection.inputs.len(), 1);
+    assert_eq!(selection.inputs[0].vout, 1);
+    assert_eq!(selection.fee, 2 * estimate_vsize(1, 2));
+    assert_eq!(selection.change, 20_000 - 10_000 - selection.fee);
+
+    // both confirmed outputs, the dust change goes to the fee
+    let selection = select_coins(utxos.clone(), 24_500, 1).unwrap();
+    assert_eq!(selection.inputs.len(), 2);
+    assert_eq!((selection.fee, selection.change), (500, 0));
+
+    // unconfirmed outputs are not spendable
+    assert_eq!(select_coins(utxos, 25_000, 1), Err(estimate_vsize(2, 1)));
+    assert_eq!(confirmations(3, 5), 3);
+    assert_eq!(confirmations(MEMPOOL_HEIGHT, 5), 0);
+}
@@ -0,0 +1,235 @@
+use super::Replication;
+use crate::{
+    models::{KeyValueStore, PersonRepository},
+    protobuffer::replication::{
+        replication_client::ReplicationClient, replication_event::Event, SyncRequest,
+    },
+    tls::ClientTls,
+    AppError, Backoff, Connection, InMemoryDatabase, Settings,
+};
+use colored::*;
+use std::time::Duration;
+use tonic::transport::Endpoint;
+use tracing::{error, info, instrument, warn};
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const LAG_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Largest replication event: a snapshot batch of a single value, up to the 4 MiB
+/// of a request, with room to spare
+const MAX_EVENT_SIZE: usize = 8 * 1024 * 1024;
+
+/// Follow the leader: apply its snapshot, then every write it streams. Reconnects
+/// with exponential backoff, and resyncs from a new snapshot after each reconnect.
+/// Returns immediately if this server is not a follower.
+pub async fn follow<C>(replication: Replication, person: PersonRepository, connection: C)
+where
+    C: Connection<Output = InMemoryDatabase> + Send + Sync,
+{
+    let leader = match replication.leader() {
+        Some(leader) => leader.to_owned(),
+        None => return,
+    };
+
+    let reporter = replication.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LAG_REPORT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let lag = reporter.lag();
+            info!(
+                message = "replication lag".blue().to_string(),
+                connected = reporter.is_connected(),
+                applied_sequence = reporter.sequence(),
+                lag_operations = lag.operations,
+                lag_millis = lag.millis
+            );
+        }
+    });
+
+    let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+    loop {
+        match sync_once(&leader, &replication, &person, &connection).await {
+            Ok(_) => warn!("{}", "leader closed the replication stream".yellow()),
+            Err(err) => error!(error = format!("{:?}", err)),
+        }
+
+        if replication.is_connected() {
+            backoff.reset();
+        }
+        replication.set_connected(false);
+
+        let delay = backoff.next_delay();
+        info!(
+            message = "reconnecting to leader".blue().to_string(),
+            leader,
+            backoff_millis = delay.as_millis() as u64
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Expiry of a replicated key, 0 if it does not expire
+fn expiry(expires_at_ms: i64) -> Option<i64> {
+    Some(expires_at_ms).filter(|expires_at_ms| *expires_at_ms > 0)
+}
+
+#[instrument(skip(replication, person, connection), name = "replication_sync")]
+async fn sync_once<C>(
+    leader: &str,
+    replication: &Replication,
+    person: &PersonRepository,
+    connection: &C,
+) -> crate::Result<()>
+where
+    C: Connection<Output = InMemoryDatabase> + Send + Sync,
+{
+    // over TLS to an https:// leader, with a client certificate if it requires one
+    let endpoint = Endpoint::new(leader.to_owned()).map_err(AppError::TonicError)?;
+    let channel = ClientTls::from_settings()
+        .await
+        .endpoint(endpoint)?
+        .connect()
+        .await
+        .map_err(AppError::TonicError)?;
+    let mut client = ReplicationClient::new(channel).max_decoding_message_size(MAX_EVENT_SIZE);
+
+    let mut request = tonic::Request::new(SyncRequest {
+        follower_id: format!("follower-{}", std::process::id()),
+    });
+    // token of the leader's AUTH_TOKENS, if it requires one
+    if let Some(token) = Settings::get_config_item("REPLICATION_TOKEN")
+        .await
+        .filter(|token| !token.is_empty())
+    {
+        let authorization =
+            format!("Bearer {}", token)
+                .parse()
+                .map_err(|_| AppError::InvalidSetting {
+                    key: "REPLICATION_TOKEN".to_owned(),
+                    value: "<redacted>".to_owned(),
+                })?;
+        request
+            .metadata_mut()
+            .insert("authorization", authorization);
+    }
+    let mut stream = client
+        .sync(request)
+        .await
+        .map_err(AppError::ReplicationError)?
+        .into_inner();
+
+    replication.set_connected(true);
+    info!(message = "following leader".green().to_string(), leader);
+
+    // entries of the snapshot applied so far, while its batches arrive
+    let mut snapshot_entries = None;
+    while let Some(event) = stream.message().await.map_err(AppError::ReplicationError)? {
+        match &event.event {
+            Some(Event::Snapshot(snapshot)) => {
+                if snapshot_entries.is_none() {
+                    person.clear_values(connection).await?;
+                }
+                for entry in snapshot.entries.iter() {
+                    person
+                        .replicate_write(
+                            connection,
+                            &entry.key,
+                            &entry.value,
+                            expiry(entry.expires_at_ms),
+                        )
+                        .await?;
+                }
+                let entries = snapshot_entries.unwrap_or(0) + snapshot.entries.len();
+                snapshot_entries = Some(entries);
+                if !snapshot.last {
+                    continue;
+                }
+
+                snapshot_entries = None;
+                info!(
+                    message = "snapshot applied".green().to_string(),
+                    sequence = event.sequence,
+                    entries
+                );
+            }
+            Some(Event::Write(write)) => {
+                person
+                    .replicate_write(
+                        connection,
+                        &write.key,
+                        &write.value,
+                        expiry(write.expires_at_ms),
+                    )
+                    .await?;
+            }
+            Some(Event::Delete(delete)) => {
+                person.replicate_delete(connection, &delete.key).await?;
+            }
+            Some(Event::Heartbeat(_)) | None => {}
+        }
+        replication.record_applied(&event);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_snapshot_above_decoding_limit() {
+    use super::Role;
+    use crate::{
+        protobuffer::replication::replication_server::ReplicationServer,
+        server::ReplicationServerBuilder,
+    };
+
+    // 6 MiB of values, over the 4 MiB a single message may carry
+    let leader_replication = Replication::default();
+    let leader = PersonRepository::default().with_replication(leader_replication.clone());
+    let leader_database = <InMemoryDatabase as Connection>::new().await.unwrap();
+    let value = "v".repeat(1024 * 1024);
+    for i in 0..6 {
+        leader
+            .put_value(&leader_database, &format!("key-{}", i), &value)
+            .await
+            .unwrap();
+    }
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let replication_server = ReplicationServerBuilder::default()
+        .person(leader)
+        .connection(leader_database.get_db())
+        .replication(leader_replication)
+        .build()
+        .unwrap();
+    let server = tonic::transport::Server::builder()
+        .add_service(ReplicationServer::new(replication_server))
+        .serve_with_incoming(futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        }));
+    tokio::spawn(server);
+
+    let leader = format!("http://127.0.0.1:{}", port);
+    let replication = Replication::new(Role::Follower {
+        leader: leader.clone(),
+    });
+    let follower = PersonRepository::default();
+    let database = <InMemoryDatabase as Connection>::new().await.unwrap();
+    let applied = async {
+        while follower.list_values(&database).await.unwrap().len() < 6 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+
+    tokio::select! {
+        result = sync_once(&leader, &replication, &follower, &database) => {
+            panic!("replication stream ended: {:?}", result)
+        }
+        _ = tokio::time::timeout(Duration::from_secs(10), applied) => {}
+    }
+    let values = follower.list_values(&database).await.unwrap();
+    assert_eq!(values.len(), 6);
+    assert!(values.iter().all(|kv| kv.value == value));
+}
@@ -0,0 +1,300 @@
+//!
+//! Leader / follower replication
+//!
+
+mod follower;
+pub use follower::follow;
+
+use crate::{
+    protobuffer::replication::{
+        replication_event::Event, DeleteOperation, ReplicationEvent, StatusResponse, WriteOperation,
+    },
+    Settings, DEFAULT_PORT,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{broadcast, Mutex, MutexGuard};
+
+/// Metadata key carrying the leader address, when a follower rejects a write
+pub const LEADER_METADATA_KEY: &str = "x-simply-leader";
+
+/// Number of write events buffered for followers. A follower falling further
+/// behind is disconnected, and resyncs from a new snapshot.
+const EVENT_BUFFER: usize = 1024;
+
+/// Replication role of a server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Role {
+    /// accepts writes, and streams them to followers
+    Leader,
+    /// serves read-only traffic, replicated from `leader`
+    Follower { leader: String },
+}
+
+impl Role {
+    /// Load the role from "REPLICATION_ROLE" and "REPLICATION_LEADER" settings
+    pub async fn from_settings() -> Role {
+        match Settings::get_config_item("REPLICATION_ROLE")
+            .await
+            .as_deref()
+        {
+            Some("follower") => Role::Follower {
+                leader: Settings::get_config_item("REPLICATION_LEADER")
+                    .await
+                    .unwrap_or(format!("http://127.0.0.1:{}", DEFAULT_PORT)),
+            },
+            _ => Role::Leader,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Leader => "leader",
+            Role::Follower { .. } => "follower",
+        }
+    }
+}
+
+/// Replication lag of a follower
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lag {
+    /// writes committed on the leader, not yet applied
+    pub operations: u64,
+    /// time since the follower was last known to be caught up
+    pub millis: i64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// leader: last committed sequence; follower: last applied sequence
+    sequence: AtomicU64,
+    /// follower: last known sequence of the leader
+    leader_sequence: AtomicU64,
+    /// follower: leader timestamp, when the follower was last caught up
+    synced_at_ms: AtomicI64,
+    /// follower: connected to the leader
+    connected: AtomicBool,
+    events: broadcast::Sender<ReplicationEvent>,
+    /// leader: held from applying a write until it is published
+    writes: Mutex<()>,
+}
+
+/// Shared replication state, cheap to clone
+#[derive(Debug, Clone)]
+pub struct Replication {
+    role: Role,
+    inner: Arc<Inner>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new(Role::Leader)
+    }
+}
+
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+impl Replication {
+    pub fn new(role: Role) -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+
+        Self {
+            role,
+            inner: Arc::new(Inner {
+                sequence: AtomicU64::new(0),
+                leader_sequence: AtomicU64::new(0),
+                synced_at_ms: AtomicI64::new(0),
+                connected: AtomicBool::new(false),
+                events,
+                writes: Mutex::new(()),
+            }),
+        }
+    }
+
+    pub fn role(&self) -> &Role {
+        &self.role
+    }
+
+    /// Address of the leader, if this server is a follower
+    pub fn leader(&self) -> Option<&str> {
+        match &self.role {
+            Role::Leader => None,
+            Role::Follower { leader } => Some(leader),
+        }
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.inner.sequence.load(Ordering::SeqCst)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.inner.connected.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_connected(&self, connected: bool) {
+        self.inner.connected.store(connected, Ordering::SeqCst);
+    }
+
+    /// Build an event stamped with the current leader sequence and clock
+    pub(crate) fn event(&self, sequence: u64, event: Event) -> ReplicationEvent {
+        ReplicationEvent {
+            sequence,
+            leader_sequence: self.sequence(),
+            leader_timestamp_ms: now_millis(),
+            event: Some(event),
+        }
+    }
+
+    /// Leader: serialize writes until they are published, so that followers apply
+    /// them in the order the leader did
+    pub async fn lock_writes(&self) -> MutexGuard<'_, ()> {
+        self.inner.writes.lock().await
+    }
+
+    /// Leader: record a committed write, expiring at `expires_at_ms` if any, and
+    /// stream it to the followers
+    pub fn publish_write(
+        &self,
+        key: impl ToString,
+        value: impl ToString,
+        expires_at_ms: Option<i64>,
+    ) -> u64 {
+        self.publish(Event::Write(WriteOperation {
+            key: key.to_string(),
+            value: value.to_string(),
+            expires_at_ms: expires_at_ms.unwrap_or_default(),
+        }))
+    }
+
+    /// Leader: record a removed key, and stream it to the followers
+    pub fn publish_delete(&self, key: impl ToString) -> u64 {
+        self.publish(Event::Delete(DeleteOperation {
+            key: key.to_string(),
+        }))
+    }
+
+    fn publish(&self, event: Event) -> u64 {
+        let sequence = self.inner.sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        let event = self.event(sequence, event);
+
+        // no receiver means no follower is connected
+        let _ = self.inner.events.send(event);
+
+        sequence
+    }
+
+    /// Leader: subscribe to upcoming writes. Returns the receiver, and the sequence
+    /// a snapshot taken right after subscribing is consistent with.
+    pub(crate) fn subscribe(&self) -> (broadcast::Receiver<ReplicationEvent>, u64) {
+        let receiver = self.inner.events.subscribe();
+
+        (receiver, self.sequence())
+    }
+
+    /// Follower: record an event received from the leader
+    pub(crate) fn record_applied(&self, event: &ReplicationEvent) {
+        if matches!(
+            event.event,
+            Some(Event::Snapshot(_)) | Some(Event::Write(_)) | Some(Event::Delete(_))
+        ) {
+            self.inner.sequence.store(event.sequence, Ordering::SeqCst);
+        }
+        self.inner
+            .leader_sequence
+            .store(event.leader_sequence, Ordering::SeqCst);
+
+        if self.sequence() >= event.leader_sequence {
+            self.inner
+                .synced_at_ms
+                .store(event.leader_timestamp_ms, Ordering::SeqCst);
+        }
+    }
+
+    /// Follower: replication lag, as of `now_ms`
+    pub fn lag_at(&self, now_ms: i64) -> Lag {
+        let leader_sequence = self.inner.leader_sequence.load(Ordering::SeqCst);
+        let synced_at_ms = self.inner.synced_at_ms.load(Ordering::SeqCst);
+
+        Lag {
+            operations: leader_sequence.saturating_sub(self.sequence()),
+            millis: (now_ms - synced_at_ms).max(0),
+        }
+    }
+
+    pub fn lag(&self) -> Lag {
+        self.lag_at(now_millis())
+    }
+
+    /// Replication status, as reported by the `Status` rpc
+    pub fn status(&self) -> StatusResponse {
+        match &self.role {
+            Role::Leader => StatusResponse {
+                role: self.role.as_str().to_owned(),
+                leader: String::new(),
+                applied_sequence: self.sequence(),
+                leader_sequence: self.sequence(),
+                lag_operations: 0,
+                lag_millis: 0,
+                connected: true,
+            },
+            Role::Follower { leader } => {
+                let lag = self.lag();
+
+                StatusResponse {
+                    role: self.role.as_str().to_owned(),
+                    leader: leader.to_owned(),
+                    applied_sequence: self.sequence(),
+                    leader_sequence: self.inner.leader_sequence.load(Ordering::SeqCst),
+                    lag_operations: lag.operations,
+                    lag_millis: lag.millis,
+                    connected: self.is_connected(),
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_replication_lag() {
+    use crate::protobuffer::replication::Heartbeat;
+
+    let leader = Replication::default();
+    let follower = Replication::new(Role::Follower {
+        leader: "http://127.0.0.1:50051".to_owned(),
+    });
+
+    let (mut events, snapshot_sequence) = leader.subscribe();
+    assert_eq!(snapshot_sequence, 0);
+    assert_eq!(leader.publish_write("foo", "bar", None), 1);
+    assert_eq!(leader.publish_write("foo", "baz", None), 2);
+
+    // follower has seen the latest write, but only applied the first one
+    let first = events.try_recv().unwrap();
+    let mut second = events.try_recv().unwrap();
+    follower.record_applied(&first);
+    second.leader_timestamp_ms = 1_000;
+    follower.record_applied(&ReplicationEvent {
+        event: Some(Event::Heartbeat(Heartbeat {})),
+        ..second.clone()
+    });
+    assert_eq!(follower.lag_at(1_500).operations, 1);
+
+    follower.record_applied(&second);
+    assert_eq!(
+        follower.lag_at(1_500),
+        Lag {
+            operations: 0,
+            millis: 500
+        }
+    );
+}
@@ -0,0 +1,239 @@
+use crate::{models::sha256_hex, Settings};
+use colored::*;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tonic::{service::Interceptor, Request, Status};
+use tracing::{error, info, warn};
+
+/// Metadata key of API keys; bearer tokens go in "authorization"
+pub const API_KEY_METADATA_KEY: &str = "x-api-key";
+
+/// How often to check "AUTH_TOKENS" for changes
+const TOKEN_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Authenticated caller, added to the extensions of its requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub token_id: String,
+}
+
+/// Token ids, by SHA-256 of their secret. Tokens are random, so a fast hash is
+/// enough: guessing a secret from its hash is as hard as guessing the secret.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenStore {
+    tokens: HashMap<String, String>,
+}
+
+impl TokenStore {
+    /// Parse `token_id:sha256` entries, separated by commas
+    pub fn parse(tokens: &str) -> Result<Self, String> {
+        let mut store = Self::default();
+        for entry in tokens
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (token_id, hash) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("`{}` is not token_id:sha256", entry))?;
+            let hash = hash.trim().to_lowercase();
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("hash of `{}` is not a hex SHA-256", token_id));
+            }
+            store.tokens.insert(hash, token_id.trim().to_owned());
+        }
+
+        Ok(store)
+    }
+
+    /// Tokens of "AUTH_TOKENS"
+    pub async fn from_settings() -> crate::Result<Self> {
+        let tokens = Settings::get_config_item("AUTH_TOKENS")
+            .await
+            .unwrap_or_default();
+
+        Self::parse(&tokens).map_err(|_| crate::AppError::InvalidSetting {
+            key: "AUTH_TOKENS".to_owned(),
+            value: "<redacted>".to_owned(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Id of the token of `secret`, if known
+    pub fn authenticate(&self, secret: &str) -> Option<&str> {
+        self.tokens.get(&hash_token(secret)).map(String::as_str)
+    }
+}
+
+/// Hash of a token secret, as configured in "AUTH_TOKENS"
+pub fn hash_token(secret: &str) -> String {
+    sha256_hex(secret.as_bytes())
+}
+
+/// Interceptor rejecting requests without a known bearer token or API key, unless
+/// explicitly disabled. Without tokens, every request is rejected.
+#[derive(Debug, Clone, Default)]
+pub struct Authenticator {
+    store: Arc<RwLock<TokenStore>>,
+    disabled: bool,
+}
+
+impl Authenticator {
+    pub fn new(store: TokenStore) -> Self {
+        Self {
+            store: Arc::new(RwLock::new(store)),
+            disabled: false,
+        }
+    }
+
+    /// Let every caller in, e.g. in development
+    pub fn disabled() -> Self {
+        Self {
+            disabled: true,
+            ..Self::default()
+        }
+    }
+
+    /// Tokens of "AUTH_TOKENS", required unless "AUTH_DISABLED" is "true"
+    pub async fn from_settings() -> crate::Result<Self> {
+        if Settings::get_config_item("AUTH_DISABLED").await.as_deref() == Some("true") {
+            return Ok(Self::disabled());
+        }
+
+        let store = TokenStore::from_settings().await?;
+        match store.is_empty() {
+            true => Err(crate::AppError::InvalidSetting {
+                key: "AUTH_TOKENS".to_owned(),
+                value: String::new(),
+            }),
+            false => Ok(Self::new(store)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.disabled
+    }
+
+    fn store(&self) -> std::sync::RwLockReadGuard<TokenStore> {
+        self.store
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Caller of `request`, from "authorization: Bearer <token>" or "x-api-key: <token>"
+    pub fn authenticate<T>(&self, request: &Request<T>) -> Result<Caller, Status> {
+        let metadata = request.metadata();
+        let secret = match (
+            metadata.get("authorization"),
+            metadata.get(API_KEY_METADATA_KEY),
+        ) {
+            (Some(authorization), _) => authorization
+                .to_str()
+                .ok()
+                .and_then(|authorization| authorization.strip_prefix("Bearer "))
+                .ok_or_else(|| Status::unauthenticated("authorization is not a bearer token"))?,
+            (None, Some(api_key)) => api_key
+                .to_str()
+                .map_err(|_| Status::unauthenticated("invalid API key"))?,
+            (None, None) => return Err(Status::unauthenticated("missing token")),
+        };
+
+        match self.store().authenticate(secret.trim()) {
+            Some(token_id) => Ok(Caller {
+                token_id: token_id.to_owned(),
+            }),
+            None => Err(Status::unauthenticated("invalid token")),
+        }
+    }
+
+    /// Reload "AUTH_TOKENS" when env.toml changes. Invalid or no tokens keep the
+    /// current ones: an unreadable env.toml must not turn authentication off.
+    pub async fn watch_settings(self) {
+        let mut interval = tokio::time::interval(TOKEN_RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            match TokenStore::from_settings().await {
+                Ok(store) if store.is_empty() => {
+                    if !self.store().is_empty() {
+                        warn!(
+                            "{}",
+                            "AUTH_TOKENS is empty, keeping the current tokens".yellow()
+                        );
+                    }
+                }
+                Ok(store) if store != *self.store() => {
+                    info!(
+                        message = "Reloaded auth tokens".blue().to_string(),
+                        tokens = store.tokens.len()
+                    );
+                    *self
+                        .store
+                        .write()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner()) = store;
+                }
+                Ok(_) => {}
+                Err(err) => error!(error = format!("{:?}", err)),
+            }
+        }
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if !self.is_enabled() {
+            return Ok(request);
+        }
+
+        let caller = self.authenticate(&request)?;
+        request.extensions_mut().insert(caller);
+        Ok(request)
+    }
+}
+
+#[test]
+fn test_authenticate() {
+    let store = TokenStore::parse(&format!(
+        "ci:{}, ops:{}",
+        hash_token("ci-secret"),
+        hash_token("ops-secret")
+    ))
+    .unwrap();
+    assert_eq!(store.authenticate("ops-secret"), Some("ops"));
+    assert_eq!(store.authenticate("guess"), None);
+    assert!(TokenStore::parse("ci:1234").is_err());
+    assert!(TokenStore::parse("").unwrap().is_empty());
+
+    let mut auth = Authenticator::new(store);
+    let mut request = Request::new(());
+    request
+        .metadata_mut()
+        .insert("authorization", "Bearer ci-secret".parse().unwrap());
+    let request = auth.call(request).unwrap();
+    assert_eq!(
+        request
+            .extensions()
+            .get::<Caller>()
+            .map(|caller| caller.token_id.as_str()),
+        Some("ci")
+    );
+
+    let mut request = Request::new(());
+    request
+        .metadata_mut()
+        .insert(API_KEY_METADATA_KEY, "wrong".parse().unwrap());
+    assert_eq!(
+        auth.call(request).unwrap_err().code(),
+        tonic::Code::Unauthenticated
+    );
+    assert!(auth.call(Request::new(())).is_err());
+
+    // no token lets no one in, unless disabled
+    assert!(Authenticator::default().call(Request::new(())).is_err());
+    assert!(Authenticator::disabled().call(Request::new(())).is_ok());
+}
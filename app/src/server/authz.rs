@@ -0,0 +1,249 @@
+use super::Caller;
+use crate::{AppError, Settings};
+use colored::*;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tonic::Status;
+use tracing::{error, info};
+
+/// Least role of the `Echo` RPCs not set by "AUTH_RPC_ROLES"; other RPCs need admin
+const DEFAULT_RPC_ROLES: [(&str, AccessRole); 7] = [
+    ("GetValue", AccessRole::Reader),
+    ("SetValue", AccessRole::Writer),
+    ("GetStats", AccessRole::Reader),
+    ("UnaryEcho", AccessRole::Reader),
+    ("ServerStreamingEcho", AccessRole::Reader),
+    ("ClientStreamingEcho", AccessRole::Reader),
+    ("BidirectionalStreamingEcho", AccessRole::Reader),
+];
+
+/// Roles, each allowed what the previous ones are
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AccessRole {
+    Reader,
+    Writer,
+    Admin,
+}
+
+impl FromStr for AccessRole {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role.trim().to_lowercase().as_str() {
+            "reader" => Ok(AccessRole::Reader),
+            "writer" => Ok(AccessRole::Writer),
+            "admin" => Ok(AccessRole::Admin),
+            _ => Err(format!("unknown role `{}`", role.trim())),
+        }
+    }
+}
+
+/// A role on the keys starting with `prefix`; every key if empty
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub role: AccessRole,
+    pub prefix: String,
+}
+
+/// Grants by token id, and the least role of each RPC
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    grants: HashMap<String, Vec<Grant>>,
+    rpc_roles: HashMap<String, AccessRole>,
+}
+
+impl Policy {
+    /// Parse `grants`, comma separated `token_id=role` or `token_id=role@prefix`,
+    /// and `rpc_roles`, comma separated `Rpc=role` overriding the defaults
+    pub fn parse(grants: &str, rpc_roles: &str) -> Result<Self, String> {
+        let mut policy = Self {
+            grants: HashMap::new(),
+            rpc_roles: DEFAULT_RPC_ROLES
+                .iter()
+                .map(|(rpc, role)| (rpc.to_string(), *role))
+                .collect(),
+        };
+
+        for entry in entries(grants) {
+            let (token_id, grant) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("`{}` is not token_id=role@prefix", entry))?;
+            let (role, prefix) = grant.split_once('@').unwrap_or((grant, ""));
+            policy
+                .grants
+                .entry(token_id.trim().to_owned())
+                .or_default()
+                .push(Grant {
+                    role: role.parse()?,
+                    prefix: prefix.trim().to_owned(),
+                });
+        }
+
+        for entry in entries(rpc_roles) {
+            let (rpc, role) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("`{}` is not Rpc=role", entry))?;
+            policy
+                .rpc_roles
+                .insert(rpc.trim().to_owned(), role.parse()?);
+        }
+
+        Ok(policy)
+    }
+
+    /// Authorization is off without grants
+    pub fn is_enabled(&self) -> bool {
+        !self.grants.is_empty()
+    }
+
+    /// Whether `token_id` may call `rpc`, on `key` if the RPC takes one
+    pub fn is_allowed(&self, token_id: &str, rpc: &str, key: Option<&str>) -> bool {
+        let required = self
+            .rpc_roles
+            .get(rpc)
+            .copied()
+            .unwrap_or(AccessRole::Admin);
+
+        self.grants
+            .get(token_id)
+            .into_iter()
+            .flatten()
+            .any(|grant| {
+                grant.role >= required && key.map_or(true, |key| key.starts_with(&grant.prefix))
+            })
+    }
+}
+
+fn entries(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+/// How often to check "AUTH_GRANTS" and "AUTH_RPC_ROLES" for changes
+const POLICY_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Checks of the role of callers, before their RPC runs. With `watch_settings`,
+/// the policy follows edits of env.toml.
+#[derive(Debug, Clone, Default)]
+pub struct Authorizer {
+    policy: Arc<RwLock<Arc<Policy>>>,
+}
+
+impl Authorizer {
+    /// Authorizer of a fixed `policy`
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            policy: Arc::new(RwLock::new(Arc::new(policy))),
+        }
+    }
+
+    /// Authorizer of "AUTH_GRANTS" and "AUTH_RPC_ROLES"
+    pub async fn from_settings() -> crate::Result<Self> {
+        let (grants, rpc_roles) = Self::settings().await;
+        Ok(Self::new(Self::parse(&grants, &rpc_roles)?))
+    }
+
+    async fn settings() -> (String, String) {
+        (
+            Settings::get_config_item("AUTH_GRANTS")
+                .await
+                .unwrap_or_default(),
+            Settings::get_config_item("AUTH_RPC_ROLES")
+                .await
+                .unwrap_or_default(),
+        )
+    }
+
+    fn parse(grants: &str, rpc_roles: &str) -> crate::Result<Policy> {
+        Policy::parse(grants, rpc_roles).map_err(|reason| AppError::InvalidSetting {
+            key: "AUTH_GRANTS".to_owned(),
+            value: reason,
+        })
+    }
+
+    /// Current policy
+    pub fn policy(&self) -> Arc<Policy> {
+        self.policy
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Reload "AUTH_GRANTS" and "AUTH_RPC_ROLES" when env.toml changes. An invalid
+    /// edit keeps the current policy.
+    pub async fn watch_settings(self) {
+        let mut loaded = Self::settings().await;
+        let mut interval = tokio::time::interval(POLICY_RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            let settings = Self::settings().await;
+            if settings == loaded {
+                continue;
+            }
+            // logged once, until the next edit
+            match Self::parse(&settings.0, &settings.1) {
+                Ok(policy) => {
+                    info!("{}", "Reloaded authorization policy".blue());
+                    *self
+                        .policy
+                        .write()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(policy);
+                }
+                Err(err) => error!(error = format!("{:?}", err)),
+            }
+            loaded = settings;
+        }
+    }
+
+    /// Reject `caller`, from the extensions of its request, unless its role allows
+    /// `rpc`, on `key`
+    pub fn check(
+        &self,
+        caller: Option<&Caller>,
+        rpc: &str,
+        key: Option<&str>,
+    ) -> Result<(), Status> {
+        let policy = self.policy();
+        if !policy.is_enabled() {
+            return Ok(());
+        }
+
+        let token_id = match caller {
+            Some(caller) => caller.token_id.as_str(),
+            None => return Err(Status::permission_denied("caller is not authenticated")),
+        };
+        match policy.is_allowed(token_id, rpc, key) {
+            true => Ok(()),
+            false => Err(Status::permission_denied(match key {
+                Some(key) => format!("`{}` may not call {} on `{}`", token_id, rpc, key),
+                None => format!("`{}` may not call {}", token_id, rpc),
+            })),
+        }
+    }
+}
+
+#[test]
+fn test_policy() {
+    let policy = Policy::parse(
+        "ci=writer@app/, ci=reader, ops=admin, dash=reader@metrics/",
+        "UnaryEcho=writer",
+    )
+    .unwrap();
+
+    assert!(policy.is_allowed("ci", "SetValue", Some("app/a")));
+    assert!(!policy.is_allowed("ci", "SetValue", Some("metrics/a")));
+    assert!(policy.is_allowed("ci", "GetValue", Some("metrics/a")));
+    assert!(policy.is_allowed("dash", "GetValue", Some("metrics/a")));
+    assert!(!policy.is_allowed("dash", "GetValue", Some("app/a")));
+    assert!(!policy.is_allowed("dash", "UnaryEcho", None));
+    assert!(policy.is_allowed("ops", "Anything", Some("a")));
+    assert!(!policy.is_allowed("unknown", "GetValue", Some("a")));
+
+    assert!(!Policy::parse("", "").unwrap().is_enabled());
+    assert!(Policy::parse("ci=owner", "").is_err());
+}
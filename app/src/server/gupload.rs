@@ -0,0 +1,377 @@
+use super::read_only;
+use crate::{
+    models::{files::MAX_SHARD_SIZE, FileEntry, FileStore, FileWriter},
+    protobuffer::gupload::{
+        chunk::Data, gupload_service_server::GuploadService, health_check_response::ServingStatus,
+        stat_request::Target, BlobStat, Chunk, DeleteFileRequest, FileInfo, FileRequest,
+        FileResponse, HealthCheckRequest, HealthCheckResponse, ListFilesRequest, ListFilesResponse,
+        StatFileRequest, StatRequest, StatusCode, UploadOffsetRequest, UploadOffsetResponse,
+        UploadStatus,
+    },
+    replicas::{now_millis, Replication},
+    AppError,
+};
+use colored::*;
+use derive_builder::*;
+use std::pin::Pin;
+use tokio::{io::AsyncReadExt, sync::mpsc};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{error, info, instrument, warn};
+
+/// Gupload Server: upload and download of files
+#[cfg_attr(feature = "server", derive(Debug, Builder))]
+#[builder(pattern = "owned")]
+pub struct GuploadServer {
+    files: FileStore,
+    #[builder(default)]
+    replication: Replication,
+}
+
+type FileStream = Pin<Box<dyn Stream<Item = Result<FileResponse, Status>> + Send>>;
+type GuploadResult<T> = Result<Response<T>, Status>;
+
+fn to_status(err: AppError) -> Status {
+    match err {
+        AppError::InvalidArgument { ref field, .. } if field == "offset" => {
+            Status::out_of_range(err.to_string())
+        }
+        AppError::InvalidArgument { .. } => Status::invalid_argument(err.to_string()),
+        AppError::FileNotFound(_)
+        | AppError::BlobNotFound(_)
+        | AppError::UploadSessionNotFound(_) => Status::not_found(err.to_string()),
+        err => {
+            error!(error = format!("{:?}", err));
+            Status::internal(err.to_string())
+        }
+    }
+}
+
+impl From<FileEntry> for FileInfo {
+    fn from(entry: FileEntry) -> Self {
+        Self {
+            filename: entry.filename,
+            size: entry.size,
+            file_type: entry.file_type,
+            uploaded_at: entry.uploaded_at_ms,
+            sha256: entry.sha256,
+        }
+    }
+}
+
+impl GuploadServer {
+    /// followers are read-only
+    fn check_writable(&self) -> Result<(), Status> {
+        match self.replication.leader() {
+            Some(leader) => Err(read_only(leader)),
+            None => Ok(()),
+        }
+    }
+
+    /// Info of a file, with its content type sniffed if not uploaded with one
+    async fn file_info(&self, entry: FileEntry) -> FileInfo {
+        let file_type = self.files.blobs().content_type(&entry).await;
+        FileInfo {
+            file_type,
+            ..FileInfo::from(entry)
+        }
+    }
+}
+
+fn failed(message: impl ToString) -> Response<UploadStatus> {
+    let message = message.to_string();
+    warn!(
+        message = "upload failed".yellow().to_string(),
+        reason = message
+    );
+    Response::new(UploadStatus {
+        message,
+        code: StatusCode::Failed as i32,
+        ..Default::default()
+    })
+}
+
+/// Upload stopped at `writer`'s offset, to resume from, unless discarded
+fn rejected(writer: &FileWriter, err: AppError) -> Response<UploadStatus> {
+    let code = match err {
+        AppError::ChecksumMismatch { .. } => StatusCode::Corrupted,
+        AppError::UploadOutOfOrder { .. } => StatusCode::OutOfOrder,
+        _ => StatusCode::Failed,
+    };
+    warn!(
+        message = "upload rejected".yellow().to_string(),
+        session_id = writer.session().id,
+        offset = writer.offset(),
+        reason = err.to_string()
+    );
+
+    Response::new(UploadStatus {
+        message: err.to_string(),
+        code: code as i32,
+        session_id: writer.session().id.clone(),
+        offset: writer.offset(),
+        ..Default::default()
+    })
+}
+
+#[tonic::async_trait]
+impl GuploadService for GuploadServer {
+    type DownloadStream = FileStream;
+
+    /// The first chunk carries the file info, the following ones its content, in
+    /// checksummed parts. A file of unknown size ends with a file info carrying its
+    /// sha256. With a session id, the upload resumes from the uploaded offset.
+    #[instrument(skip(self, req))]
+    async fn upload(&self, req: Request<Streaming<Chunk>>) -> GuploadResult<UploadStatus> {
+        self.check_writable()?;
+        let mut stream = req.into_inner();
+
+        let info = match stream.message().await? {
+            Some(Chunk {
+                data: Some(Data::Info(info)),
+            }) => info,
+            _ => return Ok(failed("the first chunk must carry the file info")),
+        };
+
+        let writer = match info.session_id.as_str() {
+            "" => {
+                let size = Some(info.size).filter(|size| *size > 0);
+                self.files
+                    .begin(&info.filename, &info.file_type, size, &info.sha256)
+                    .await
+            }
+            session_id => self.files.resume(session_id).await,
+        };
+        let mut writer = match writer {
+            Ok(writer) => writer,
+            Err(err) => return Ok(failed(err)),
+        };
+        if !info.filename.is_empty() && info.filename != writer.session().filename {
+            return Ok(failed(format!(
+                "session {} uploads {}",
+                writer.session().id,
+                writer.session().filename
+            )));
+        }
+
+        // streamed to disk, chunk by chunk. On a broken stream, the session is kept
+        while let Some(chunk) = stream.message().await? {
+            let written = match chunk.data {
+                // neither offset nor checksum to verify
+                Some(Data::Content(_)) => Err(AppError::InvalidArgument {
+                    field: "chunk".to_owned(),
+                    reason: "content must be sent in parts, with an offset and a sha256".to_owned(),
+                }),
+                Some(Data::Part(part)) => {
+                    writer
+                        .write_chunk(part.offset, &part.content, &part.sha256)
+                        .await
+                }
+                // the checksum of a file of unknown size, once read
+                Some(Data::Info(info)) if !info.sha256.is_empty() => {
+                    writer.expect_sha256(&info.sha256).await
+                }
+                Some(Data::Info(_)) | None => Err(AppError::InvalidArgument {
+                    field: "chunk".to_owned(),
+                    reason: "expected content, got another file info".to_owned(),
+                }),
+            };
+            if let Err(err) = written {
+                return Ok(rejected(&writer, err));
+            }
+        }
+
+        if !writer.is_complete() {
+            return Ok(Response::new(UploadStatus {
+                message: format!("{} is incomplete, resume the session", info.filename),
+                code: StatusCode::Incomplete as i32,
+                session_id: writer.session().id.clone(),
+                offset: writer.offset(),
+                ..Default::default()
+            }));
+        }
+
+        let session = writer.session().clone();
+        match writer.commit().await {
+            Ok(entry) => {
+                info!(
+                    message = "file uploaded".green().to_string(),
+                    filename = entry.filename,
+                    file_type = entry.file_type,
+                    size = entry.size,
+                    sha256 = entry.sha256
+                );
+                Ok(Response::new(UploadStatus {
+                    message: format!("{} uploaded, {} bytes", entry.filename, entry.size),
+                    code: StatusCode::Ok as i32,
+                    session_id: session.id,
+                    offset: entry.size,
+                    sha256: entry.sha256,
+                }))
+            }
+            Err(err @ AppError::ChecksumMismatch { .. }) => Ok(Response::new(UploadStatus {
+                message: format!("{}, upload discarded", err),
+                code: StatusCode::Corrupted as i32,
+                session_id: session.id,
+                ..Default::default()
+            })),
+            Err(err) => Ok(failed(err)),
+        }
+    }
+
+    #[instrument(skip(self, req))]
+    async fn download(&self, req: Request<FileRequest>) -> GuploadResult<Self::DownloadStream> {
+        let req = req.into_inner();
+        let (_, mut file) = self
+            .files
+            .open_range(&req.filename, req.offset, req.length)
+            .await
+            .map_err(to_status)?;
+        let shard_size = match req.shard_size as usize {
+            0 => self.files.shard_size(),
+            shard_size => shard_size.min(MAX_SHARD_SIZE),
+        };
+        let filename = req.filename;
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            let mut buffer = vec![0; shard_size];
+            loop {
+                // full shards, but the last one
+                let mut filled = 0;
+                let mut failed = None;
+                while filled < shard_size {
+                    match file.read(&mut buffer[filled..]).await {
+                        Ok(0) => break,
+                        Ok(read) => filled += read,
+                        Err(err) => {
+                            failed = Some(Status::internal(format!("{}: {}", filename, err)));
+                            break;
+                        }
+                    }
+                }
+
+                let item = match (failed, filled) {
+                    (Some(status), _) => Err(status),
+                    (None, 0) => break,
+                    (None, filled) => Ok(FileResponse {
+                        shard: buffer[..filled].to_vec(),
+                    }),
+                };
+
+                let is_err = item.is_err();
+                if tx.send(item).await.is_err() || is_err {
+                    // client disconnected, or read failed
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as Self::DownloadStream
+        ))
+    }
+
+    #[instrument(skip(self, _req))]
+    async fn check(&self, _req: Request<HealthCheckRequest>) -> GuploadResult<HealthCheckResponse> {
+        let status = match self.files.is_available().await {
+            true => ServingStatus::Serving,
+            false => ServingStatus::NotServing,
+        };
+
+        Ok(Response::new(HealthCheckResponse {
+            status: status as i32,
+            received_at: now_millis().to_string(),
+        }))
+    }
+
+    #[instrument(skip(self, req))]
+    async fn upload_offset(
+        &self,
+        req: Request<UploadOffsetRequest>,
+    ) -> GuploadResult<UploadOffsetResponse> {
+        let session_id = req.into_inner().session_id;
+        let (session, offset) = self.files.session(&session_id).await.map_err(to_status)?;
+
+        Ok(Response::new(UploadOffsetResponse {
+            session_id,
+            filename: session.filename,
+            offset,
+            size: session.size.unwrap_or_default(),
+        }))
+    }
+
+    #[instrument(skip(self, req))]
+    async fn list_files(&self, req: Request<ListFilesRequest>) -> GuploadResult<ListFilesResponse> {
+        let prefix = req.into_inner().prefix;
+        let mut files = Vec::new();
+        for entry in self.files.blobs().list(&prefix).await {
+            files.push(self.file_info(entry).await);
+        }
+
+        Ok(Response::new(ListFilesResponse { files }))
+    }
+
+    #[instrument(skip(self, req))]
+    async fn stat_file(&self, req: Request<StatFileRequest>) -> GuploadResult<FileInfo> {
+        let filename = req.into_inner().filename;
+        let entry = self
+            .files
+            .blobs()
+            .entry(&filename)
+            .await
+            .map_err(to_status)?;
+
+        Ok(Response::new(self.file_info(entry).await))
+    }
+
+    #[instrument(skip(self, req))]
+    async fn delete_file(&self, req: Request<DeleteFileRequest>) -> GuploadResult<FileInfo> {
+        self.check_writable()?;
+        let filename = req.into_inner().filename;
+        // sniffed before the content may be gone
+        let entry = self
+            .files
+            .blobs()
+            .entry(&filename)
+            .await
+            .map_err(to_status)?;
+        let file_type = self.files.blobs().content_type(&entry).await;
+
+        let entry = self
+            .files
+            .blobs()
+            .remove(&filename)
+            .await
+            .map_err(to_status)?;
+        info!(
+            message = "file deleted".yellow().to_string(),
+            filename = entry.filename
+        );
+
+        Ok(Response::new(FileInfo {
+            file_type,
+            ..FileInfo::from(entry)
+        }))
+    }
+
+    #[instrument(skip(self, req))]
+    async fn stat(&self, req: Request<StatRequest>) -> GuploadResult<BlobStat> {
+        let blobs = self.files.blobs();
+        let sha256 = match req.into_inner().target {
+            Some(Target::Filename(filename)) => {
+                blobs.entry(&filename).await.map_err(to_status)?.sha256
+            }
+            Some(Target::Sha256(sha256)) => sha256,
+            None => return Err(Status::invalid_argument("filename or sha256 required")),
+        };
+        let stat = blobs.stat(&sha256).await.map_err(to_status)?;
+
+        Ok(Response::new(BlobStat {
+            sha256: stat.sha256,
+            size: stat.size,
+            content_type: stat.content_type,
+            references: stat.references,
+        }))
+    }
+}
@@ -0,0 +1,142 @@
+use super::{EchoServer, GuploadServer, PaymentServer, PersonServer, ReplicationServer};
+use crate::{
+    protobuffer::{
+        echo_server, gupload::gupload_service_server, payments::bitcoin_server,
+        person::person_service_server, replication::replication_server,
+    },
+    Connection, InMemoryDatabase,
+};
+use colored::*;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use tonic::server::NamedService;
+use tonic_health::{server::HealthReporter, ServingStatus};
+use tracing::{error, info, warn};
+
+/// Services reported by grpc.health.v1, besides the server as a whole (""). Named
+/// after the served types, so that a renamed service cannot go unreported.
+pub const HEALTH_SERVICES: [&str; 5] = [
+    <echo_server::EchoServer<EchoServer<InMemoryDatabase>> as NamedService>::NAME,
+    <bitcoin_server::BitcoinServer<PaymentServer<InMemoryDatabase>> as NamedService>::NAME,
+    <gupload_service_server::GuploadServiceServer<GuploadServer> as NamedService>::NAME,
+    <person_service_server::PersonServiceServer<PersonServer<InMemoryDatabase>> as NamedService>::NAME,
+    <replication_server::ReplicationServer<ReplicationServer<InMemoryDatabase>> as NamedService>::NAME,
+];
+
+/// Interval of database health checks
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct State {
+    shutting_down: bool,
+    /// last reported status
+    status: Option<ServingStatus>,
+}
+
+/// grpc.health.v1 status of every service: SERVING while the database is healthy,
+/// NOT_SERVING otherwise, and from shutdown on
+#[derive(Clone)]
+pub struct HealthMonitor {
+    reporter: HealthReporter,
+    /// held while reporting, so that no check reports after shutdown
+    state: Arc<Mutex<State>>,
+}
+
+impl HealthMonitor {
+    pub fn new(reporter: HealthReporter) -> Self {
+        Self {
+            reporter,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Last reported status, if any
+    pub async fn status(&self) -> Option<ServingStatus> {
+        self.state.lock().await.status
+    }
+
+    async fn set_status(&mut self, status: ServingStatus) {
+        self.reporter.set_service_status("", status).await;
+        for service in HEALTH_SERVICES {
+            self.reporter.set_service_status(service, status).await;
+        }
+    }
+
+    /// Report `status` of the database, if it changed. Returns false from shutdown on.
+    async fn report(&mut self, status: ServingStatus) -> bool {
+        let state = self.state.clone();
+        let mut state = state.lock().await;
+        if state.shutting_down {
+            return false;
+        }
+
+        if state.status != Some(status) {
+            match status {
+                ServingStatus::Serving => info!("{}", "health: SERVING".green()),
+                _ => warn!("{}", "health: NOT_SERVING, database is unhealthy".yellow()),
+            }
+            self.set_status(status).await;
+            state.status = Some(status);
+        }
+        true
+    }
+
+    /// Check the database health periodically, until shutdown
+    pub async fn watch_database<C>(mut self, connection: C)
+    where
+        C: Connection<Output = InMemoryDatabase> + Send + Sync,
+    {
+        let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let status = match connection.get_db().db.health().await {
+                Ok(_) => ServingStatus::Serving,
+                Err(err) => {
+                    error!(error = format!("{:?}", err));
+                    ServingStatus::NotServing
+                }
+            };
+
+            if !self.report(status).await {
+                return;
+            }
+        }
+    }
+
+    /// Report NOT_SERVING from now on, e.g. while draining connections
+    pub async fn shutdown(mut self) {
+        let state = self.state.clone();
+        let mut state = state.lock().await;
+        state.shutting_down = true;
+
+        warn!("{}", "health: NOT_SERVING, shutting down".yellow());
+        self.set_status(ServingStatus::NotServing).await;
+        state.status = Some(ServingStatus::NotServing);
+    }
+}
+
+#[tokio::test]
+async fn test_health_transitions() {
+    let (reporter, _) = tonic_health::server::health_reporter();
+    let mut health = HealthMonitor::new(reporter);
+
+    assert!(health.report(ServingStatus::Serving).await);
+    // database down, then up again
+    assert!(health.report(ServingStatus::NotServing).await);
+    assert_eq!(health.status().await, Some(ServingStatus::NotServing));
+    assert!(health.report(ServingStatus::Serving).await);
+    assert_eq!(health.status().await, Some(ServingStatus::Serving));
+
+    health.clone().shutdown().await;
+    // a check finishing after shutdown does not report SERVING again
+    assert!(!health.report(ServingStatus::Serving).await);
+    assert_eq!(health.status().await, Some(ServingStatus::NotServing));
+}
+
+#[test]
+fn test_health_services() {
+    assert!(HEALTH_SERVICES.contains(&"gupload.GuploadService"));
+    assert!(HEALTH_SERVICES.contains(&"echo.Echo"));
+}
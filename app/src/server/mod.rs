@@ -5,12 +5,40 @@
 mod setup_logging;
 pub use setup_logging::{set_up_logging, shutdown_tracer_provider};
 
+mod replication;
+pub use replication::{ReplicationServer, ReplicationServerBuilder};
+
+mod person;
+pub use person::{PersonServer, PersonServerBuilder};
+
+mod gupload;
+pub use gupload::{GuploadServer, GuploadServerBuilder};
+
+mod payments;
+pub use payments::{payments_admin_enabled, PaymentServer, PaymentServerBuilder};
+
+mod reflection;
+pub use reflection::{reflection_enabled, reflection_service};
+
+mod health;
+pub use health::{HealthMonitor, HEALTH_SERVICES};
+
+mod auth;
+pub use auth::{hash_token, Authenticator, Caller, TokenStore, API_KEY_METADATA_KEY};
+
+mod authz;
+pub use authz::{AccessRole, Authorizer, Grant, Policy};
+
 // NOTE:
 // https://github.com/open-telemetry/opentelemetry-rust/blob/main/examples/tracing-grpc/src/server.rs
 use crate::{
     cmd::{Get, Ping, Set},
     models::PersonRepository,
-    protobuffer::{self, EchoRequest, EchoResponse, KeyValueRequest, KeyValueResponse},
+    protobuffer::{
+        self, EchoRequest, EchoResponse, KeyValueRequest, KeyValueResponse, StatsRequest,
+        StatsResponse,
+    },
+    replicas::{Replication, LEADER_METADATA_KEY},
     Connection, InMemoryDatabase,
 };
 use colored::*;
@@ -70,6 +98,35 @@ fn match_for_io_error(err_status: &Status) -> Option<&std::io::Error> {
     }
 }
 
+/// Rejection of a request sent to a follower, pointing the client to the leader
+fn to_leader(message: String, leader: &str) -> Status {
+    let mut status = Status::failed_precondition(message);
+    if let Ok(leader) = leader.parse() {
+        status.metadata_mut().insert(LEADER_METADATA_KEY, leader);
+    }
+    status
+}
+
+/// Rejection of a write sent to a read-only follower, pointing the client to the leader
+pub(crate) fn read_only(leader: &str) -> Status {
+    to_leader(
+        format!("read-only follower, send writes to leader at {}", leader),
+        leader,
+    )
+}
+
+/// Rejection of a read of `records` a follower does not replicate, which would be
+/// empty or stale, pointing the client to the leader
+pub(crate) fn not_replicated(records: &str, leader: &str) -> Status {
+    to_leader(
+        format!(
+            "{} are not replicated, send reads to leader at {}",
+            records, leader
+        ),
+        leader,
+    )
+}
+
 /// Simply Echo Server
 #[cfg_attr(feature = "server", derive(Debug, Builder))]
 #[builder(pattern = "owned")]
@@ -78,6 +135,10 @@ pub struct EchoServer<
 > {
     person: PersonRepository,
     connection: C,
+    #[builder(default)]
+    replication: Replication,
+    #[builder(default)]
+    authz: Authorizer,
 }
 
 type ResponseStream = Pin<Box<dyn Stream<Item = Result<EchoResponse, Status>> + Send>>;
@@ -116,6 +177,8 @@ where
         Self::inject_context(&req);
 
         info!(message = "get_value".blue().to_string());
+        self.authz
+            .check(req.extensions().get(), "GetValue", Some(&req.get_ref().key))?;
 
         let key_value_request = req.into_inner();
         let key = key_value_request.key;
@@ -138,12 +201,21 @@ where
         Self::inject_context(&req);
 
         info!(message = "set_value".blue().to_string());
+        self.authz
+            .check(req.extensions().get(), "SetValue", Some(&req.get_ref().key))?;
+
+        // followers are read-only; point the client to the leader
+        if let Some(leader) = self.replication.leader() {
+            return Err(read_only(leader));
+        }
 
         let key_value_request = req.into_inner();
         let key = key_value_request.key;
         let value = key_value_request.value.unwrap();
-        let cmd = Set::new(key, value);
+        let ttl = key_value_request.ttl.map(Duration::from_secs);
+        let cmd = Set::new(&key, &value).with_ttl(ttl);
 
+        // published to the followers by the repository
         match cmd.apply(&self.person, &self.connection).await {
             Ok(_) => Ok(Response::new(KeyValueResponse {
                 status: "Ok".to_owned(),
@@ -156,9 +228,30 @@ where
         }
     }
 
+    #[instrument(skip(self, req), name = "recv_get_stats_request")]
+    async fn get_stats(&self, req: Request<StatsRequest>) -> EchoResult<StatsResponse> {
+        self.authz.check(req.extensions().get(), "GetStats", None)?;
+
+        let memory = self.person.memory_stats();
+        let compression = self.person.compression_stats();
+        Ok(Response::new(StatsResponse {
+            used_memory: memory.used_memory as u64,
+            max_memory: memory.max_memory as u64,
+            keys: memory.keys as u64,
+            evicted_keys: memory.evicted_keys,
+            compressed_values: compression.compressed_values,
+            incompressible_values: compression.incompressible_values,
+            raw_bytes: compression.raw_bytes,
+            stored_bytes: compression.stored_bytes,
+            compression_ratio: compression.ratio(),
+        }))
+    }
+
     #[instrument(skip(self, req))]
     async fn unary_echo(&self, req: Request<EchoRequest>) -> EchoResult<EchoResponse> {
         info!(message = "unary_echo".blue().to_string());
+        self.authz
+            .check(req.extensions().get(), "UnaryEcho", None)?;
         info!(message = format!("{:?}", req.remote_addr().unwrap()));
 
         let message = req.into_inner().message;
@@ -178,6 +271,8 @@ where
         req: Request<Streaming<EchoRequest>>,
     ) -> EchoResult<EchoResponse> {
         info!(messsage = "client_streaming_echo".blue().to_string());
+        self.authz
+            .check(req.extensions().get(), "ClientStreamingEcho", None)?;
         info!(message = format!("{:?}", req.remote_addr().unwrap()));
 
         let mut in_stream = req.into_inner();
@@ -230,6 +325,8 @@ where
         req: Request<EchoRequest>,
     ) -> EchoResult<Self::ServerStreamingEchoStream> {
         info!(messsage = "server_streaming_echo".blue().to_string());
+        self.authz
+            .check(req.extensions().get(), "ServerStreamingEcho", None)?;
         info!(message = format!("{:?}", req.remote_addr().unwrap()));
 
         // TODO: It should change to other implementation of streamed response
@@ -277,6 +374,8 @@ where
         req: Request<Streaming<EchoRequest>>,
     ) -> EchoResult<Self::BidirectionalStreamingEchoStream> {
         info!(message = "bidirectional_streaming_echo".blue().to_string());
+        self.authz
+            .check(req.extensions().get(), "BidirectionalStreamingEcho", None)?;
         info!(message = format!("{:?}", req.remote_addr().unwrap()));
 
         let mut in_stream = req.into_inner();
@@ -0,0 +1,298 @@
+use super::{not_replicated, Authorizer};
+use crate::{
+    models::{self, ledger::DEFAULT_PAYMENT_PAGE_SIZE, Ledger, PaymentCursor, PaymentFilter},
+    protobuffer::payments::{
+        bitcoin_server::Bitcoin, ledger_admin_server::LedgerAdmin, Account, BTCPaymentRequest,
+        BTCPaymentResponse, GetBalanceRequest, GetTransactionRequest, ListTransactionsRequest,
+        ListTransactionsResponse, ListUtxosRequest, ListUtxosResponse, SeedBalanceRequest,
+        Transaction, Utxo,
+    },
+    replicas::Replication,
+    AppError, Connection, InMemoryDatabase, Settings,
+};
+use colored::*;
+use derive_builder::*;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, instrument};
+
+/// Payments Server: bitcoin payments, against a simulated ledger
+#[cfg_attr(feature = "server", derive(Debug, Builder))]
+#[builder(pattern = "owned")]
+pub struct PaymentServer<
+    C: Connection<Output = InMemoryDatabase> + Sync + Send + std::fmt::Debug + 'static,
+> {
+    ledger: Ledger,
+    connection: C,
+    #[builder(default)]
+    replication: Replication,
+    /// seeding balances needs an admin token
+    #[builder(default)]
+    authz: Authorizer,
+}
+
+type PaymentResult<T> = Result<Response<T>, Status>;
+
+impl From<models::Account> for Account {
+    fn from(account: models::Account) -> Self {
+        Self {
+            address: account.address,
+            balance: account.balance.max(0) as u64,
+            unconfirmed_balance: account.unconfirmed_balance.max(0) as u64,
+        }
+    }
+}
+
+/// `payment`, confirmed up to block `tip`
+fn transaction(payment: models::Payment, tip: i64) -> Transaction {
+    Transaction {
+        confirmations: models::utxo::confirmations(payment.height, tip),
+        id: payment.id,
+        from_add: payment.from_address,
+        to_add: payment.to_address,
+        amount: payment.amount.max(0) as u64,
+        created_at: payment.created_at_ms,
+        fee: payment.fee.max(0) as u64,
+        change: payment.change.max(0) as u64,
+        inputs: payment.inputs,
+        block_height: payment.height,
+    }
+}
+
+/// `utxo`, confirmed up to block `tip`
+fn utxo(utxo: models::Utxo, tip: i64) -> Utxo {
+    Utxo {
+        confirmations: utxo.confirmations(tip),
+        txid: utxo.txid,
+        vout: utxo.vout.max(0) as u32,
+        address: utxo.address,
+        amount: utxo.amount.max(0) as u64,
+        block_height: utxo.height,
+        spent_by: utxo.spent_by,
+    }
+}
+
+fn to_status(err: AppError) -> Status {
+    match err {
+        AppError::InvalidArgument { .. } => Status::invalid_argument(err.to_string()),
+        AppError::PaymentNotFound(_) => Status::not_found(err.to_string()),
+        err => {
+            error!(error = format!("{:?}", err));
+            Status::internal(err.to_string())
+        }
+    }
+}
+
+/// Whether the LedgerAdmin service is served, from PAYMENTS_ADMIN
+pub async fn payments_admin_enabled() -> bool {
+    matches!(
+        Settings::get_config_item("PAYMENTS_ADMIN").await.as_deref(),
+        Some("true")
+    )
+}
+
+impl<C: Connection<Output = InMemoryDatabase> + Sync + Send + std::fmt::Debug + 'static>
+    PaymentServer<C>
+{
+    /// the ledger is not replicated: followers refuse reads, as well as writes
+    fn check_leader(&self) -> Result<(), Status> {
+        match self.replication.leader() {
+            Some(leader) => Err(not_replicated("payments", leader)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<C> Bitcoin for PaymentServer<C>
+where
+    C: Connection<Output = InMemoryDatabase> + Sync + Send + std::fmt::Debug + 'static,
+{
+    #[instrument(skip(self, req), name = "recv_send_payment_request")]
+    async fn send_payment(
+        &self,
+        req: Request<BTCPaymentRequest>,
+    ) -> PaymentResult<BTCPaymentResponse> {
+        info!(message = "send_payment".blue().to_string());
+        self.check_leader()?;
+
+        let request = req.into_inner();
+        let outcome = self
+            .ledger
+            .send(
+                &self.connection,
+                &request.from_add,
+                &request.to_add,
+                request.amount.into(),
+                &request.idempotency_key,
+            )
+            .await;
+
+        // a rejected payment is a response, telling why; not an rpc error
+        match outcome {
+            Ok(outcome) => Ok(Response::new(BTCPaymentResponse {
+                successful: outcome.successful,
+                message: outcome.message,
+                payment_id: outcome.payment_id,
+                fee: outcome.fee.max(0) as u64,
+            })),
+            Err(err @ AppError::InvalidArgument { .. }) => Ok(Response::new(BTCPaymentResponse {
+                successful: false,
+                message: err.to_string(),
+                ..Default::default()
+            })),
+            Err(err) => Err(to_status(err)),
+        }
+    }
+
+    #[instrument(skip(self, req), name = "recv_get_balance_request")]
+    async fn get_balance(&self, req: Request<GetBalanceRequest>) -> PaymentResult<Account> {
+        info!(message = "get_balance".blue().to_string());
+        self.check_leader()?;
+
+        let address = self
+            .ledger
+            .check_address("address", &req.into_inner().address)
+            .map_err(to_status)?;
+        match self.ledger.account(&self.connection, &address).await {
+            Ok(account) => Ok(Response::new(account.into())),
+            Err(err) => Err(to_status(err)),
+        }
+    }
+
+    #[instrument(skip(self, req), name = "recv_list_transactions_request")]
+    async fn list_transactions(
+        &self,
+        req: Request<ListTransactionsRequest>,
+    ) -> PaymentResult<ListTransactionsResponse> {
+        info!(message = "list_transactions".blue().to_string());
+        self.check_leader()?;
+
+        let request = req.into_inner();
+        let filter = PaymentFilter {
+            address: request.address,
+            after_ms: request.after,
+            before_ms: request.before,
+        };
+
+        // the page token is the cursor after the previous page
+        let cursor = match request.page_token.as_str() {
+            "" => None,
+            token => Some(token.parse::<PaymentCursor>().map_err(to_status)?),
+        };
+        let limit = match request.page_size {
+            0 => DEFAULT_PAYMENT_PAGE_SIZE,
+            page_size => page_size as usize,
+        };
+
+        let page = self
+            .ledger
+            .list_payments(&self.connection, &filter, cursor.as_ref(), limit)
+            .await
+            .map_err(to_status)?;
+        let tip = self.ledger.tip(&self.connection).await.map_err(to_status)?;
+
+        Ok(Response::new(ListTransactionsResponse {
+            transactions: page
+                .items
+                .into_iter()
+                .map(|payment| transaction(payment, tip))
+                .collect(),
+            next_page_token: page
+                .next
+                .map(|cursor| cursor.to_string())
+                .unwrap_or_default(),
+        }))
+    }
+
+    #[instrument(skip(self, req), name = "recv_get_transaction_request")]
+    async fn get_transaction(
+        &self,
+        req: Request<GetTransactionRequest>,
+    ) -> PaymentResult<Transaction> {
+        info!(message = "get_transaction".blue().to_string());
+        self.check_leader()?;
+
+        let id = req.into_inner().id;
+        let payment = self
+            .ledger
+            .payment(&self.connection, &id)
+            .await
+            .map_err(to_status)?;
+        let tip = self.ledger.tip(&self.connection).await.map_err(to_status)?;
+
+        Ok(Response::new(transaction(payment, tip)))
+    }
+
+    #[instrument(skip(self, req), name = "recv_list_utxos_request")]
+    async fn list_utxos(&self, req: Request<ListUtxosRequest>) -> PaymentResult<ListUtxosResponse> {
+        info!(message = "list_utxos".blue().to_string());
+        self.check_leader()?;
+
+        let request = req.into_inner();
+        let address = self
+            .ledger
+            .check_address("address", &request.address)
+            .map_err(to_status)?;
+        let utxos = self
+            .ledger
+            .utxos(&self.connection, &address, request.include_spent)
+            .await
+            .map_err(to_status)?;
+        let tip = self.ledger.tip(&self.connection).await.map_err(to_status)?;
+
+        Ok(Response::new(ListUtxosResponse {
+            utxos: utxos.into_iter().map(|output| utxo(output, tip)).collect(),
+            tip_height: tip,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl<C> LedgerAdmin for PaymentServer<C>
+where
+    C: Connection<Output = InMemoryDatabase> + Sync + Send + std::fmt::Debug + 'static,
+{
+    #[instrument(skip(self, req), name = "recv_seed_balance_request")]
+    async fn seed_balance(&self, req: Request<SeedBalanceRequest>) -> PaymentResult<Account> {
+        info!(message = "seed_balance".blue().to_string());
+        self.authz
+            .check(req.extensions().get(), "SeedBalance", None)?;
+        self.check_leader()?;
+
+        let request = req.into_inner();
+        match self
+            .ledger
+            .seed(&self.connection, &request.address, request.balance)
+            .await
+        {
+            Ok(account) => Ok(Response::new(account.into())),
+            Err(err) => Err(to_status(err)),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_followers_refuse_reads() {
+    use crate::{
+        models::Network,
+        replicas::{Role, LEADER_METADATA_KEY},
+    };
+
+    let server = PaymentServerBuilder::default()
+        .ledger(Ledger::new(Network::Regtest))
+        .connection(<InMemoryDatabase as Connection>::new().await.unwrap())
+        .replication(Replication::new(Role::Follower {
+            leader: "http://127.0.0.1:50051".to_owned(),
+        }))
+        .build()
+        .unwrap();
+
+    let status = server
+        .get_balance(Request::new(GetBalanceRequest {
+            address: "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r".to_owned(),
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    assert!(status.metadata().get(LEADER_METADATA_KEY).is_some());
+}
@@ -0,0 +1,213 @@
+use super::not_replicated;
+use crate::{
+    models::{self, PersonFilter, PersonRepository, PersonStore, DEFAULT_PAGE_SIZE},
+    protobuffer::person::{
+        person_service_server::PersonService, CreatePersonRequest, DeletePersonRequest,
+        GetPersonRequest, ListPersonsRequest, ListPersonsResponse, Person, UpdatePersonRequest,
+    },
+    replicas::Replication,
+    AppError, Connection, InMemoryDatabase,
+};
+use colored::*;
+use derive_builder::*;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, instrument};
+
+/// Person Server: CRUD of person records
+#[cfg_attr(feature = "server", derive(Debug, Builder))]
+#[builder(pattern = "owned")]
+pub struct PersonServer<
+    C: Connection<Output = InMemoryDatabase> + Sync + Send + std::fmt::Debug + 'static,
+> {
+    person: PersonRepository,
+    connection: C,
+    #[builder(default)]
+    replication: Replication,
+}
+
+type PersonResult<T> = Result<Response<T>, Status>;
+
+impl From<models::Person> for Person {
+    fn from(person: models::Person) -> Self {
+        Self {
+            id: person.id,
+            name: person.name,
+            email: person.email,
+            birth_date: person.birth_date,
+            tags: person.tags,
+        }
+    }
+}
+
+impl From<Person> for models::Person {
+    fn from(person: Person) -> Self {
+        Self {
+            id: person.id,
+            name: person.name,
+            email: person.email,
+            birth_date: person.birth_date,
+            tags: person.tags,
+        }
+    }
+}
+
+fn to_status(err: AppError) -> Status {
+    match err {
+        AppError::InvalidArgument { .. } => Status::invalid_argument(err.to_string()),
+        AppError::PersonNotFound(_) => Status::not_found(err.to_string()),
+        err => {
+            error!(error = format!("{:?}", err));
+            Status::internal(err.to_string())
+        }
+    }
+}
+
+impl<C: Connection<Output = InMemoryDatabase> + Sync + Send + std::fmt::Debug + 'static>
+    PersonServer<C>
+{
+    /// person records are not replicated: followers refuse reads, as well as writes
+    fn check_leader(&self) -> Result<(), Status> {
+        match self.replication.leader() {
+            Some(leader) => Err(not_replicated("person records", leader)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<C> PersonService for PersonServer<C>
+where
+    C: Connection<Output = InMemoryDatabase> + Sync + Send + std::fmt::Debug + 'static,
+{
+    #[instrument(skip(self, req), name = "recv_create_person_request")]
+    async fn create_person(&self, req: Request<CreatePersonRequest>) -> PersonResult<Person> {
+        info!(message = "create_person".blue().to_string());
+        self.check_leader()?;
+
+        let request = req.into_inner();
+        let person = models::Person {
+            id: String::new(),
+            name: request.name,
+            email: request.email,
+            birth_date: request.birth_date,
+            tags: request.tags,
+        };
+
+        match self.person.create_person(&self.connection, person).await {
+            Ok(person) => Ok(Response::new(person.into())),
+            Err(err) => Err(to_status(err)),
+        }
+    }
+
+    #[instrument(skip(self, req), name = "recv_get_person_request")]
+    async fn get_person(&self, req: Request<GetPersonRequest>) -> PersonResult<Person> {
+        info!(message = "get_person".blue().to_string());
+        self.check_leader()?;
+
+        let id = req.into_inner().id;
+        match self.person.get_person(&self.connection, &id).await {
+            Ok(person) => Ok(Response::new(person.into())),
+            Err(err) => Err(to_status(err)),
+        }
+    }
+
+    #[instrument(skip(self, req), name = "recv_update_person_request")]
+    async fn update_person(&self, req: Request<UpdatePersonRequest>) -> PersonResult<Person> {
+        info!(message = "update_person".blue().to_string());
+        self.check_leader()?;
+
+        let person = req
+            .into_inner()
+            .person
+            .ok_or_else(|| Status::invalid_argument("missing person"))?;
+
+        match self
+            .person
+            .update_person(&self.connection, person.into())
+            .await
+        {
+            Ok(person) => Ok(Response::new(person.into())),
+            Err(err) => Err(to_status(err)),
+        }
+    }
+
+    #[instrument(skip(self, req), name = "recv_delete_person_request")]
+    async fn delete_person(&self, req: Request<DeletePersonRequest>) -> PersonResult<Person> {
+        info!(message = "delete_person".blue().to_string());
+        self.check_leader()?;
+
+        let id = req.into_inner().id;
+        match self.person.delete_person(&self.connection, &id).await {
+            Ok(person) => Ok(Response::new(person.into())),
+            Err(err) => Err(to_status(err)),
+        }
+    }
+
+    #[instrument(skip(self, req), name = "recv_list_persons_request")]
+    async fn list_persons(
+        &self,
+        req: Request<ListPersonsRequest>,
+    ) -> PersonResult<ListPersonsResponse> {
+        info!(message = "list_persons".blue().to_string());
+        self.check_leader()?;
+
+        let request = req.into_inner();
+        let filter = PersonFilter {
+            name: request.name,
+            email: request.email,
+            tags: request.tags,
+            born_after: request.born_after,
+            born_before: request.born_before,
+        };
+
+        // the page token is the offset of the page
+        let offset = match request.page_token.as_str() {
+            "" => 0,
+            token => token
+                .parse::<usize>()
+                .map_err(|_| Status::invalid_argument("invalid page_token"))?,
+        };
+        let limit = match request.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            page_size => page_size as usize,
+        };
+
+        match self
+            .person
+            .list_persons(&self.connection, &filter, offset, limit)
+            .await
+        {
+            Ok(page) => Ok(Response::new(ListPersonsResponse {
+                persons: page.items.into_iter().map(Person::from).collect(),
+                next_page_token: page
+                    .next_offset
+                    .map(|offset| offset.to_string())
+                    .unwrap_or_default(),
+            })),
+            Err(err) => Err(to_status(err)),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_followers_refuse_reads() {
+    use crate::replicas::{Role, LEADER_METADATA_KEY};
+
+    let server = PersonServerBuilder::default()
+        .person(PersonRepository::default())
+        .connection(<InMemoryDatabase as Connection>::new().await.unwrap())
+        .replication(Replication::new(Role::Follower {
+            leader: "http://127.0.0.1:50051".to_owned(),
+        }))
+        .build()
+        .unwrap();
+
+    let status = server
+        .get_person(Request::new(GetPersonRequest {
+            id: "person-1".to_owned(),
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    assert!(status.metadata().get(LEADER_METADATA_KEY).is_some());
+}
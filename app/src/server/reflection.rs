@@ -0,0 +1,82 @@
+use crate::{protobuffer::FILE_DESCRIPTOR_SET, AppError, Settings};
+use tonic_reflection::server::{ServerReflection, ServerReflectionServer};
+
+/// Whether grpc reflection is served, from GRPC_REFLECTION; disable it in production
+pub async fn reflection_enabled() -> bool {
+    matches!(
+        Settings::get_config_item("GRPC_REFLECTION")
+            .await
+            .as_deref(),
+        Some("true")
+    )
+}
+
+/// grpc.reflection.v1alpha, describing every served service: ours, health and
+/// reflection itself
+pub fn reflection_service() -> crate::Result<ServerReflectionServer<impl ServerReflection>> {
+    tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_reflection::pb::FILE_DESCRIPTOR_SET)
+        .build()
+        .map_err(|err| AppError::ReflectionError(err.to_string()))
+}
+
+#[tokio::test]
+async fn test_reflection_service() {
+    use super::HEALTH_SERVICES;
+    use tonic_reflection::pb::{
+        server_reflection_client::ServerReflectionClient,
+        server_reflection_request::MessageRequest, server_reflection_response::MessageResponse,
+        ServerReflectionRequest,
+    };
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = tonic::transport::Server::builder()
+        .add_service(reflection_service().unwrap())
+        .serve_with_incoming(futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        }));
+    tokio::spawn(server);
+
+    let mut client = ServerReflectionClient::connect(format!("http://127.0.0.1:{}", port))
+        .await
+        .unwrap();
+    let symbols: Vec<String> = HEALTH_SERVICES
+        .iter()
+        .chain(&[
+            "payments.LedgerAdmin",
+            "echo.StatsResponse",
+            "grpc.health.v1.Health",
+        ])
+        .map(|symbol| symbol.to_string())
+        .collect();
+    let requests = symbols
+        .clone()
+        .into_iter()
+        .map(|symbol| ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(MessageRequest::FileContainingSymbol(symbol)),
+        });
+    let mut responses = client
+        .server_reflection_info(tokio_stream::iter(requests))
+        .await
+        .unwrap()
+        .into_inner();
+
+    for symbol in symbols {
+        let response = responses.message().await.unwrap().unwrap();
+        assert!(
+            matches!(
+                response.message_response,
+                Some(MessageResponse::FileDescriptorResponse(ref files))
+                    if !files.file_descriptor_proto.is_empty()
+            ),
+            "{} not resolved: {:?}",
+            symbol,
+            response.message_response
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use super::Authorizer;
+use crate::{
+    models::{KeyValueStore, PersonRepository},
+    protobuffer::replication::{
+        replication_event::Event, replication_server::Replication as ReplicationService, Heartbeat,
+        KeyValueEntry, ReplicationEvent, Snapshot, StatusRequest, StatusResponse, SyncRequest,
+    },
+    replicas::Replication,
+    Connection, InMemoryDatabase,
+};
+use colored::*;
+use derive_builder::*;
+use std::{pin::Pin, time::Duration};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{Request, Response, Status};
+use tracing::{info, instrument, warn};
+
+/// Interval of heartbeats sent to idle followers
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes of keys and values per snapshot batch, well below the 4 MiB decoding
+/// limit of followers. A larger value goes in a batch of its own.
+const SNAPSHOT_BATCH_SIZE: usize = 1024 * 1024;
+
+/// Split `entries` in batches of about `SNAPSHOT_BATCH_SIZE` bytes, the last one
+/// marked as such; a single empty batch for an empty key space
+fn snapshot_batches(entries: Vec<KeyValueEntry>) -> Vec<Snapshot> {
+    let mut batches = vec![Snapshot::default()];
+    let mut size = 0;
+    for entry in entries {
+        let entry_size = entry.key.len() + entry.value.len();
+        let batch = batches.last_mut().unwrap();
+        if !batch.entries.is_empty() && size + entry_size > SNAPSHOT_BATCH_SIZE {
+            batches.push(Snapshot::default());
+            size = 0;
+        }
+        size += entry_size;
+        batches.last_mut().unwrap().entries.push(entry);
+    }
+    batches.last_mut().unwrap().last = true;
+
+    batches
+}
+
+/// Replication Server: streams snapshot and writes of the leader to followers
+#[cfg_attr(feature = "server", derive(Debug, Builder))]
+#[builder(pattern = "owned")]
+pub struct ReplicationServer<
+    C: Connection<Output = InMemoryDatabase> + Sync + Send + std::fmt::Debug + 'static,
+> {
+    person: PersonRepository,
+    connection: C,
+    replication: Replication,
+    /// followers need an admin token: the stream carries every key
+    #[builder(default)]
+    authz: Authorizer,
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<ReplicationEvent, Status>> + Send>>;
+type ReplicationResult<T> = Result<Response<T>, Status>;
+
+#[tonic::async_trait]
+impl<C> ReplicationService for ReplicationServer<C>
+where
+    C: Connection<Output = InMemoryDatabase> + Sync + Send + std::fmt::Debug + 'static,
+{
+    type SyncStream = EventStream;
+
+    #[instrument(skip(self, req), name = "recv_sync_request")]
+    async fn sync(&self, req: Request<SyncRequest>) -> ReplicationResult<Self::SyncStream> {
+        self.authz.check(req.extensions().get(), "Sync", None)?;
+        if let Some(leader) = self.replication.leader() {
+            return Err(Status::failed_precondition(format!(
+                "not a leader, replicate from {}",
+                leader
+            )));
+        }
+
+        let follower_id = req.into_inner().follower_id;
+        info!(
+            message = "follower connected".green().to_string(),
+            follower_id
+        );
+
+        // subscribe before reading the snapshot, so no write falls in between
+        let (mut events, snapshot_sequence) = self.replication.subscribe();
+        let entries = match self.person.list_values(&self.connection).await {
+            Ok(values) => values
+                .into_iter()
+                .map(|kv| KeyValueEntry {
+                    expires_at_ms: kv.expires_at_ms.unwrap_or_default(),
+                    key: kv.key.into_owned(),
+                    value: kv.value.into_owned(),
+                })
+                .collect(),
+            Err(err) => return Err(Status::internal(format!("{:?}", err))),
+        };
+
+        let (tx, rx) = mpsc::channel(128);
+        let replication = self.replication.clone();
+
+        tokio::spawn(async move {
+            for batch in snapshot_batches(entries) {
+                let snapshot = replication.event(snapshot_sequence, Event::Snapshot(batch));
+                if tx.send(Ok(snapshot)).await.is_err() {
+                    return;
+                }
+            }
+
+            let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                let item = tokio::select! {
+                    received = events.recv() => match received {
+                        // already part of the snapshot
+                        Ok(event) if event.sequence <= snapshot_sequence => continue,
+                        Ok(event) => Ok(event),
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!(
+                                message = "follower lagged".yellow().to_string(),
+                                follower_id,
+                                skipped
+                            );
+                            Err(Status::data_loss(format!(
+                                "follower lagged behind by {} writes, resync required",
+                                skipped
+                            )))
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = heartbeat.tick() => Ok(replication.event(
+                        replication.sequence(),
+                        Event::Heartbeat(Heartbeat {}),
+                    )),
+                };
+
+                let is_err = item.is_err();
+                if tx.send(item).await.is_err() || is_err {
+                    // output stream was dropped, or follower has to resync
+                    break;
+                }
+            }
+            info!(
+                "{}",
+                format!("\tfollower {} disconnected", follower_id).red()
+            );
+        });
+
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as Self::SyncStream
+        ))
+    }
+
+    #[instrument(skip(self, _req), name = "recv_replication_status_request")]
+    async fn status(&self, _req: Request<StatusRequest>) -> ReplicationResult<StatusResponse> {
+        Ok(Response::new(self.replication.status()))
+    }
+}
+
+#[tokio::test]
+async fn test_sync_requires_admin() {
+    use super::{Caller, Policy};
+
+    let server = ReplicationServerBuilder::default()
+        .person(PersonRepository::default())
+        .connection(<InMemoryDatabase as Connection>::new().await.unwrap())
+        .replication(Replication::default())
+        .authz(Authorizer::new(
+            Policy::parse("dash=reader, ops=admin", "").unwrap(),
+        ))
+        .build()
+        .unwrap();
+    let sync = |token_id: &str| {
+        let mut req = Request::new(SyncRequest {
+            follower_id: "follower-1".to_owned(),
+        });
+        req.extensions_mut().insert(Caller {
+            token_id: token_id.to_owned(),
+        });
+        req
+    };
+
+    let status = server.sync(sync("dash")).await.err().unwrap();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    assert!(server.sync(sync("ops")).await.is_ok());
+}
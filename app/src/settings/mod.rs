@@ -2,13 +2,32 @@
 //! Settings
 //!
 
+use crate::watch::FileWatcher;
 use colored::*;
 use config::{Config, Environment, File};
-use notify::{event::ModifyKind, Event, RecommendedWatcher, RecursiveMode, Watcher};
-use std::{collections::HashMap, io::prelude::*, path::Path, time::Duration};
-use tokio::sync::{mpsc, RwLock};
+use std::{collections::HashMap, io::prelude::*, path::Path};
+use tokio::sync::RwLock;
 use tracing::{error, info};
 
+/// Settings printed as `<redacted>` when set
+const SECRET_KEYS: [&str; 4] = [
+    "SURREALDB_PASSWORD",
+    "ENCRYPTION_PASSPHRASE",
+    "REPLICATION_TOKEN",
+    "HMAC_SECRET",
+];
+
+/// `config` with the value of its secret keys hidden; keys from the environment
+/// are lowercase
+fn redacted(mut config: HashMap<String, String>) -> HashMap<String, String> {
+    for (key, value) in config.iter_mut() {
+        if !value.is_empty() && SECRET_KEYS.iter().any(|k| k.eq_ignore_ascii_case(key)) {
+            *value = "<redacted>".to_owned();
+        }
+    }
+    config
+}
+
 lazy_static::lazy_static! {
     pub static ref GLOBAL_SETTINGS: Settings<RwLock<Config>> = Settings::new();
 }
@@ -26,7 +45,7 @@ lazy_static::lazy_static! {
 /// file content changes, every two seconds, asynchronously. The configuration is stored in
 /// global static variables.
 ///
-/// TODO: if env.toml is removed or renamed, it is unhandled here.
+/// TODO: if env.toml is removed, it is unhandled here; replacing it by a rename is fine.
 /// TODO: if it shall accept many sources of changes, and frequently change occurs,
 /// it may uplift to multithread implementation
 ///
@@ -48,6 +67,17 @@ impl Settings {
     /// Load configuration from env.toml file and environment variables
     /// environment variables shall be prefixed with APP_
     fn load_config() -> Config {
+        match Self::try_load_config() {
+            Ok(config) => config,
+            Err(err) => {
+                error!(error = format!("cannot load file, {:?}", err));
+                Config::default()
+            }
+        }
+    }
+
+    /// Like `load_config`, failing on an unreadable env.toml instead
+    fn try_load_config() -> Result<Config, config::ConfigError> {
         let path = Path::new(ENV_FILENAME);
         let display = path.display();
 
@@ -66,6 +96,36 @@ SURREALDB_DB = "test"
 SURREALDB_NS = "test"
 SURREALDB_USERNAME = "root"
 SURREALDB_PASSWORD = "root"
+REPLICATION_ROLE = "leader"
+REPLICATION_LEADER = "http://127.0.0.1:50051"
+MAX_MEMORY = "0"
+MAXMEMORY_POLICY = "noeviction"
+COMPRESSION_CODEC = "lz4"
+COMPRESSION_THRESHOLD = "1kb"
+ENCRYPTION_KEY_ID = ""
+ENCRYPTION_PASSPHRASE = ""
+ENCRYPTION_KEYFILE = ""
+ENCRYPTION_SALT = "simply-hard"
+UPLOAD_DIR = "uploads"
+DOWNLOAD_SHARD_SIZE = "64kb"
+PAYMENTS_ADMIN = "false"
+BITCOIN_NETWORK = "regtest"
+BITCOIN_FEE_RATE = "2"
+BITCOIN_BLOCK_INTERVAL_SECS = "10"
+GRPC_REFLECTION = "false"
+TLS_CERT_PATH = ""
+TLS_KEY_PATH = ""
+TLS_CLIENT_CA_PATH = ""
+AUTH_TOKENS = ""
+AUTH_DISABLED = "false"
+REPLICATION_TOKEN = ""
+REPLICATION_TLS_CA_PATH = ""
+REPLICATION_TLS_CERT_PATH = ""
+REPLICATION_TLS_KEY_PATH = ""
+AUTH_GRANTS = ""
+AUTH_RPC_ROLES = ""
+HMAC_SECRET = ""
+HMAC_WINDOW_SECS = "300"
 "#;
             match new_file.write_all(sample_env.as_bytes()) {
                 Ok(_) => info!(message = format!("{}", "env.toml created successfully.".blue())),
@@ -74,22 +134,14 @@ SURREALDB_PASSWORD = "root"
         }
 
         // load configuration into Config object
-        let env_config = Config::builder()
+        Config::builder()
             .add_source(File::with_name(ENV_FILENAME))
             .add_source(
                 Environment::with_prefix("APP")
                     .try_parsing(true)
                     .separator("_"),
             )
-            .build();
-
-        match env_config {
-            Ok(config) => config,
-            Err(err) => {
-                error!(error = format!("cannot load file, {:?}", err));
-                Config::default()
-            }
-        }
+            .build()
     }
 
     pub fn new() -> Self {
@@ -98,18 +150,20 @@ SURREALDB_PASSWORD = "root"
         Self(RwLock::new(config))
     }
 
-    /// Print config information
+    /// Print config information, without secrets
     pub async fn print_config(prefix: &str) {
         println!(
             " * {} configuration * \n\t\x1b[31m{:?}\x1b[0m",
             prefix,
-            GLOBAL_SETTINGS
-                .0
-                .read()
-                .await
-                .clone()
-                .try_deserialize::<HashMap<String, String>>()
-                .unwrap()
+            redacted(
+                GLOBAL_SETTINGS
+                    .0
+                    .read()
+                    .await
+                    .clone()
+                    .try_deserialize::<HashMap<String, String>>()
+                    .unwrap()
+            )
         );
     }
 
@@ -138,48 +192,28 @@ SURREALDB_PASSWORD = "root"
         }
     }
 
+    /// Reload the configuration whenever env.toml is written, or replaced. An
+    /// unreadable file keeps the current configuration.
     pub async fn watch(&self) -> notify::Result<()> {
-        let (tx, mut rx) = mpsc::channel(1);
-
-        let mut watcher: RecommendedWatcher = Watcher::new(
-            move |res| match res {
-                Ok(Event {
-                    kind: notify::event::EventKind::Modify(ModifyKind::Data(_)),
-                    ..
-                }) => tx.blocking_send(res).unwrap(),
-                Err(err) => error!(error = format!("notify error, {:?}", err)),
-                _ => {
-                    // every content change emits two events, DataChange and MetaChange
-                    // this arm ignores below metadata change events
-                    // Event { kind: Modify(Metadata(Any)) }
-                }
-            },
-            notify::Config::default().with_poll_interval(Duration::from_secs(2)),
-        )?;
-
-        // Add a path to be watched. All files and directories at that path and
-        // below will be monitored for changes.
-        watcher.watch(Path::new(ENV_FILENAME), RecursiveMode::NonRecursive)?;
-
-        while let Some(res) = rx.recv().await {
-            match res {
-                Ok(Event {
-                    kind: notify::event::EventKind::Modify(_),
-                    ..
-                }) => {
-                    info!("{}", "env.toml written; refreshing configuration".blue());
-
-                    // Settings::print_config("Before").await;
-                    let mut write_lock = GLOBAL_SETTINGS.0.write().await;
-                    *write_lock = Self::load_config();
-                    drop(write_lock);
-                    Settings::print_config("New").await;
-                }
-                Err(err) => error!(error = format!("recv error, {:?}", err)),
-                _ => {
-                    // ignore event
+        let mut watcher = FileWatcher::new(&[ENV_FILENAME])?;
+
+        while watcher.changed().await.is_some() {
+            info!("{}", "env.toml written; refreshing configuration".blue());
+
+            // Settings::print_config("Before").await;
+            let config = match Self::try_load_config() {
+                Ok(config) => config,
+                Err(err) => {
+                    error!(
+                        error = format!("cannot load file, keeping the configuration, {:?}", err)
+                    );
+                    continue;
                 }
-            }
+            };
+            let mut write_lock = GLOBAL_SETTINGS.0.write().await;
+            *write_lock = config;
+            drop(write_lock);
+            Settings::print_config("New").await;
         }
         Ok(())
     }
@@ -210,3 +244,18 @@ fn test_settings_new() {
 
     std::env::remove_var("APP_UNITTEST");
 }
+
+#[test]
+fn test_redacted() {
+    let config = redacted(HashMap::from([
+        ("HMAC_SECRET".to_owned(), "s3cret".to_owned()),
+        ("replication_token".to_owned(), "t0ken".to_owned()),
+        ("ENCRYPTION_PASSPHRASE".to_owned(), String::new()),
+        ("SERVER_PORT".to_owned(), "50051".to_owned()),
+    ]));
+
+    assert_eq!(config["HMAC_SECRET"], "<redacted>");
+    assert_eq!(config["replication_token"], "<redacted>");
+    assert_eq!(config["ENCRYPTION_PASSPHRASE"], "");
+    assert_eq!(config["SERVER_PORT"], "50051");
+}
@@ -0,0 +1,537 @@
+//!
+//! HMAC-SHA256 signatures of requests, over their method, payload and timestamp,
+//! with a secret shared by clients and servers. Proxies between them can neither
+//! alter signed requests, nor replay them.
+//!
+
+use crate::{
+    protobuffer::gupload::{chunk::Data, Chunk},
+    replicas::now_millis,
+    AppError, Settings,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use bytes::BytesMut;
+use futures::{future::BoxFuture, StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use prost::Message;
+use sha2::Sha256;
+use std::{
+    collections::{HashSet, VecDeque},
+    ops::Range,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tonic::{
+    body::BoxBody,
+    codegen::{http, StdError},
+    metadata::MetadataMap,
+    transport::Body,
+    Request, Status,
+};
+use tower::{Layer, Service};
+
+pub const TIMESTAMP_METADATA_KEY: &str = "x-signature-timestamp";
+pub const NONCE_METADATA_KEY: &str = "x-signature-nonce";
+pub const SIGNATURE_METADATA_KEY: &str = "x-signature";
+
+pub const SET_VALUE_METHOD: &str = "/echo.Echo/SetValue";
+pub const UPLOAD_METHOD: &str = "/gupload.GuploadService/Upload";
+
+/// Methods of the writes, whose requests must be signed
+pub const SIGNED_METHODS: [&str; 8] = [
+    SET_VALUE_METHOD,
+    "/payments.Bitcoin/SendPayment",
+    "/payments.LedgerAdmin/SeedBalance",
+    "/person.PersonService/CreatePerson",
+    "/person.PersonService/UpdatePerson",
+    "/person.PersonService/DeletePerson",
+    UPLOAD_METHOD,
+    "/gupload.GuploadService/DeleteFile",
+];
+
+/// Signatures older, or further in the future, are rejected
+pub const DEFAULT_SIGNATURE_WINDOW: Duration = Duration::from_secs(300);
+
+/// Longest nonce remembered, against replays
+const MAX_NONCE_LEN: usize = 64;
+
+/// Largest signed message, the default decoding limit of tonic
+const MAX_SIGNED_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &[u8], method: &str, timestamp_ms: i64, nonce: &str, payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(format!("{}\n{}\n{}\n", method, timestamp_ms, nonce).as_bytes());
+    mac.update(payload);
+    mac
+}
+
+/// Signature of `payload` sent to `method` at `timestamp_ms`, base64 encoded
+pub fn sign(secret: &[u8], method: &str, timestamp_ms: i64, nonce: &str, payload: &[u8]) -> String {
+    STANDARD.encode(
+        mac(secret, method, timestamp_ms, nonce, payload)
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+/// Signs requests of a client
+#[derive(Clone)]
+pub struct Signer {
+    secret: Arc<Vec<u8>>,
+}
+
+impl std::fmt::Debug for Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Signer").finish_non_exhaustive()
+    }
+}
+
+impl Signer {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            secret: Arc::new(secret.as_bytes().to_vec()),
+        }
+    }
+
+    /// Add the timestamp, nonce and signature of `request` to its metadata
+    pub fn sign_request<T: prost::Message>(&self, method: &str, request: &mut Request<T>) {
+        let payload = request.get_ref().encode_to_vec();
+        self.sign_payload(method, &payload, request.metadata_mut());
+    }
+
+    /// Add the timestamp, nonce and signature of `payload` to `metadata`. Streams
+    /// are signed over their first message.
+    pub fn sign_payload(&self, method: &str, payload: &[u8], metadata: &mut MetadataMap) {
+        let timestamp_ms = now_millis();
+        let nonce = uuid::Uuid::new_v4().to_string();
+        let signature = sign(&self.secret, method, timestamp_ms, &nonce, payload);
+
+        // timestamps, uuids and base64 are valid metadata values
+        metadata.insert(TIMESTAMP_METADATA_KEY, timestamp_ms.into());
+        metadata.insert(NONCE_METADATA_KEY, nonce.parse().unwrap());
+        metadata.insert(SIGNATURE_METADATA_KEY, signature.parse().unwrap());
+    }
+}
+
+/// Nonces accepted within the window, in the order they expire
+#[derive(Debug, Default)]
+struct Seen {
+    nonces: HashSet<String>,
+    expiries: VecDeque<(i64, String)>,
+}
+
+impl Seen {
+    /// False if `nonce` was seen before `now_ms`; else remembered until `expires_ms`
+    fn insert(&mut self, nonce: &str, now_ms: i64, expires_ms: i64) -> bool {
+        while let Some((expiry_ms, _)) = self.expiries.front() {
+            if *expiry_ms > now_ms {
+                break;
+            }
+            if let Some((_, expired)) = self.expiries.pop_front() {
+                self.nonces.remove(&expired);
+            }
+        }
+
+        if !self.nonces.insert(nonce.to_owned()) {
+            return false;
+        }
+        self.expiries.push_back((expires_ms, nonce.to_owned()));
+        true
+    }
+}
+
+/// Verifies signed requests, remembering their nonces for the window, so that each
+/// is accepted once
+#[derive(Clone)]
+pub struct Verifier {
+    secret: Arc<Vec<u8>>,
+    window: Duration,
+    seen: Arc<Mutex<Seen>>,
+}
+
+impl std::fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Verifier")
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Verifier {
+    pub fn new(secret: &str, window: Duration) -> Self {
+        Self {
+            secret: Arc::new(secret.as_bytes().to_vec()),
+            window,
+            seen: Default::default(),
+        }
+    }
+
+    /// Verifier of "HMAC_SECRET", accepting signatures within "HMAC_WINDOW_SECS".
+    /// None, for unsigned requests, without a secret.
+    pub async fn from_settings() -> crate::Result<Option<Self>> {
+        let secret = match Settings::get_config_item("HMAC_SECRET").await {
+            Some(secret) if !secret.is_empty() => secret,
+            _ => return Ok(None),
+        };
+        let window = match Settings::get_config_item("HMAC_WINDOW_SECS").await {
+            Some(secs) if !secs.is_empty() => {
+                secs.parse()
+                    .map(Duration::from_secs)
+                    .map_err(|_| AppError::InvalidSetting {
+                        key: "HMAC_WINDOW_SECS".to_owned(),
+                        value: secs,
+                    })?
+            }
+            _ => DEFAULT_SIGNATURE_WINDOW,
+        };
+
+        Ok(Some(Self::new(&secret, window)))
+    }
+
+    /// Reject `payload` sent to `method` unless signed in `headers` with the
+    /// secret, within the window, and not seen before
+    pub fn verify(
+        &self,
+        method: &str,
+        headers: &http::HeaderMap,
+        payload: &[u8],
+    ) -> Result<(), Status> {
+        self.verify_at(method, headers, payload, now_millis())
+    }
+
+    fn verify_at(
+        &self,
+        method: &str,
+        headers: &http::HeaderMap,
+        payload: &[u8],
+        now_ms: i64,
+    ) -> Result<(), Status> {
+        let value = |key: &str| {
+            headers
+                .get(key)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| Status::unauthenticated(format!("missing {}", key)))
+        };
+        let timestamp_ms = value(TIMESTAMP_METADATA_KEY)?
+            .parse::<i64>()
+            .map_err(|_| Status::unauthenticated("invalid signature timestamp"))?;
+        let nonce = value(NONCE_METADATA_KEY)?;
+        let signature = value(SIGNATURE_METADATA_KEY)?;
+
+        let window_ms = self.window.as_millis() as i64;
+        if (now_ms - timestamp_ms).abs() > window_ms {
+            return Err(Status::unauthenticated("stale signature"));
+        }
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(Status::unauthenticated("invalid signature nonce"));
+        }
+
+        let bytes = STANDARD
+            .decode(signature)
+            .map_err(|_| Status::unauthenticated("invalid signature"))?;
+        mac(&self.secret, method, timestamp_ms, nonce, payload)
+            .verify_slice(&bytes)
+            .map_err(|_| Status::unauthenticated("invalid signature"))?;
+
+        // accepted until timestamp + window, which is within 2 windows of now
+        let inserted = self
+            .seen
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(nonce, now_ms, now_ms + 2 * window_ms);
+        match inserted {
+            true => Ok(()),
+            false => Err(Status::unauthenticated("replayed signature")),
+        }
+    }
+
+    /// Verify the first message of `body`, sent to `method`, then hand the body on,
+    /// whole
+    async fn verify_body(
+        &self,
+        method: &str,
+        headers: &http::HeaderMap,
+        mut body: Body,
+    ) -> Result<Body, Status> {
+        let mut read = BytesMut::new();
+        let message = loop {
+            if let Some(message) = first_message(&read)? {
+                break message;
+            }
+            match body.next().await {
+                Some(Ok(chunk)) => read.extend_from_slice(&chunk),
+                Some(Err(err)) => return Err(Status::cancelled(err.to_string())),
+                // a stream of no message
+                None if read.is_empty() => break 0..0,
+                None => return Err(Status::invalid_argument("truncated message")),
+            }
+        };
+        self.verify(method, headers, &read[message.clone()])?;
+        if method == UPLOAD_METHOD {
+            check_upload_info(&read[message])?;
+        }
+
+        let read = read.freeze();
+        Ok(Body::wrap_stream(
+            futures::stream::iter([Ok(read)]).chain(body.map_err(StdError::from)),
+        ))
+    }
+}
+
+/// Uploads are signed over their first chunk, which binds their content only if it
+/// declares the sha256 of the file, or resumes a session that did
+fn check_upload_info(payload: &[u8]) -> Result<(), Status> {
+    match Chunk::decode(payload) {
+        Ok(Chunk {
+            data: Some(Data::Info(info)),
+        }) if !info.sha256.is_empty() || !info.session_id.is_empty() => Ok(()),
+        _ => Err(Status::invalid_argument(
+            "signed uploads must declare the sha256 of the file in their first chunk",
+        )),
+    }
+}
+
+/// Range of the first length-prefixed gRPC message of `read`, once read whole
+fn first_message(read: &[u8]) -> Result<Option<Range<usize>>, Status> {
+    if read.len() < 5 {
+        return Ok(None);
+    }
+    if read[0] != 0 {
+        return Err(Status::unimplemented(
+            "signed messages may not be compressed",
+        ));
+    }
+    let len = u32::from_be_bytes([read[1], read[2], read[3], read[4]]) as usize;
+    if len > MAX_SIGNED_MESSAGE_SIZE {
+        return Err(Status::resource_exhausted("signed message too large"));
+    }
+
+    Ok(Some(5..5 + len).filter(|message| message.end <= read.len()))
+}
+
+/// Layer verifying the signatures of the requests to `SIGNED_METHODS`, keyed on
+/// their path, before any service decodes them. Without a verifier, requests pass.
+#[derive(Debug, Clone, Default)]
+pub struct SignatureLayer {
+    verifier: Option<Verifier>,
+}
+
+impl SignatureLayer {
+    pub fn new(verifier: Option<Verifier>) -> Self {
+        Self { verifier }
+    }
+}
+
+impl<S> Layer<S> for SignatureLayer {
+    type Service = Signed<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Signed {
+            inner,
+            verifier: self.verifier.clone(),
+        }
+    }
+}
+
+/// Service of `SignatureLayer`
+#[derive(Debug, Clone)]
+pub struct Signed<S> {
+    inner: S,
+    verifier: Option<Verifier>,
+}
+
+impl<S> Service<http::Request<Body>> for Signed<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        // the clone may not be ready; call the one that is
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let path = request.uri().path();
+        let verifier = match &self.verifier {
+            Some(verifier) if SIGNED_METHODS.iter().any(|method| *method == path) => {
+                verifier.clone()
+            }
+            _ => return Box::pin(inner.call(request)),
+        };
+
+        Box::pin(async move {
+            let (parts, body) = request.into_parts();
+            match verifier
+                .verify_body(parts.uri.path(), &parts.headers, body)
+                .await
+            {
+                Ok(body) => inner.call(http::Request::from_parts(parts, body)).await,
+                Err(status) => Ok(status.to_http()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+fn key_value(value: &str) -> crate::protobuffer::KeyValueRequest {
+    crate::protobuffer::KeyValueRequest {
+        key: "foo".to_owned(),
+        value: Some(value.to_owned()),
+        ttl: None,
+    }
+}
+
+#[test]
+fn test_verify() {
+    let signer = Signer::new("secret");
+    let verifier = Verifier::new("secret", Duration::from_secs(60));
+    let signed = |signer: &Signer, value: &str| {
+        let mut request = Request::new(key_value(value));
+        signer.sign_request(SET_VALUE_METHOD, &mut request);
+        request.metadata().clone().into_headers()
+    };
+    let payload = key_value("bar").encode_to_vec();
+
+    let headers = signed(&signer, "bar");
+    assert!(verifier
+        .verify(SET_VALUE_METHOD, &headers, &payload)
+        .is_ok());
+    // replayed
+    assert!(verifier
+        .verify(SET_VALUE_METHOD, &headers, &payload)
+        .is_err());
+
+    // another method, payload or secret
+    let headers = signed(&signer, "bar");
+    assert!(verifier
+        .verify("/echo.Echo/GetValue", &headers, &payload)
+        .is_err());
+    assert!(verifier
+        .verify(
+            SET_VALUE_METHOD,
+            &headers,
+            &key_value("baz").encode_to_vec()
+        )
+        .is_err());
+    let headers = signed(&Signer::new("guess"), "bar");
+    assert!(verifier
+        .verify(SET_VALUE_METHOD, &headers, &payload)
+        .is_err());
+
+    // stale
+    let headers = signed(&signer, "bar");
+    assert!(verifier
+        .verify_at(SET_VALUE_METHOD, &headers, &payload, now_millis() + 61_000)
+        .is_err());
+
+    assert!(verifier
+        .verify(SET_VALUE_METHOD, &http::HeaderMap::new(), &payload)
+        .is_err());
+
+    // nonces expire in order, once out of the window
+    let mut seen = Seen::default();
+    assert!(seen.insert("a", 0, 10));
+    assert!(seen.insert("b", 5, 15));
+    assert!(!seen.insert("a", 9, 19));
+    assert!(seen.insert("a", 10, 20));
+    assert_eq!(seen.nonces.len(), 2);
+    assert_eq!(seen.expiries.len(), 2);
+}
+
+#[tokio::test]
+async fn test_signature_layer() {
+    let signer = Signer::new("secret");
+    let verifier = Verifier::new("secret", Duration::from_secs(60));
+    // the verified body reaches the service whole
+    let service = tower::service_fn(|request: http::Request<Body>| async move {
+        let body: Vec<u8> = request
+            .into_body()
+            .try_fold(Vec::new(), |mut body, chunk| async move {
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            })
+            .await
+            .unwrap();
+        let value = crate::protobuffer::KeyValueRequest::decode(&body[5..]).unwrap();
+        assert_eq!(value.value.as_deref(), Some("bar"));
+        Ok::<_, std::convert::Infallible>(http::Response::new(tonic::body::empty_body()))
+    });
+    let mut service = SignatureLayer::new(Some(verifier)).layer(service);
+
+    let request = |path: &str, signer: Option<&Signer>| {
+        let payload = key_value("bar").encode_to_vec();
+        let mut metadata = MetadataMap::new();
+        if let Some(signer) = signer {
+            signer.sign_payload(path, &payload, &mut metadata);
+        }
+        // a message, split across two chunks
+        let mut framed = vec![0];
+        framed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        framed.extend_from_slice(&payload);
+        let (head, tail) = framed.split_at(3);
+        let chunks = [Ok::<_, StdError>(head.to_vec()), Ok(tail.to_vec())];
+
+        let mut request = http::Request::new(Body::wrap_stream(futures::stream::iter(chunks)));
+        *request.uri_mut() = path.parse().unwrap();
+        *request.headers_mut() = metadata.into_headers();
+        request
+    };
+    let grpc_status = |response: http::Response<BoxBody>| {
+        response
+            .headers()
+            .get("grpc-status")
+            .map(|status| status.to_str().unwrap().to_owned())
+    };
+
+    let response = service
+        .call(request(SET_VALUE_METHOD, Some(&signer)))
+        .await
+        .unwrap();
+    assert_eq!(grpc_status(response), None);
+    let response = service.call(request(SET_VALUE_METHOD, None)).await.unwrap();
+    assert_eq!(
+        grpc_status(response),
+        Some((tonic::Code::Unauthenticated as i32).to_string())
+    );
+    // unsigned methods pass
+    let response = service
+        .call(request("/echo.Echo/GetValue", None))
+        .await
+        .unwrap();
+    assert_eq!(grpc_status(response), None);
+}
+
+#[test]
+fn test_check_upload_info() {
+    use crate::protobuffer::gupload::{ChunkPart, UploadFileInfo};
+
+    let info = |sha256: &str, session_id: &str| {
+        Chunk {
+            data: Some(Data::Info(UploadFileInfo {
+                filename: "report.pdf".to_owned(),
+                sha256: sha256.to_owned(),
+                session_id: session_id.to_owned(),
+                ..Default::default()
+            })),
+        }
+        .encode_to_vec()
+    };
+
+    assert!(check_upload_info(&info(&"a".repeat(64), "")).is_ok());
+    assert!(check_upload_info(&info("", "session-1")).is_ok());
+    // content unbound by the signature
+    assert!(check_upload_info(&info("", "")).is_err());
+    let part = Chunk {
+        data: Some(Data::Part(ChunkPart::default())),
+    };
+    assert!(check_upload_info(&part.encode_to_vec()).is_err());
+}
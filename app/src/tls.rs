@@ -0,0 +1,412 @@
+//!
+//! TLS of the server, and of client connections: PEM certificates and keys read
+//! from files, with optional client certificates (mTLS). The server certificate
+//! is reloaded when its files change, for new connections only.
+//!
+
+use crate::{watch::FileWatcher, AppError, Settings};
+use colored::*;
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use rustls::{
+    server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert},
+    sign::{CertifiedKey, SigningKey},
+    RootCertStore, ServerConfig, SignatureScheme,
+};
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+use tracing::{error, info, warn};
+use x509_parser::{prelude::parse_x509_certificate, time::ASN1Time};
+
+/// Handshakes taking longer are dropped, so that idle connections hold no task
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificates expiring sooner are logged as warnings
+const EXPIRY_WARNING_DAYS: i64 = 14;
+
+fn read_pem(what: &str, path: &Path) -> crate::Result<Vec<u8>> {
+    std::fs::read(path)
+        .map_err(|err| AppError::TlsError(format!("{} {}: {}", what, path.display(), err)))
+}
+
+/// DER certificates of a PEM file
+fn read_certs(what: &str, path: &Path) -> crate::Result<Vec<rustls::Certificate>> {
+    let pem = read_pem(what, path)?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(pem.as_slice()))
+        .map_err(|err| AppError::TlsError(format!("{} {}: {}", what, path.display(), err)))?;
+
+    match certs.is_empty() {
+        true => Err(AppError::TlsError(format!(
+            "{} {}: no certificate found",
+            what,
+            path.display()
+        ))),
+        false => Ok(certs.into_iter().map(rustls::Certificate).collect()),
+    }
+}
+
+/// First private key of a PEM file, PKCS#8, PKCS#1 or SEC1
+fn read_key(path: &Path) -> crate::Result<rustls::PrivateKey> {
+    let pem = read_pem("key", path)?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(pem.as_slice()))
+        .map_err(|err| AppError::TlsError(format!("key {}: {}", path.display(), err)))?;
+
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| AppError::TlsError(format!("key {}: no private key found", path.display())))
+}
+
+/// Whether `key` signs for `public_key`, the subject public key of a certificate
+fn key_matches(key: &dyn SigningKey, public_key: &[u8]) -> bool {
+    let algorithms: [(SignatureScheme, &'static dyn VerificationAlgorithm); 4] = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &signature::ECDSA_P256_SHA256_ASN1,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &signature::ECDSA_P384_SHA384_ASN1,
+        ),
+        (SignatureScheme::ED25519, &signature::ED25519),
+        (
+            SignatureScheme::RSA_PSS_SHA256,
+            &signature::RSA_PSS_2048_8192_SHA256,
+        ),
+    ];
+    let schemes = algorithms.map(|(scheme, _)| scheme);
+    let signer = match key.choose_scheme(&schemes) {
+        Some(signer) => signer,
+        None => return false,
+    };
+
+    let message = b"simply certificate check";
+    match (
+        algorithms
+            .iter()
+            .find(|(scheme, _)| *scheme == signer.scheme()),
+        signer.sign(message),
+    ) {
+        (Some((_, algorithm)), Ok(sig)) => UnparsedPublicKey::new(*algorithm, public_key)
+            .verify(message, &sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// Path of a setting, None if unset or empty
+async fn path_setting(key: &str) -> Option<PathBuf> {
+    Settings::get_config_item(key)
+        .await
+        .filter(|path| !path.trim().is_empty())
+        .map(PathBuf::from)
+}
+
+/// TLS of the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerTls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA bundle verifying client certificates; clients need none without it
+    pub client_ca: Option<PathBuf>,
+}
+
+impl ServerTls {
+    /// TLS from "TLS_CERT_PATH" and "TLS_KEY_PATH", requiring client certificates
+    /// signed by "TLS_CLIENT_CA_PATH" if set. None, for plaintext, without a cert.
+    pub async fn from_settings() -> crate::Result<Option<Self>> {
+        let cert = path_setting("TLS_CERT_PATH").await;
+        let key = path_setting("TLS_KEY_PATH").await;
+        let client_ca = path_setting("TLS_CLIENT_CA_PATH").await;
+
+        match (cert, key) {
+            (Some(cert), Some(key)) => Ok(Some(Self {
+                cert,
+                key,
+                client_ca,
+            })),
+            (None, None) if client_ca.is_none() => Ok(None),
+            (None, _) => Err(AppError::InvalidSetting {
+                key: "TLS_CERT_PATH".to_owned(),
+                value: String::new(),
+            }),
+            (Some(_), None) => Err(AppError::InvalidSetting {
+                key: "TLS_KEY_PATH".to_owned(),
+                value: String::new(),
+            }),
+        }
+    }
+
+    /// Certificate chain and key, if the certificate is currently valid and the key
+    /// is its own. Returns the expiry of the certificate too.
+    pub fn load(&self) -> crate::Result<(CertifiedKey, ASN1Time)> {
+        let invalid =
+            |reason: String| AppError::TlsError(format!("{}: {}", self.cert.display(), reason));
+
+        let certs = read_certs("certificate", &self.cert)?;
+        let key = rustls::sign::any_supported_type(&read_key(&self.key)?)
+            .map_err(|err| AppError::TlsError(format!("key {}: {}", self.key.display(), err)))?;
+
+        let (_, leaf) =
+            parse_x509_certificate(&certs[0].0).map_err(|err| invalid(err.to_string()))?;
+        let validity = leaf.validity();
+        if !validity.is_valid() {
+            return Err(invalid(format!(
+                "valid from {} to {}, not now",
+                validity.not_before, validity.not_after
+            )));
+        }
+        if !key_matches(
+            key.as_ref(),
+            leaf.public_key().subject_public_key.data.as_ref(),
+        ) {
+            return Err(invalid(format!(
+                "not the certificate of the key {}",
+                self.key.display()
+            )));
+        }
+
+        Ok((CertifiedKey::new(certs, key), validity.not_after))
+    }
+
+    /// Acceptor of TLS connections, and the resolver swapping its certificate
+    pub fn acceptor(&self) -> crate::Result<(TlsAcceptor, Arc<CertResolver>)> {
+        let resolver = Arc::new(CertResolver::new(self)?);
+        let builder = ServerConfig::builder().with_safe_defaults();
+
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs("client CA bundle", client_ca)? {
+                    roots.add(&cert).map_err(|err| {
+                        AppError::TlsError(format!("{}: {}", client_ca.display(), err))
+                    })?;
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(resolver.clone());
+        // grpc runs over http/2 only
+        config.alpn_protocols = vec![b"h2".to_vec()];
+
+        Ok((TlsAcceptor::from(Arc::new(config)), resolver))
+    }
+
+    /// Reload the certificate of `resolver` when its files change. Invalid
+    /// replacements are logged and rejected, keeping the current certificate.
+    pub async fn watch(self, resolver: Arc<CertResolver>) -> notify::Result<()> {
+        let mut watcher = FileWatcher::new(&[&self.cert, &self.key])?;
+
+        while watcher.changed().await.is_some() {
+            if let Err(err) = resolver.reload(&self) {
+                error!(
+                    "{}",
+                    format!("TLS certificate rejected, keeping the current one: {}", err).red()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Server certificate of new connections, swapped on reload
+pub struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(tls: &ServerTls) -> crate::Result<Self> {
+        let (key, not_after) = tls.load()?;
+        log_expiry(&tls.cert, &not_after);
+
+        Ok(Self {
+            current: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    /// Swap in the certificate of `tls`, if valid; established connections keep theirs
+    pub fn reload(&self, tls: &ServerTls) -> crate::Result<()> {
+        let (key, not_after) = tls.load()?;
+        *self.current.write().unwrap() = Arc::new(key);
+
+        info!(
+            "{}",
+            format!("TLS certificate {} reloaded", tls.cert.display()).blue()
+        );
+        log_expiry(&tls.cert, &not_after);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn log_expiry(cert: &Path, not_after: &ASN1Time) {
+    let days = (not_after.timestamp() - ASN1Time::now().timestamp()) / 86_400;
+    let message = format!(
+        "TLS certificate {} expires {}, in {} days",
+        cert.display(),
+        not_after,
+        days
+    );
+
+    match days < EXPIRY_WARNING_DAYS {
+        true => warn!("{}", message.yellow()),
+        false => info!("{}", message.blue()),
+    }
+}
+
+/// TLS connections accepted on `listener`, for `Server::serve_with_incoming`.
+/// Handshakes run concurrently; failed ones are logged and dropped.
+pub fn incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> impl Stream<Item = std::io::Result<TlsStream<TcpStream>>> {
+    let (tx, rx) = mpsc::channel(64);
+
+    tokio::spawn(async move {
+        while !tx.is_closed() {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // e.g. out of file descriptors
+                    error!(error = format!("accept error, {:?}", err));
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Ok(Err(err)) => warn!(
+                        "{}",
+                        format!("TLS handshake with {}: {}", peer, err).yellow()
+                    ),
+                    Err(_) => warn!(
+                        "{}",
+                        format!("TLS handshake with {} timed out", peer).yellow()
+                    ),
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+/// TLS of client connections to https:// servers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientTls {
+    /// CA bundle verifying servers; the system roots without it
+    pub ca: Option<PathBuf>,
+    /// client certificate and key, for servers requiring them (mTLS)
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl ClientTls {
+    /// TLS of a follower's connection to its leader: the CA bundle of
+    /// "REPLICATION_TLS_CA_PATH", and the client certificate and key of
+    /// "REPLICATION_TLS_CERT_PATH" and "REPLICATION_TLS_KEY_PATH", for a leader
+    /// requiring one
+    pub async fn from_settings() -> Self {
+        Self {
+            ca: path_setting("REPLICATION_TLS_CA_PATH").await,
+            cert: path_setting("REPLICATION_TLS_CERT_PATH").await,
+            key: path_setting("REPLICATION_TLS_KEY_PATH").await,
+        }
+    }
+
+    /// Whether any option is set, so that servers are expected at https://
+    pub fn is_configured(&self) -> bool {
+        self.ca.is_some() || self.cert.is_some() || self.key.is_some()
+    }
+
+    /// CA and identity, read from their files
+    pub fn config(&self) -> crate::Result<ClientTlsConfig> {
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = &self.ca {
+            config = config.ca_certificate(Certificate::from_pem(read_pem("CA bundle", ca)?));
+        }
+
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Ok(config.identity(Identity::from_pem(
+                read_pem("certificate", cert)?,
+                read_pem("key", key)?,
+            ))),
+            (None, None) => Ok(config),
+            _ => Err(AppError::TlsError(
+                "a client certificate needs its key, and a key its certificate".to_owned(),
+            )),
+        }
+    }
+
+    /// TLS of `https://` endpoints; TLS options are an error over plain `http://`
+    pub fn endpoint(&self, endpoint: Endpoint) -> crate::Result<Endpoint> {
+        match endpoint.uri().scheme_str() {
+            Some("https") => endpoint
+                .tls_config(self.config()?)
+                .map_err(AppError::TonicError),
+            _ if self.is_configured() => Err(AppError::TlsError(format!(
+                "TLS options given for the plaintext address {}",
+                endpoint.uri()
+            ))),
+            _ => Ok(endpoint),
+        }
+    }
+}
+
+#[test]
+fn test_client_tls() {
+    assert!(!ClientTls::default().is_configured());
+    assert!(ClientTls::default().config().is_ok());
+
+    let cert_only = ClientTls {
+        cert: Some(PathBuf::from("client.pem")),
+        ..Default::default()
+    };
+    assert!(cert_only.is_configured());
+    assert!(matches!(cert_only.config(), Err(AppError::TlsError(_))));
+
+    let ca_only = ClientTls {
+        ca: Some(PathBuf::from("ca.pem")),
+        ..Default::default()
+    };
+    let endpoint = |addr: &'static str| Endpoint::from_static(addr);
+    assert!(ClientTls::default()
+        .endpoint(endpoint("http://127.0.0.1:50051"))
+        .is_ok());
+    assert!(matches!(
+        ca_only.endpoint(endpoint("http://127.0.0.1:50051")),
+        Err(AppError::TlsError(_))
+    ));
+    // the CA bundle is read for https://
+    assert!(matches!(
+        ca_only.endpoint(endpoint("https://127.0.0.1:50051")),
+        Err(AppError::TlsError(_))
+    ));
+}
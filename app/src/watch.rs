@@ -0,0 +1,112 @@
+//!
+//! Changes of watched files, for reloading settings and certificates
+//!
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tracing::error;
+
+/// Wait after a change, for related writes to land, e.g. a certificate then its key
+pub const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// Watches files through their directories, since editors and rotations often
+/// rename a new file into place. Stops watching when dropped.
+pub struct FileWatcher {
+    files: Vec<PathBuf>,
+    events: mpsc::Receiver<Event>,
+    _watcher: RecommendedWatcher,
+}
+
+impl FileWatcher {
+    pub fn new<P: AsRef<Path>>(files: &[P]) -> notify::Result<Self> {
+        let (tx, events) = mpsc::channel(16);
+
+        let mut watcher: RecommendedWatcher = Watcher::new(
+            move |res: notify::Result<Event>| match res {
+                Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                    let _ = tx.try_send(event);
+                }
+                Ok(_) => {}
+                Err(err) => error!(error = format!("notify error, {:?}", err)),
+            },
+            notify::Config::default().with_poll_interval(Duration::from_secs(2)),
+        )?;
+
+        let files: Vec<PathBuf> = files.iter().map(|file| file.as_ref().to_owned()).collect();
+        let mut dirs: Vec<&Path> = files
+            .iter()
+            .map(|file| match file.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            })
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        for dir in dirs {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+
+        Ok(Self {
+            files,
+            events,
+            _watcher: watcher,
+        })
+    }
+
+    /// Wait for a change of a watched file, then `SETTLE_DELAY`; the changes
+    /// meanwhile are merged into it. None once the watcher stops.
+    pub async fn changed(&mut self) -> Option<()> {
+        loop {
+            let event = self.events.recv().await?;
+            if event.paths.iter().any(|path| self.is_watched(path)) {
+                break;
+            }
+        }
+
+        tokio::time::sleep(SETTLE_DELAY).await;
+        while self.events.try_recv().is_ok() {}
+        Some(())
+    }
+
+    /// By file name, as events may carry absolute paths
+    fn is_watched(&self, path: &Path) -> bool {
+        path.file_name().is_some()
+            && self
+                .files
+                .iter()
+                .any(|file| file.file_name() == path.file_name())
+    }
+}
+
+#[tokio::test]
+async fn test_file_watcher() {
+    let dir = std::env::temp_dir().join(format!("simply-watch-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let watched = dir.join("watched.toml");
+    std::fs::write(&watched, "a = 1").unwrap();
+
+    let mut watcher = FileWatcher::new(&[&watched]).unwrap();
+    // another file of the directory is ignored
+    std::fs::write(dir.join("other.toml"), "b = 1").unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_secs(1), watcher.changed())
+            .await
+            .is_err()
+    );
+
+    // replaced by a rename, as editors do
+    std::fs::write(dir.join(".watched.toml.swp"), "a = 2").unwrap();
+    std::fs::rename(dir.join(".watched.toml.swp"), &watched).unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .unwrap()
+            .is_some()
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
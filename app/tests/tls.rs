@@ -0,0 +1,210 @@
+mod common;
+use common::setup;
+
+extern crate app;
+use app::{
+    clients::Client,
+    models::PersonRepository,
+    protobuffer::{self, echo_client::EchoClient},
+    server::EchoServerBuilder,
+    tls::{self, CertResolver, ClientTls, ServerTls},
+    AppError, Connection, InMemoryDatabase,
+};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tonic::transport::{Endpoint, Server};
+
+/// A CA, written as ca.pem
+fn generate_ca(dir: &Path) -> Certificate {
+    let mut params = CertificateParams::new(Vec::new());
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "simply test CA");
+    let ca = Certificate::from_params(params).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+    ca
+}
+
+/// A certificate of `san` signed by `ca`, written as `name`.pem and `name`.key
+fn generate_cert(dir: &Path, name: &str, san: &str, ca: &Certificate, expired: bool) {
+    let mut params = CertificateParams::new(vec![san.to_owned()]);
+    if expired {
+        params.not_before = rcgen::date_time_ymd(2000, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2001, 1, 1);
+    }
+    let cert = Certificate::from_params(params).unwrap();
+
+    std::fs::write(
+        dir.join(format!("{}.pem", name)),
+        cert.serialize_pem_with_signer(ca).unwrap(),
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join(format!("{}.key", name)),
+        cert.serialize_private_key_pem(),
+    )
+    .unwrap();
+}
+
+/// Serve echo over TLS, on a free port of localhost
+async fn serve(tls: ServerTls) -> (u16, Arc<CertResolver>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (acceptor, resolver) = tls.acceptor().unwrap();
+
+    let simply_server = EchoServerBuilder::default()
+        .person(PersonRepository::default())
+        .connection(<InMemoryDatabase as Connection>::new().await.unwrap())
+        .build()
+        .unwrap();
+    let server = Server::builder()
+        .add_service(protobuffer::echo_server::EchoServer::new(simply_server))
+        .serve_with_incoming(tls::incoming(listener, acceptor));
+    tokio::spawn(server);
+
+    (port, resolver)
+}
+
+/// Echo "foo" through a client of `tls`
+async fn unary_echo(port: u16, tls: &ClientTls) -> Result<String, String> {
+    let channel = Endpoint::new(format!("https://localhost:{}", port))
+        .unwrap()
+        .tls_config(tls.config().unwrap())
+        .unwrap()
+        .connect()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    EchoClient::new(channel)
+        .unary_echo(protobuffer::EchoRequest {
+            message: "foo".to_owned(),
+        })
+        .await
+        .map(|response| response.into_inner().message)
+        .map_err(|status| status.to_string())
+}
+
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("simply-tls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn test_mutual_tls() {
+    setup();
+    let dir = temp_dir();
+    let ca = generate_ca(&dir);
+    generate_cert(&dir, "server", "localhost", &ca, false);
+    generate_cert(&dir, "client", "simply-cli", &ca, false);
+
+    let (port, _) = serve(ServerTls {
+        cert: dir.join("server.pem"),
+        key: dir.join("server.key"),
+        client_ca: Some(dir.join("ca.pem")),
+    })
+    .await;
+
+    let client_tls = ClientTls {
+        ca: Some(dir.join("ca.pem")),
+        cert: Some(dir.join("client.pem")),
+        key: Some(dir.join("client.key")),
+    };
+    assert_eq!(unary_echo(port, &client_tls).await.as_deref(), Ok("foo"));
+
+    // addresses without scheme default to https
+    assert!(
+        Client::connect_tls(format!("localhost:{}", port), client_tls.clone())
+            .await
+            .is_ok()
+    );
+    assert!(matches!(
+        Client::connect_tls(format!("http://localhost:{}", port), client_tls.clone()).await,
+        Err(AppError::TlsError(_))
+    ));
+
+    // without a client certificate
+    let server_only = ClientTls {
+        ca: Some(dir.join("ca.pem")),
+        ..Default::default()
+    };
+    assert!(unary_echo(port, &server_only).await.is_err());
+
+    // without the CA, the server is not trusted
+    let untrusted = ClientTls {
+        ca: Some(dir.join("client.pem")),
+        ..client_tls
+    };
+    assert!(unary_echo(port, &untrusted).await.is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_reload() {
+    let dir = temp_dir();
+    let ca = generate_ca(&dir);
+    generate_cert(&dir, "server", "localhost", &ca, false);
+
+    let tls = ServerTls {
+        cert: dir.join("server.pem"),
+        key: dir.join("server.key"),
+        client_ca: None,
+    };
+    let (port, resolver) = serve(tls.clone()).await;
+    let client_tls = ClientTls {
+        ca: Some(dir.join("ca.pem")),
+        ..Default::default()
+    };
+    assert_eq!(unary_echo(port, &client_tls).await.as_deref(), Ok("foo"));
+
+    // a certificate written before its key does not match the current key
+    let old_key = std::fs::read(dir.join("server.key")).unwrap();
+    generate_cert(&dir, "server", "localhost", &ca, false);
+    std::fs::write(
+        dir.join("new.key"),
+        std::fs::read(dir.join("server.key")).unwrap(),
+    )
+    .unwrap();
+    std::fs::write(dir.join("server.key"), &old_key).unwrap();
+    assert!(matches!(resolver.reload(&tls), Err(AppError::TlsError(_))));
+    assert_eq!(unary_echo(port, &client_tls).await.as_deref(), Ok("foo"));
+
+    std::fs::rename(dir.join("new.key"), dir.join("server.key")).unwrap();
+    assert!(resolver.reload(&tls).is_ok());
+    assert_eq!(unary_echo(port, &client_tls).await.as_deref(), Ok("foo"));
+
+    // expired certificates are rejected
+    generate_cert(&dir, "server", "localhost", &ca, true);
+    assert!(matches!(resolver.reload(&tls), Err(AppError::TlsError(_))));
+
+    // after a swap to a certificate of another CA, only clients trusting it connect
+    std::fs::rename(dir.join("ca.pem"), dir.join("old-ca.pem")).unwrap();
+    let new_ca = generate_ca(&dir);
+    generate_cert(&dir, "server", "localhost", &new_ca, false);
+    assert!(resolver.reload(&tls).is_ok());
+    let old_client_tls = ClientTls {
+        ca: Some(dir.join("old-ca.pem")),
+        ..Default::default()
+    };
+    assert!(unary_echo(port, &old_client_tls).await.is_err());
+    assert_eq!(unary_echo(port, &client_tls).await.as_deref(), Ok("foo"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_missing_files() {
+    let missing = PathBuf::from("does-not-exist.pem");
+    let tls = ServerTls {
+        cert: missing.clone(),
+        key: missing,
+        client_ca: None,
+    };
+    assert!(matches!(tls.load(), Err(AppError::TlsError(_))));
+}
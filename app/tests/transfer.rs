@@ -0,0 +1,108 @@
+mod common;
+use common::setup;
+
+extern crate app;
+use app::{
+    clients::Client, models::FileStore, protobuffer::gupload::gupload_service_server,
+    server::GuploadServerBuilder, AppError,
+};
+use indicatif::ProgressBar;
+use std::{io::Cursor, path::PathBuf};
+use tonic::transport::Server;
+
+/// Serve gupload from `root`, on a free port of localhost
+async fn serve(root: &PathBuf) -> u16 {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let gupload_server = GuploadServerBuilder::default()
+        .files(FileStore::open(root).await.unwrap())
+        .build()
+        .unwrap();
+    let server = Server::builder()
+        .add_service(gupload_service_server::GuploadServiceServer::new(
+            gupload_server,
+        ))
+        .serve_with_incoming(futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        }));
+    tokio::spawn(server);
+
+    port
+}
+
+#[tokio::test]
+async fn test_upload_download() {
+    setup();
+    let root = std::env::temp_dir().join(format!("simply-transfer-{}", uuid::Uuid::new_v4()));
+    let port = serve(&root).await;
+    let mut client = Client::connect(format!("http://127.0.0.1:{}", port))
+        .await
+        .unwrap();
+
+    // a file, several chunks long, checksummed before upload
+    let content: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let path = root.join(".local.bin");
+    std::fs::write(&path, &content).unwrap();
+    let status = client
+        .upload_file(
+            &path,
+            "file.bin".to_owned(),
+            String::new(),
+            ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(status.offset, content.len() as u64);
+
+    let mut downloaded = Vec::new();
+    let written = client
+        .download(
+            "file.bin".to_owned(),
+            &mut downloaded,
+            ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(written, content.len() as u64);
+    assert_eq!(downloaded, content);
+
+    // of unknown size, its checksum sent once read
+    client
+        .upload(
+            Cursor::new(b"from stdin".to_vec()),
+            "stdin.txt".to_owned(),
+            String::new(),
+            None,
+            None,
+            ProgressBar::hidden(),
+        )
+        .await
+        .unwrap();
+
+    // content not matching its announced checksum is not committed
+    assert!(matches!(
+        client
+            .upload(
+                Cursor::new(b"corrupted".to_vec()),
+                "corrupted.txt".to_owned(),
+                String::new(),
+                Some(9),
+                Some(app::models::sha256_hex(b"original")),
+                ProgressBar::hidden(),
+            )
+            .await,
+        Err(AppError::UploadFailed(_))
+    ));
+    assert!(client
+        .download(
+            "corrupted.txt".to_owned(),
+            Vec::new(),
+            ProgressBar::hidden()
+        )
+        .await
+        .is_err());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}